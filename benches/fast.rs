@@ -0,0 +1,54 @@
+//! Compares the normal conversion against `--fast`, which writes records
+//! without building a JSON value first, on a stream of integer-heavy records.
+//!
+//! Run with `cargo bench --bench fast`.
+
+use std::time::{Duration, Instant};
+
+use mp2json::Converter;
+use rmpv::Value;
+
+const RECORDS: u32 = 50_000;
+
+fn record(i: u32) -> Value {
+    Value::Map(vec![
+        ("id".into(), i.into()),
+        (
+            "timestamp".into(),
+            (1_700_000_000_000u64 + u64::from(i)).into(),
+        ),
+        ("delta".into(), (-(i as i64) * 7).into()),
+        (
+            "samples".into(),
+            Value::Array((0..32).map(|j| (i.wrapping_mul(j) % 1000).into()).collect()),
+        ),
+    ])
+}
+
+fn time(input: &[u8], direct: bool) -> (Duration, Vec<u8>) {
+    let c = Converter {
+        buffered: true,
+        direct,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let start = Instant::now();
+    c.run(input, &mut output).unwrap();
+    (start.elapsed(), output)
+}
+
+fn main() {
+    let mut input = Vec::new();
+    for i in 0..RECORDS {
+        rmpv::encode::write_value(&mut input, &record(i)).unwrap();
+    }
+    let (baseline, expected) = time(&input, false);
+    println!("normal: {:?}", baseline);
+    let (elapsed, output) = time(&input, true);
+    assert!(output == expected, "output differs with --fast");
+    println!(
+        "fast: {:?} ({:.2}x)",
+        elapsed,
+        baseline.as_secs_f64() / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,55 @@
+//! Compares single-threaded conversion against `--jobs` on a stream of
+//! deeply nested records with large binary payloads.
+//!
+//! Run with `cargo bench --bench jobs`.
+
+use std::time::{Duration, Instant};
+
+use mp2json::Converter;
+use rmpv::Value;
+
+const RECORDS: u32 = 2_000;
+
+fn record(i: u32) -> Value {
+    let mut v = Value::Binary(vec![i as u8; 4096]);
+    for depth in 0..32 {
+        v = Value::Map(vec![
+            ("depth".into(), depth.into()),
+            ("id".into(), i.into()),
+            ("tags".into(), Value::Array(vec!["a".into(), "b".into()])),
+            ("child".into(), v),
+        ]);
+    }
+    v
+}
+
+fn time(input: &[u8], jobs: usize) -> (Duration, Vec<u8>) {
+    let c = Converter {
+        buffered: true,
+        jobs,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let start = Instant::now();
+    c.run(input, &mut output).unwrap();
+    (start.elapsed(), output)
+}
+
+fn main() {
+    let mut input = Vec::new();
+    for i in 0..RECORDS {
+        rmpv::encode::write_value(&mut input, &record(i)).unwrap();
+    }
+    let (baseline, expected) = time(&input, 1);
+    println!("jobs=1: {:?}", baseline);
+    for jobs in [2, 4, 8] {
+        let (elapsed, output) = time(&input, jobs);
+        assert!(output == expected, "output differs with jobs={jobs}");
+        println!(
+            "jobs={}: {:?} ({:.2}x)",
+            jobs,
+            elapsed,
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
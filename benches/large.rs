@@ -0,0 +1,68 @@
+//! Compares writing each record through a `BufWriter` piece by piece, as the
+//! `json` crate's writer does, against the conversion's own output, which
+//! hands each serialized record to the `BufWriter` whole, on a stream of a
+//! few very large records.
+//!
+//! Run with `cargo bench --bench large`.
+
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use mp2json::{read_and_convert_one, ConvertOptions, Converter};
+use rmpv::Value;
+
+const RECORDS: u32 = 40;
+
+fn record(i: u32) -> Value {
+    Value::Array(
+        (0..100_000)
+            .map(|j| {
+                Value::Map(vec![
+                    ("id".into(), (i * j).into()),
+                    ("name".into(), format!("item {}", j).into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn piecewise(input: &[u8]) -> (Duration, Vec<u8>) {
+    let options = ConvertOptions::default();
+    let mut output = BufWriter::new(Vec::new());
+    let start = Instant::now();
+    let mut input = input;
+    while !input.is_empty() {
+        let v = read_and_convert_one(&mut input, &options).unwrap();
+        v.write(&mut output).unwrap();
+        output.write_all(b"\n").unwrap();
+    }
+    output.flush().unwrap();
+    (start.elapsed(), output.into_inner().unwrap())
+}
+
+fn whole(input: &[u8]) -> (Duration, Vec<u8>) {
+    let c = Converter {
+        buffered: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let start = Instant::now();
+    c.run(input, &mut output).unwrap();
+    (start.elapsed(), output)
+}
+
+fn main() {
+    let mut input = Vec::new();
+    for i in 0..RECORDS {
+        rmpv::encode::write_value(&mut input, &record(i)).unwrap();
+    }
+    let (baseline, expected) = piecewise(&input);
+    println!("piecewise: {:?}", baseline);
+    let (elapsed, output) = whole(&input);
+    assert!(output == expected, "output differs when written whole");
+    println!(
+        "whole: {:?} ({:.2}x)",
+        elapsed,
+        baseline.as_secs_f64() / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mp2json::{read_and_convert_one, ConvertOptions, Converter};
+
+fuzz_target!(|data: &[u8]| {
+    // Any input must produce either a value or an error; panics and aborts
+    // are reported by libfuzzer
+    let _ = read_and_convert_one(&mut &data[..], &ConvertOptions::default());
+    let _ = read_and_convert_one(&mut &data[..], &ConvertOptions::strict());
+
+    let converter = Converter {
+        annotate_types: true,
+        ..Default::default()
+    };
+    let _ = converter.run(data, std::io::sink());
+
+    let converter = Converter {
+        pretty_width: Some(40),
+        ..Default::default()
+    };
+    let _ = converter.run(data, std::io::sink());
+});
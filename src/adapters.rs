@@ -0,0 +1,393 @@
+//! Small `Read` and `Write` adapters used while converting.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::sha256::Sha256;
+
+/// A writer which counts the bytes written through it
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader which retries reads interrupted by a signal, so that EINTR from
+/// a slow device is never mistaken for a failure. `read_exact` already does
+/// this, but `BufRead::fill_buf` and plain `read` calls don't.
+///
+/// A read interrupted once `interrupt` is raised fails instead, with an
+/// error `read_exact` won't retry, so that a Ctrl-C stops a blocked read.
+pub(crate) struct RetryReader<R> {
+    inner: R,
+    interrupt: Option<&'static AtomicBool>,
+}
+
+impl<R> RetryReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        RetryReader {
+            inner,
+            interrupt: None,
+        }
+    }
+
+    pub(crate) fn interruptible(inner: R, interrupt: Option<&'static AtomicBool>) -> Self {
+        RetryReader { inner, interrupt }
+    }
+}
+
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    if self
+                        .interrupt
+                        .is_some_and(|flag| flag.load(Ordering::SeqCst))
+                    {
+                        return Err(io::Error::other("read interrupted"));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A reader which counts the bytes read through it
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        CountingReader { inner, count: 0 }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// A reader which hashes the bytes read through it, if it has a hash
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hash: Option<Sha256>,
+}
+
+impl<R> HashingReader<R> {
+    pub(crate) fn new(inner: R, hash: Option<Sha256>) -> Self {
+        HashingReader { inner, hash }
+    }
+
+    pub(crate) fn into_hash(self) -> Option<Sha256> {
+        self.hash
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hash) = &mut self.hash {
+            hash.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// A writer which hashes the bytes written through it, if it has a hash
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hash: Option<Sha256>,
+}
+
+impl<W> HashingWriter<W> {
+    pub(crate) fn new(inner: W, hash: Option<Sha256>) -> Self {
+        HashingWriter { inner, hash }
+    }
+
+    pub(crate) fn into_hash(self) -> Option<Sha256> {
+        self.hash
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hash) = &mut self.hash {
+            hash.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer which copies everything written to it to a second writer. If
+/// the first writer's pipe is closed, writing continues to the second alone;
+/// errors from the second are returned.
+pub(crate) struct TeeWriter<A, B> {
+    primary: A,
+    secondary: B,
+    primary_closed: bool,
+}
+
+impl<A, B> TeeWriter<A, B> {
+    pub(crate) fn new(primary: A, secondary: B) -> Self {
+        TeeWriter {
+            primary,
+            secondary,
+            primary_closed: false,
+        }
+    }
+
+    /// Whether writing to the primary has failed with a broken pipe
+    pub(crate) fn primary_closed(&self) -> bool {
+        self.primary_closed
+    }
+
+    fn primary_result(&mut self, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.primary_closed = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.secondary.write_all(buf)?;
+        if !self.primary_closed {
+            let result = self.primary.write_all(buf);
+            self.primary_result(result)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.secondary.flush()?;
+        if !self.primary_closed {
+            let result = self.primary.flush();
+            self.primary_result(result)?;
+        }
+        Ok(())
+    }
+}
+
+/// A handle for writing diagnostic lines, such as warnings and reports, to
+/// an output shared with its clones.
+///
+/// Each handle collects a line until it is complete, and only then writes
+/// it to the output in one piece, so that lines written through different
+/// handles, even from different threads, are never torn or interleaved.
+/// A partial line is written when the handle is flushed or dropped.
+pub struct Diagnostics {
+    output: Arc<Mutex<dyn Write + Send>>,
+    line: Vec<u8>,
+}
+
+impl Diagnostics {
+    pub fn new<W: Write + Send + 'static>(output: W) -> Self {
+        Diagnostics {
+            output: Arc::new(Mutex::new(output)),
+            line: Vec::new(),
+        }
+    }
+
+    /// Write out everything collected up to `end`
+    fn write_out(&mut self, end: usize) -> io::Result<()> {
+        // A handle which panicked while writing has still written whole lines
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let result = output.write_all(&self.line[..end]);
+        self.line.drain(..end);
+        result
+    }
+}
+
+impl Clone for Diagnostics {
+    /// Another handle on the same output, with a line of its own
+    fn clone(&self) -> Self {
+        Diagnostics {
+            output: Arc::clone(&self.output),
+            line: Vec::new(),
+        }
+    }
+}
+
+impl Write for Diagnostics {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if let Some(last) = buf.iter().rposition(|b| *b == b'\n') {
+            self.write_out(self.line.len() - buf.len() + last + 1)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.write_out(self.line.len())?;
+        }
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        output.flush()
+    }
+}
+
+impl Drop for Diagnostics {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{Diagnostics, TeeWriter};
+
+    /// A pipe whose reader goes away after `capacity` bytes
+    struct ClosingPipe {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for ClosingPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.capacity {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee() {
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        let mut tee = TeeWriter::new(&mut a, &mut b);
+        tee.write_all(b"one\n").unwrap();
+        tee.write_all(b"two\n").unwrap();
+        tee.flush().unwrap();
+        assert_eq!(a, b"one\ntwo\n");
+        assert_eq!(a, b);
+
+        let mut pipe = ClosingPipe {
+            written: Vec::new(),
+            capacity: 4,
+        };
+        let mut file = Vec::new();
+        let mut tee = TeeWriter::new(&mut pipe, &mut file);
+        tee.write_all(b"one\n").unwrap();
+        tee.write_all(b"two\n").unwrap();
+        tee.write_all(b"three\n").unwrap();
+        tee.flush().unwrap();
+        assert_eq!(pipe.written, b"one\n");
+        assert_eq!(file, b"one\ntwo\nthree\n");
+    }
+
+    /// An output which takes at most a few bytes per write, as a pipe may
+    #[derive(Clone, Default)]
+    struct Trickle(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+            std::thread::yield_now();
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_diagnostics_lines_are_intact() {
+        const THREADS: usize = 8;
+        const LINES: usize = 200;
+        let output = Trickle::default();
+        let diagnostics = Diagnostics::new(output.clone());
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let mut diagnostics = diagnostics.clone();
+                scope.spawn(move || {
+                    for line in 0..LINES {
+                        // Each line in several writes
+                        write!(diagnostics, "warning: thread {}", thread).unwrap();
+                        write!(diagnostics, " line {}", line).unwrap();
+                        diagnostics.write_all(b"\n").unwrap();
+                    }
+                });
+            }
+        });
+        drop(diagnostics);
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let mut seen = vec![0; THREADS];
+        for line in written.lines() {
+            let (thread, n) = line
+                .strip_prefix("warning: thread ")
+                .and_then(|rest| rest.split_once(" line "))
+                .unwrap_or_else(|| panic!("torn line {:?}", line));
+            let thread: usize = thread.parse().unwrap();
+            // In order within each thread
+            assert_eq!(n.parse::<usize>().unwrap(), seen[thread], "{:?}", line);
+            seen[thread] += 1;
+        }
+        assert_eq!(seen, [LINES; THREADS]);
+    }
+
+    #[test]
+    fn test_diagnostics_partial_line() {
+        let output = Trickle::default();
+        let mut diagnostics = Diagnostics::new(output.clone());
+        diagnostics.write_all(b"one\ntw").unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"one\n");
+        diagnostics.flush().unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"one\ntw");
+    }
+}
@@ -0,0 +1,232 @@
+//! Modes which collect every record before producing their output.
+
+use json::object::Object as JsonObject;
+use json::JsonValue;
+
+use crate::path::Path;
+use crate::Mp2JsonError;
+
+/// Render a value as an object key: strings as themselves, anything else as
+/// its compact JSON form
+pub(crate) fn key_for(v: &JsonValue) -> String {
+    match v.as_str() {
+        Some(s) => s.to_string(),
+        None => v.dump(),
+    }
+}
+
+/// Deep-merge `source` into `target`: nested objects are merged key by key,
+/// and anything else in `source` replaces what is in `target`, except that
+/// arrays are appended to if `concat_arrays` is set
+fn merge_into(target: &mut JsonObject, mut source: JsonObject, concat_arrays: bool) {
+    for (key, value) in source.iter_mut() {
+        match (target.get_mut(key), value.take()) {
+            (Some(JsonValue::Object(target)), JsonValue::Object(source)) => {
+                merge_into(target, source, concat_arrays)
+            }
+            (Some(JsonValue::Array(target)), JsonValue::Array(source)) if concat_arrays => {
+                target.extend(source)
+            }
+            (_, value) => target.insert(key, value),
+        }
+    }
+}
+
+pub(crate) enum Aggregate {
+    /// Bucket records into arrays by the value at a path
+    GroupBy { path: Path, groups: JsonObject },
+    /// Deep-merge every record, each of which must be an object, into one
+    Merge {
+        merged: JsonObject,
+        concat_arrays: bool,
+    },
+    /// Keep a uniformly random sample of `size` records by reservoir
+    /// sampling, each with its position in the input
+    Sample {
+        size: usize,
+        seen: u64,
+        reservoir: Vec<(u64, JsonValue)>,
+        rng: fastrand::Rng,
+    },
+    /// Count the records with each value at a path
+    CountBy { path: Path, counts: JsonObject },
+}
+
+impl Aggregate {
+    pub(crate) fn group_by(path: Path) -> Self {
+        Aggregate::GroupBy {
+            path,
+            groups: JsonObject::new(),
+        }
+    }
+
+    pub(crate) fn merge(concat_arrays: bool) -> Self {
+        Aggregate::Merge {
+            merged: JsonObject::new(),
+            concat_arrays,
+        }
+    }
+
+    /// Sample `size` records, reproducibly if a `seed` is given
+    pub(crate) fn sample(size: usize, seed: Option<u64>) -> Self {
+        Aggregate::Sample {
+            size,
+            seen: 0,
+            reservoir: Vec::with_capacity(size),
+            rng: seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed),
+        }
+    }
+
+    pub(crate) fn count_by(path: Path) -> Self {
+        Aggregate::CountBy {
+            path,
+            counts: JsonObject::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, v: JsonValue) -> Result<(), Mp2JsonError> {
+        match self {
+            Aggregate::GroupBy { path, groups } => {
+                let key = path
+                    .lookup(&v)
+                    .map(key_for)
+                    .unwrap_or_else(|| "__ungrouped__".to_string());
+                match groups.get_mut(&key) {
+                    Some(JsonValue::Array(records)) => records.push(v),
+                    _ => groups.insert(&key, JsonValue::Array(vec![v])),
+                }
+            }
+            Aggregate::Merge {
+                merged,
+                concat_arrays,
+            } => match v {
+                JsonValue::Object(o) => merge_into(merged, o, *concat_arrays),
+                _ => return Err(Mp2JsonError::MergeRecordNotObject),
+            },
+            Aggregate::Sample {
+                size,
+                seen,
+                reservoir,
+                rng,
+            } => {
+                if reservoir.len() < *size {
+                    reservoir.push((*seen, v));
+                } else if let Ok(i) = usize::try_from(rng.u64(0..=*seen)) {
+                    if i < *size {
+                        reservoir[i] = (*seen, v);
+                    }
+                }
+                *seen += 1;
+            }
+            Aggregate::CountBy { path, counts } => {
+                let key = path
+                    .lookup(&v)
+                    .map(key_for)
+                    .unwrap_or_else(|| "__missing__".to_string());
+                let count = counts.get(&key).and_then(JsonValue::as_u64).unwrap_or(0);
+                counts.insert(&key, (count + 1).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The records to write once the input is finished
+    pub(crate) fn finish(self) -> Vec<JsonValue> {
+        match self {
+            Aggregate::GroupBy { groups, .. } => vec![groups.into()],
+            Aggregate::Merge { merged, .. } => vec![merged.into()],
+            Aggregate::CountBy { counts, .. } => vec![counts.into()],
+            Aggregate::Sample { mut reservoir, .. } => {
+                reservoir.sort_by_key(|(i, _)| *i);
+                reservoir.into_iter().map(|(_, v)| v).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aggregate;
+
+    fn merge(records: Vec<json::JsonValue>, concat_arrays: bool) -> String {
+        let mut merge = Aggregate::merge(concat_arrays);
+        for record in records {
+            merge.add(record).unwrap();
+        }
+        merge.finish()[0].dump()
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(
+            merge(
+                vec![json::object! { "a": 1 }, json::object! { "b": 2 }],
+                false
+            ),
+            r#"{"a":1,"b":2}"#
+        );
+        assert_eq!(
+            merge(
+                vec![json::object! { "a": 1 }, json::object! { "a": 2 }],
+                false
+            ),
+            r#"{"a":2}"#
+        );
+        let records = || {
+            vec![
+                json::object! { "a": { "x": 1, "l": [1] }, "s": "keep" },
+                json::object! { "a": { "y": 2, "l": [2] } },
+            ]
+        };
+        assert_eq!(
+            merge(records(), false),
+            r#"{"a":{"x":1,"l":[2],"y":2},"s":"keep"}"#
+        );
+        assert_eq!(
+            merge(records(), true),
+            r#"{"a":{"x":1,"l":[1,2],"y":2},"s":"keep"}"#
+        );
+        assert_eq!(merge(vec![], false), "{}");
+        assert!(Aggregate::merge(false).add(1.into()).is_err());
+    }
+
+    #[test]
+    fn test_count_by() {
+        let mut count = Aggregate::count_by("type".parse().unwrap());
+        for record in [
+            json::object! { "type": "a" },
+            json::object! { "type": "a" },
+            json::object! { "type": "b" },
+        ] {
+            count.add(record).unwrap();
+        }
+        assert_eq!(count.finish()[0].dump(), r#"{"a":2,"b":1}"#);
+        let mut count = Aggregate::count_by("n".parse().unwrap());
+        for record in [json::object! { "n": 1 }, json::object! {}, 1.into()] {
+            count.add(record).unwrap();
+        }
+        assert_eq!(count.finish()[0].dump(), r#"{"1":1,"__missing__":2}"#);
+    }
+
+    fn sample(size: usize, seed: u64) -> Vec<u64> {
+        let mut sample = Aggregate::sample(size, Some(seed));
+        for i in 0..1000u64 {
+            sample.add(i.into()).unwrap();
+        }
+        sample
+            .finish()
+            .iter()
+            .map(|v| v.as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sample() {
+        let chosen = sample(5, 42);
+        assert_eq!(chosen.len(), 5);
+        assert!(chosen.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sample(5, 42), chosen);
+        assert_ne!(sample(5, 43), chosen);
+        assert_eq!(sample(2000, 42), (0..1000).collect::<Vec<_>>());
+    }
+}
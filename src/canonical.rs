@@ -0,0 +1,203 @@
+//! Canonical JSON as defined by the JSON Canonicalization Scheme (RFC 8785),
+//! for output which is byte-for-byte stable enough to hash or sign.
+
+use std::io::{self, Write};
+
+use json::number::Number;
+use json::JsonValue;
+
+/// Format a double as ECMAScript's `Number.prototype.toString` does, which
+/// is what JCS requires: the shortest digits that round-trip, positioned by
+/// the magnitude of the number
+pub(crate) fn format_double(f: f64) -> String {
+    if f == 0.0 {
+        // Including negative zero
+        return "0".to_string();
+    }
+    // Rust also prints the shortest round-tripping digits
+    let scientific = format!("{:e}", f.abs());
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("scientific notation has an exponent");
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    let n = exponent.parse::<i32>().expect("exponent is an integer") + 1;
+    let sign = if f < 0.0 { "-" } else { "" };
+    let body = if k <= n && n <= 21 {
+        format!("{}{}", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        let (int, frac) = digits.split_at(n as usize);
+        format!("{}.{}", int, frac)
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let point = if rest.is_empty() { "" } else { "." };
+        let exponent_sign = if n > 0 { "+" } else { "-" };
+        format!(
+            "{}{}{}e{}{}",
+            first,
+            point,
+            rest,
+            exponent_sign,
+            (n - 1).abs()
+        )
+    };
+    format!("{}{}", sign, body)
+}
+
+fn format_number(num: &Number) -> String {
+    if num.is_nan() {
+        return "null".to_string();
+    }
+    let (positive, mantissa, exponent) = num.as_parts();
+    // Parse the decimal form rather than converting the parts with float
+    // arithmetic, which can be off by an ulp
+    let f: f64 = format!("{}e{}", mantissa, exponent)
+        .parse()
+        .expect("number parts form a float");
+    format_double(if positive { f } else { -f })
+}
+
+fn write_string<W: Write>(s: &str, output: &mut W) -> io::Result<()> {
+    output.write_all(b"\"")?;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let escape = match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\u{8}' => "\\b".to_string(),
+            '\t' => "\\t".to_string(),
+            '\n' => "\\n".to_string(),
+            '\u{c}' => "\\f".to_string(),
+            '\r' => "\\r".to_string(),
+            c if c < ' ' => format!("\\u{:04x}", u32::from(c)),
+            _ => continue,
+        };
+        output.write_all(&s.as_bytes()[start..i])?;
+        output.write_all(escape.as_bytes())?;
+        start = i + c.len_utf8();
+    }
+    output.write_all(&s.as_bytes()[start..])?;
+    output.write_all(b"\"")
+}
+
+/// Write `v` in canonical form: no whitespace, object keys sorted by their
+/// UTF-16 code units, numbers formatted as ECMAScript does, and only the
+/// characters JSON requires escaped
+pub(crate) fn write<W: Write>(v: &JsonValue, output: &mut W) -> io::Result<()> {
+    match v {
+        JsonValue::Null => output.write_all(b"null"),
+        JsonValue::Boolean(b) => output.write_all(if *b { b"true" } else { b"false" }),
+        JsonValue::Number(n) => output.write_all(format_number(n).as_bytes()),
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            write_string(v.as_str().unwrap_or_default(), output)
+        }
+        JsonValue::Array(a) => {
+            output.write_all(b"[")?;
+            for (i, item) in a.iter().enumerate() {
+                if i > 0 {
+                    output.write_all(b",")?;
+                }
+                write(item, output)?;
+            }
+            output.write_all(b"]")
+        }
+        JsonValue::Object(o) => {
+            let mut entries: Vec<_> = o.iter().collect();
+            entries.sort_by(|a, b| a.0.encode_utf16().cmp(b.0.encode_utf16()));
+            output.write_all(b"{")?;
+            for (i, (k, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    output.write_all(b",")?;
+                }
+                write_string(k, output)?;
+                output.write_all(b":")?;
+                write(item, output)?;
+            }
+            output.write_all(b"}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_double, write};
+
+    fn canonical(text: &str) -> String {
+        let mut out = Vec::new();
+        write(&json::parse(text).unwrap(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_numbers() {
+        // From Appendix B of RFC 8785
+        for (bits, expected) in [
+            (0x0000000000000000u64, "0"),
+            (0x8000000000000000, "0"),
+            (0x0000000000000001, "5e-324"),
+            (0x8000000000000001, "-5e-324"),
+            (0x7fefffffffffffff, "1.7976931348623157e+308"),
+            (0x4340000000000000, "9007199254740992"),
+            (0xc340000000000000, "-9007199254740992"),
+            (0x4430000000000000, "295147905179352830000"),
+            (0x44b52d02c7e14af5, "9.999999999999997e+22"),
+            (0x44b52d02c7e14af6, "1e+23"),
+            (0x3eb0c6f7a0b5ed8d, "0.000001"),
+            (0x3eb0c6f7a0b5ed8c, "9.999999999999997e-7"),
+            (0x41b3de4355555555, "333333333.3333333"),
+        ] {
+            assert_eq!(format_double(f64::from_bits(bits)), expected, "{:#x}", bits);
+        }
+    }
+
+    #[test]
+    fn test_rfc_example() {
+        let input = r#"{
+            "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+            "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+            "literals": [null, true, false]
+        }"#;
+        assert_eq!(
+            canonical(input),
+            concat!(
+                r#"{"literals":[null,true,false],"#,
+                r#""numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"#,
+                r#""string":"€$\u000f\nA'B\"\\\\\"/"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_key_order() {
+        // Sorted by UTF-16 code units, so the emoji's surrogates come before
+        // U+FB33 even though its UTF-8 encoding sorts after
+        let input = r#"{
+            "\u20ac": "Euro Sign",
+            "\r": "Carriage Return",
+            "\ufb33": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "\ud83d\ude00": "Emoji: Grinning Face",
+            "\u0080": "Control",
+            "\u00f6": "Latin Small Letter O With Diaeresis"
+        }"#;
+        let keys: Vec<String> = json::parse(&canonical(input))
+            .unwrap()
+            .entries()
+            .map(|(k, _)| k.to_string())
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "\r",
+                "1",
+                "\u{80}",
+                "\u{f6}",
+                "\u{20ac}",
+                "\u{1f600}",
+                "\u{fb33}"
+            ]
+        );
+    }
+}
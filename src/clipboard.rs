@@ -0,0 +1,100 @@
+//! Reading input from the system clipboard, by way of whichever clipboard
+//! command-line tool the platform provides.
+
+use std::io;
+use std::process::Command;
+
+use clap::ValueEnum;
+
+/// How the text on the clipboard encodes the msgpack input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ClipboardFormat {
+    /// Base64 text
+    #[default]
+    Base64,
+    /// Hex digits, optionally separated by whitespace
+    Hex,
+    /// The bytes of the text itself
+    Raw,
+}
+
+pub trait Clipboard {
+    fn text(&mut self) -> io::Result<String>;
+}
+
+/// The clipboard of the desktop session, read with the first of the usual
+/// clipboard tools which is installed
+pub struct SystemClipboard;
+
+const COMMANDS: &[&[&str]] = &[
+    &["pbpaste"],
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-out"],
+    &["xsel", "--clipboard", "--output"],
+    &["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"],
+];
+
+impl Clipboard for SystemClipboard {
+    fn text(&mut self) -> io::Result<String> {
+        for command in COMMANDS {
+            let output = match Command::new(command[0]).args(&command[1..]).output() {
+                Ok(output) => output,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    command[0],
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            return String::from_utf8(output.stdout)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no clipboard tool found (tried pbpaste, wl-paste, xclip, xsel, and powershell)",
+        ))
+    }
+}
+
+/// Read the clipboard and decode it to msgpack bytes
+pub fn read(clipboard: &mut dyn Clipboard, format: ClipboardFormat) -> Result<Vec<u8>, String> {
+    let text = clipboard.text().map_err(|e| e.to_string())?;
+    match format {
+        ClipboardFormat::Base64 => super::parse_base64(&text).map(|input| input.0),
+        ClipboardFormat::Hex => super::parse_hex(&text).map(|input| input.0),
+        ClipboardFormat::Raw => Ok(text.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, Clipboard, ClipboardFormat};
+
+    struct FakeClipboard(&'static str);
+
+    impl Clipboard for FakeClipboard {
+        fn text(&mut self) -> std::io::Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        assert_eq!(
+            read(&mut FakeClipboard("gaFhoWI=\n"), ClipboardFormat::Base64).unwrap(),
+            b"\x81\xa1a\xa1b"
+        );
+        assert_eq!(
+            read(&mut FakeClipboard("81 a1 61 c3"), ClipboardFormat::Hex).unwrap(),
+            b"\x81\xa1a\xc3"
+        );
+        assert_eq!(
+            read(&mut FakeClipboard("\x01"), ClipboardFormat::Raw).unwrap(),
+            b"\x01"
+        );
+        assert!(read(&mut FakeClipboard("!"), ClipboardFormat::Base64).is_err());
+    }
+}
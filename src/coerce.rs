@@ -0,0 +1,140 @@
+//! Forcing the values at particular paths to a particular JSON type.
+
+use std::fmt;
+use std::str::FromStr;
+
+use json::JsonValue;
+
+use crate::path::Path;
+use crate::Mp2JsonError;
+
+/// A JSON type which values can be coerced to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoerceType {
+    String,
+    Number,
+    Bool,
+}
+
+impl fmt::Display for CoerceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CoerceType::String => "string",
+            CoerceType::Number => "number",
+            CoerceType::Bool => "bool",
+        })
+    }
+}
+
+/// A rule coercing the value at a path, written as `path=type`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coercion {
+    pub path: Path,
+    pub to: CoerceType,
+}
+
+impl FromStr for Coercion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, to) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH=TYPE, got {:?}", s))?;
+        let to = match to {
+            "string" => CoerceType::String,
+            "number" => CoerceType::Number,
+            "bool" => CoerceType::Bool,
+            other => {
+                return Err(format!(
+                    "unknown type {:?}; expected string, number, or bool",
+                    other
+                ))
+            }
+        };
+        Ok(Coercion {
+            path: path.parse()?,
+            to,
+        })
+    }
+}
+
+fn coerce_value(v: &JsonValue, to: CoerceType) -> Option<JsonValue> {
+    match (to, v) {
+        (CoerceType::String, JsonValue::Short(_) | JsonValue::String(_)) => Some(v.clone()),
+        (CoerceType::String, JsonValue::Number(_) | JsonValue::Boolean(_)) => Some(v.dump().into()),
+        (CoerceType::Number, JsonValue::Number(_)) => Some(v.clone()),
+        (CoerceType::Number, JsonValue::Short(_) | JsonValue::String(_)) => {
+            json::parse(v.as_str()?.trim())
+                .ok()
+                .filter(JsonValue::is_number)
+        }
+        (CoerceType::Bool, JsonValue::Boolean(_)) => Some(v.clone()),
+        (CoerceType::Bool, JsonValue::Short(_) | JsonValue::String(_)) => match v.as_str()? {
+            "true" => Some(true.into()),
+            "false" => Some(false.into()),
+            _ => None,
+        },
+        (CoerceType::Bool, JsonValue::Number(_)) => match v.as_u64()? {
+            0 => Some(false.into()),
+            1 => Some(true.into()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Coercion {
+    /// Coerce the value at this rule's path within `record`, if there is one
+    pub fn apply(&self, record: &mut JsonValue) -> Result<(), Mp2JsonError> {
+        if let Some(v) = self.path.lookup_mut(record) {
+            *v = coerce_value(v, self.to).ok_or_else(|| Mp2JsonError::IncompatibleCoercion {
+                path: self.path.to_string(),
+                to: self.to,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::Coercion;
+    use crate::Mp2JsonError;
+
+    fn coerce(rule: &str, mut v: json::JsonValue) -> Result<String, Mp2JsonError> {
+        rule.parse::<Coercion>().unwrap().apply(&mut v)?;
+        Ok(v.dump())
+    }
+
+    #[test]
+    fn test_coerce() {
+        assert_eq!(
+            coerce("id=string", json::object! { "id": 5 }).unwrap(),
+            r#"{"id":"5"}"#
+        );
+        assert_eq!(
+            coerce("id=string", json::object! { "id": "x" }).unwrap(),
+            r#"{"id":"x"}"#
+        );
+        assert_eq!(
+            coerce("a[1]=number", json::object! { "a": [1, " 2.5 "] }).unwrap(),
+            r#"{"a":[1,2.5]}"#
+        );
+        assert_eq!(
+            coerce("ok=bool", json::object! { "ok": 0 }).unwrap(),
+            r#"{"ok":false}"#
+        );
+        assert_eq!(
+            coerce("missing=bool", json::object! { "ok": 0 }).unwrap(),
+            r#"{"ok":0}"#
+        );
+        assert_matches!(
+            coerce("id=number", json::object! { "id": "abc" }),
+            Err(Mp2JsonError::IncompatibleCoercion { .. })
+        );
+        assert!("id=date".parse::<Coercion>().is_err());
+        assert!("id".parse::<Coercion>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! CSV output for streams of flat objects.
+
+use json::JsonValue;
+
+use crate::{is_binary_wrapper, Mp2JsonError};
+
+/// Append `field` to `out`, quoting it if it contains any character which is
+/// special in CSV
+fn push_field(out: &mut String, field: &str) {
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+fn push_row<'a>(out: &mut String, fields: impl Iterator<Item = &'a str>) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_field(out, field);
+    }
+    out.push('\n');
+}
+
+/// Render a value as the text of a CSV cell
+fn cell(key: &str, v: &JsonValue, strict: bool) -> Result<String, Mp2JsonError> {
+    Ok(match v {
+        JsonValue::Null => String::new(),
+        JsonValue::Short(_) | JsonValue::String(_) => v.as_str().unwrap_or_default().to_string(),
+        JsonValue::Number(_) | JsonValue::Boolean(_) => v.dump(),
+        JsonValue::Object(o) if is_binary_wrapper(o) => {
+            o["value"].as_str().unwrap_or_default().to_string()
+        }
+        JsonValue::Object(_) | JsonValue::Array(_) if strict => {
+            return Err(Mp2JsonError::CsvNestedValue(key.to_string()))
+        }
+        JsonValue::Object(_) | JsonValue::Array(_) => v.dump(),
+    })
+}
+
+/// Writes records as CSV rows, taking the header from the first record
+#[derive(Default)]
+pub(crate) struct CsvWriter {
+    header: Option<Vec<String>>,
+}
+
+impl CsvWriter {
+    /// Render one record as CSV, preceded by the header if this is the first
+    pub(crate) fn record(&mut self, v: &JsonValue, strict: bool) -> Result<String, Mp2JsonError> {
+        let o = match v {
+            JsonValue::Object(o) => o,
+            _ => return Err(Mp2JsonError::CsvRecordNotObject),
+        };
+        let mut out = String::new();
+        let header = self.header.get_or_insert_with(|| {
+            let header: Vec<String> = o.iter().map(|(k, _)| k.to_string()).collect();
+            push_row(&mut out, header.iter().map(String::as_str));
+            header
+        });
+        if let Some((k, _)) = o.iter().find(|(k, _)| !header.iter().any(|h| h == k)) {
+            return Err(Mp2JsonError::CsvUnexpectedKey(k.to_string()));
+        }
+        let cells = header
+            .iter()
+            .map(|k| o.get(k).map_or(Ok(String::new()), |v| cell(k, v, strict)))
+            .collect::<Result<Vec<_>, _>>()?;
+        push_row(&mut out, cells.iter().map(String::as_str));
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::CsvWriter;
+    use crate::Mp2JsonError;
+
+    #[test]
+    fn test_csv_rows() {
+        let mut w = CsvWriter::default();
+        assert_eq!(
+            w.record(&json::object! { "a": 1, "b": "x,y" }, false)
+                .unwrap(),
+            "a,b\n1,\"x,y\"\n"
+        );
+        assert_eq!(
+            w.record(&json::object! { "b": "say \"hi\"", "a": [1, 2] }, false)
+                .unwrap(),
+            "\"[1,2]\",\"say \"\"hi\"\"\"\n"
+        );
+        assert_eq!(
+            w.record(
+                &json::object! { "a": { "encoding": "base64", "value": "YmFy" } },
+                true
+            )
+            .unwrap(),
+            "YmFy,\n"
+        );
+        assert_matches!(
+            w.record(&json::object! { "a": [1] }, true),
+            Err(Mp2JsonError::CsvNestedValue(k)) if k == "a"
+        );
+        assert_matches!(
+            w.record(&json::object! { "c": 1 }, false),
+            Err(Mp2JsonError::CsvUnexpectedKey(k)) if k == "c"
+        );
+        assert_matches!(
+            w.record(&json::array![1], false),
+            Err(Mp2JsonError::CsvRecordNotObject)
+        );
+    }
+}
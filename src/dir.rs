@@ -0,0 +1,172 @@
+//! Reading the files in a directory, in sorted order, as one concatenated
+//! input.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of
+/// characters and `?` any one character
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*`, if the match so far fails
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    backtrack = Some((star, from + 1));
+                    p = star + 1;
+                    n = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The files in `dir` whose names match `glob`, relative to `dir` and
+/// sorted, including those in subdirectories if `recursive`
+pub fn list(dir: &Path, glob: Option<&str>, recursive: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let walk = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(if recursive { usize::MAX } else { 1 })
+        .follow_links(true);
+    for entry in walk {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy();
+        if glob.is_some_and(|glob| !glob_match(glob, &name)) {
+            continue;
+        }
+        let path = entry
+            .path()
+            .strip_prefix(dir)
+            .expect("walked paths are within the directory");
+        files.push(path.to_path_buf());
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A reader over several files one after another, each opened only once
+/// the last is finished
+pub struct Files {
+    dir: PathBuf,
+    pending: VecDeque<(PathBuf, u64)>,
+    /// The file being read, with its expected length and how much has been
+    /// read of it
+    current: Option<(File, PathBuf, u64, u64)>,
+}
+
+impl Files {
+    /// Read the files listed in `dir`, giving also the name of each with
+    /// the offset at which it starts in the combined input
+    pub fn open(
+        dir: &Path,
+        glob: Option<&str>,
+        recursive: bool,
+    ) -> io::Result<(Self, Vec<(u64, String)>)> {
+        let mut pending = VecDeque::new();
+        let mut sources = Vec::new();
+        let mut offset = 0;
+        for path in list(dir, glob, recursive)? {
+            let len = dir.join(&path).metadata()?.len();
+            sources.push((offset, path.to_string_lossy().into_owned()));
+            offset += len;
+            pending.push_back((path, len));
+        }
+        let files = Files {
+            dir: dir.to_path_buf(),
+            pending,
+            current: None,
+        };
+        Ok((files, sources))
+    }
+}
+
+impl Read for Files {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (file, path, expected, read) = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let Some((path, expected)) = self.pending.pop_front() else {
+                        return Ok(0);
+                    };
+                    let file = File::open(self.dir.join(&path))?;
+                    self.current.insert((file, path, expected, 0))
+                }
+            };
+            let n = file.read(buf)?;
+            *read += n as u64;
+            // The offsets of the files were fixed when they were listed
+            if *read > *expected || (n == 0 && *read != *expected) {
+                return Err(io::Error::other(format!(
+                    "{} changed size while being read",
+                    path.display()
+                )));
+            }
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use super::{glob_match, list, Files};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.msgpack", "a.msgpack"));
+        assert!(glob_match("*.msgpack", ".msgpack"));
+        assert!(!glob_match("*.msgpack", "a.msgpack.bak"));
+        assert!(glob_match("part-??.*", "part-01.mp"));
+        assert!(!glob_match("part-??.*", "part-1.mp"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b*", "xxbxxaxx"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_list_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("b.mp"), b"\x02").unwrap();
+        fs::write(dir.path().join("a.mp"), b"\x01").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"").unwrap();
+        fs::create_dir(dir.path().join("c")).unwrap();
+        fs::write(dir.path().join("c").join("d.mp"), b"\x03\x04").unwrap();
+        assert_eq!(
+            list(dir.path(), Some("*.mp"), false).unwrap(),
+            [std::path::Path::new("a.mp"), "b.mp".as_ref()]
+        );
+        let (mut files, sources) = Files::open(dir.path(), Some("*.mp"), true).unwrap();
+        let sources: Vec<(u64, &str)> = sources.iter().map(|(o, n)| (*o, n.as_str())).collect();
+        let nested = format!("c{}d.mp", std::path::MAIN_SEPARATOR);
+        assert_eq!(sources, [(0, "a.mp"), (1, "b.mp"), (2, nested.as_str())]);
+        let mut contents = Vec::new();
+        files.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"\x01\x02\x03\x04");
+    }
+}
@@ -0,0 +1,255 @@
+//! Writing msgpack values straight to compact JSON, without building a
+//! [`json::JsonValue`] first.
+//!
+//! This only covers values whose conversion involves no policy: the
+//! converter only writes directly under options which leave every value as
+//! it is, and [`supported`] checks each value for anything the remaining
+//! policies act on. Anything else takes the normal path, so the output is
+//! the same either way.
+
+use std::io::{self, Write};
+
+use json::codegen::Generator;
+use json::number::Number;
+use rmpv::Value as MpValue;
+
+use crate::generator::OutputGenerator;
+use crate::{integer_number, FloatNotation};
+
+/// Maps with at most this many keys are checked for duplicates pairwise
+/// rather than by collecting the keys
+const SMALL_MAP: usize = 16;
+
+/// Binary values larger than this are worth streaming to the output rather
+/// than collecting the record first
+const LARGE_BINARY: usize = 64 * 1024;
+
+fn has_duplicate_keys(m: &[(MpValue, MpValue)]) -> bool {
+    if m.len() <= SMALL_MAP {
+        m.iter()
+            .enumerate()
+            .any(|(i, (k, _))| m[..i].iter().any(|(seen, _)| seen == k))
+    } else {
+        let mut seen = std::collections::HashSet::with_capacity(m.len());
+        !m.iter().all(|(k, _)| seen.insert(k.as_str()))
+    }
+}
+
+/// Whether `v` converts the same way under any options [`write`] can stand
+/// in for: its strings and keys are UTF-8, its map keys are strings and
+/// distinct, its floats are finite, and it has no ext values
+pub(crate) fn supported(v: &MpValue) -> bool {
+    match v {
+        MpValue::Nil | MpValue::Boolean(_) | MpValue::Integer(_) | MpValue::Binary(_) => true,
+        MpValue::F32(f) => f.is_finite(),
+        MpValue::F64(f) => f.is_finite(),
+        MpValue::String(s) => s.is_str(),
+        MpValue::Array(a) => a.iter().all(supported),
+        MpValue::Map(m) => {
+            m.iter().all(|(k, v)| k.is_str() && supported(v)) && !has_duplicate_keys(m)
+        }
+        MpValue::Ext(..) => false,
+    }
+}
+
+/// Whether `v` holds a binary value large enough that it should be written
+/// straight to the output
+pub(crate) fn has_large_binary(v: &MpValue) -> bool {
+    match v {
+        MpValue::Binary(b) => b.len() > LARGE_BINARY,
+        MpValue::Array(a) => a.iter().any(has_large_binary),
+        MpValue::Map(m) => m.iter().any(|(_, v)| has_large_binary(v)),
+        _ => false,
+    }
+}
+
+fn write_inner<W: Write>(v: &MpValue, g: &mut OutputGenerator<'_, W>) -> io::Result<()> {
+    match v {
+        MpValue::Nil => g.write(b"null"),
+        MpValue::Boolean(b) => g.write(if *b { b"true" } else { b"false" }),
+        MpValue::Integer(i) => {
+            let number = match (i.as_i64(), i.as_u64()) {
+                (Some(i), _) => integer_number(i),
+                (None, Some(u)) => Number::from(u),
+                (None, None) => unreachable!("msgpack integers fit in an i64 or u64"),
+            };
+            g.write_number(&number)
+        }
+        MpValue::F32(f) => g.write_number(&f64::from(*f).into()),
+        MpValue::F64(f) => g.write_number(&(*f).into()),
+        MpValue::String(s) => g.write_string(s.as_str().unwrap_or_default()),
+        MpValue::Binary(b) => {
+            g.write(br#"{"encoding":"base64","value":""#)?;
+            // Encoded a chunk at a time, rather than into a string which
+            // would be larger than the value itself
+            let mut encoder = base64::write::EncoderWriter::new(g.get_writer(), base64::STANDARD);
+            encoder.write_all(b)?;
+            encoder.finish()?;
+            drop(encoder);
+            g.write(br#""}"#)
+        }
+        MpValue::Array(a) => {
+            g.write_char(b'[')?;
+            for (i, item) in a.iter().enumerate() {
+                if i > 0 {
+                    g.write_char(b',')?;
+                }
+                write_inner(item, g)?;
+            }
+            g.write_char(b']')
+        }
+        MpValue::Map(m) => {
+            g.write_char(b'{')?;
+            for (i, (k, item)) in m.iter().enumerate() {
+                if i > 0 {
+                    g.write_char(b',')?;
+                }
+                g.write_string(k.as_str().unwrap_or_default())?;
+                g.write_char(b':')?;
+                write_inner(item, g)?;
+            }
+            g.write_char(b'}')
+        }
+        MpValue::Ext(..) => unreachable!("ext values are not supported"),
+    }
+}
+
+/// Write `v`, which must be [`supported`], exactly as its conversion would
+/// be written compactly with the default options
+pub(crate) fn write<W: Write>(v: &MpValue, output: &mut W) -> io::Result<()> {
+    write_inner(
+        v,
+        &mut OutputGenerator::new(output, None, FloatNotation::Auto),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rmpv::Value as MpValue;
+
+    use crate::{ConvertOptions, Converter, LargeIntegers};
+
+    const STRINGS: &[&str] = &[
+        "",
+        "a",
+        "b",
+        "quote\"",
+        "tab\t",
+        "\u{1}",
+        "\u{e9}t\u{e9}",
+        "\u{1f600}",
+    ];
+
+    fn random_value(rng: &mut fastrand::Rng, depth: usize) -> MpValue {
+        let leaf_kinds = 9;
+        let kinds = if depth < 4 {
+            leaf_kinds + 2
+        } else {
+            leaf_kinds
+        };
+        match rng.usize(..kinds) {
+            0 => MpValue::Nil,
+            1 => rng.bool().into(),
+            2 if rng.bool() => i64::MIN.into(),
+            2 => rng.i64(..).into(),
+            3 => [0, u64::MAX, i64::MAX as u64 + 1, 1 << 53][rng.usize(..4)].into(),
+            4 => MpValue::F32(f32::from_bits(rng.u32(..))),
+            5 => {
+                MpValue::F64([rng.f64() * 1e20, -0.0, 1.0, f64::NAN, f64::INFINITY][rng.usize(..5)])
+            }
+            6 => STRINGS[rng.usize(..STRINGS.len())].into(),
+            7 if rng.bool() => MpValue::Binary((0..rng.usize(..8)).map(|_| rng.u8(..)).collect()),
+            7 => MpValue::Ext(rng.i8(..), vec![rng.u8(..)]),
+            8 => MpValue::Array(Vec::new()),
+            9 => MpValue::Array(
+                (0..rng.usize(..4))
+                    .map(|_| random_value(rng, depth + 1))
+                    .collect(),
+            ),
+            _ => MpValue::Map(
+                (0..rng.usize(..4))
+                    .map(|_| {
+                        // Few enough keys that some collide, and sometimes not a string
+                        let key = if rng.usize(..20) == 0 {
+                            MpValue::from(1)
+                        } else {
+                            STRINGS[rng.usize(..3)].into()
+                        };
+                        (key, random_value(rng, depth + 1))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn run(
+        input: &[u8],
+        direct: bool,
+        array: bool,
+        jobs: usize,
+        options: ConvertOptions,
+    ) -> (String, String) {
+        let c = Converter {
+            direct,
+            array,
+            jobs,
+            errors_as_json: true,
+            options,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let result = c.run(input, &mut output);
+        (String::from_utf8(output).unwrap(), format!("{:?}", result))
+    }
+
+    #[test]
+    fn test_matches_conversion() {
+        let mut rng = fastrand::Rng::with_seed(154);
+        for _ in 0..200 {
+            let mut input = Vec::new();
+            for _ in 0..rng.usize(1..8) {
+                if rng.usize(..10) == 0 {
+                    // A str of invalid UTF-8, which rmpv can't encode
+                    input.extend_from_slice(b"\x81\xa1a\xa2\xffa");
+                } else {
+                    rmpv::encode::write_value(&mut input, &random_value(&mut rng, 0)).unwrap();
+                }
+            }
+            let policies: [fn() -> ConvertOptions; 3] =
+                [ConvertOptions::default, ConvertOptions::strict, || {
+                    ConvertOptions {
+                        large_integers: LargeIntegers::String,
+                        ..Default::default()
+                    }
+                }];
+            for options in policies {
+                for (array, jobs) in [(false, 1), (true, 1), (false, 2)] {
+                    assert_eq!(
+                        run(&input, true, array, jobs, options()),
+                        run(&input, false, array, jobs, options()),
+                        "input {:x?}",
+                        input
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_binary_matches_conversion() {
+        let mut input = Vec::new();
+        for v in [
+            MpValue::from(1),
+            MpValue::Array(vec![MpValue::Binary(vec![0xab; 100_000])]),
+            MpValue::from(2),
+        ] {
+            rmpv::encode::write_value(&mut input, &v).unwrap();
+        }
+        for (array, jobs) in [(false, 1), (true, 1), (false, 2)] {
+            assert_eq!(
+                run(&input, true, array, jobs, ConvertOptions::default()),
+                run(&input, false, array, jobs, ConvertOptions::default())
+            );
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Transcoding of the (UTF-8) JSON output into other character encodings.
+
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+/// The character encoding of the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputEncoding {
+    #[default]
+    Utf8,
+    /// UTF-16, little-endian, preceded by a byte order mark
+    Utf16le,
+    /// ISO-8859-1; characters outside it are written as JSON `\u` escapes
+    Latin1,
+}
+
+/// A writer which accepts UTF-8 and writes it to `inner` in another encoding
+pub struct EncodingWriter<W> {
+    inner: W,
+    encoding: OutputEncoding,
+    /// The start of a UTF-8 sequence which was split across writes
+    pending: Vec<u8>,
+    started: bool,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, encoding: OutputEncoding) -> Self {
+        EncodingWriter {
+            inner,
+            encoding,
+            pending: Vec::new(),
+            started: false,
+        }
+    }
+
+    fn encode(&self, s: &str, out: &mut Vec<u8>) {
+        match self.encoding {
+            OutputEncoding::Utf8 => out.extend_from_slice(s.as_bytes()),
+            OutputEncoding::Utf16le => {
+                for unit in s.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            OutputEncoding::Latin1 => {
+                for c in s.chars() {
+                    if let Ok(b) = u8::try_from(u32::from(c)) {
+                        out.push(b);
+                    } else {
+                        // Non-ASCII characters only occur inside JSON strings,
+                        // so they can always be escaped
+                        let mut units = [0; 2];
+                        for unit in c.encode_utf16(&mut units) {
+                            out.extend_from_slice(format!("\\u{:04x}", unit).as_bytes());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() * 2 + 2);
+        if !self.started {
+            self.started = true;
+            if self.encoding == OutputEncoding::Utf16le {
+                out.extend_from_slice(&[0xff, 0xfe]);
+            }
+        }
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        // SAFETY: checked just above
+        let s = unsafe { std::str::from_utf8_unchecked(&self.pending[..valid]) };
+        self.encode(s, &mut out);
+        self.pending.drain(..valid);
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{EncodingWriter, OutputEncoding};
+
+    #[test]
+    fn test_utf16le() {
+        let mut out = Vec::new();
+        let mut w = EncodingWriter::new(&mut out, OutputEncoding::Utf16le);
+        w.write_all(br#"{"a":1}"#).unwrap();
+        assert_eq!(
+            out,
+            b"\xff\xfe{\x00\"\x00a\x00\"\x00:\x001\x00}\x00".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_latin1_split_sequences() {
+        let mut out = Vec::new();
+        let mut w = EncodingWriter::new(&mut out, OutputEncoding::Latin1);
+        let s = "\"é€😀\"".as_bytes();
+        // Feed one byte at a time so every multi-byte sequence is split
+        for b in s {
+            w.write_all(&[*b]).unwrap();
+        }
+        assert_eq!(out, b"\"\xe9\\u20ac\\ud83d\\ude00\"".to_vec());
+    }
+}
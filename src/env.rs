@@ -0,0 +1,170 @@
+//! Shell variable assignments for flat objects, for sourcing into a shell.
+
+use json::JsonValue;
+
+use crate::{is_binary_wrapper, Mp2JsonError};
+
+/// How to write records as assignments
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EnvStyle {
+    /// Begin each assignment with `export`
+    pub(crate) export: bool,
+    /// Write nested values as a variable per leaf, named by joining the
+    /// keys (and array indices) on the way to it with `_`
+    pub(crate) flatten: bool,
+    /// Uppercase each name and replace the characters a shell doesn't allow
+    /// in it with `_`; otherwise such names are rejected
+    pub(crate) sanitize_keys: bool,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Quote `value` for a POSIX shell, in single quotes, within which only a
+/// single quote itself needs escaping
+fn push_quoted(out: &mut String, value: &str) {
+    out.push('\'');
+    out.push_str(&value.replace('\'', r"'\''"));
+    out.push('\'');
+}
+
+impl EnvStyle {
+    fn push_assignment(
+        &self,
+        out: &mut String,
+        name: &str,
+        value: &str,
+    ) -> Result<(), Mp2JsonError> {
+        let name = if self.sanitize_keys {
+            sanitize(name)
+        } else if is_identifier(name) {
+            name.to_string()
+        } else {
+            return Err(Mp2JsonError::EnvInvalidName(name.to_string()));
+        };
+        if self.export {
+            out.push_str("export ");
+        }
+        out.push_str(&name);
+        out.push('=');
+        push_quoted(out, value);
+        out.push('\n');
+        Ok(())
+    }
+
+    fn push_value(&self, out: &mut String, name: &str, v: &JsonValue) -> Result<(), Mp2JsonError> {
+        match v {
+            JsonValue::Null => self.push_assignment(out, name, ""),
+            JsonValue::Short(_) | JsonValue::String(_) => {
+                self.push_assignment(out, name, v.as_str().unwrap_or_default())
+            }
+            JsonValue::Number(_) | JsonValue::Boolean(_) => {
+                self.push_assignment(out, name, &v.dump())
+            }
+            JsonValue::Object(o) if is_binary_wrapper(o) => {
+                self.push_assignment(out, name, o["value"].as_str().unwrap_or_default())
+            }
+            JsonValue::Object(o) if self.flatten => o
+                .iter()
+                .try_for_each(|(k, v)| self.push_value(out, &format!("{}_{}", name, k), v)),
+            JsonValue::Array(a) if self.flatten => a
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, v)| self.push_value(out, &format!("{}_{}", name, i), v)),
+            JsonValue::Object(_) | JsonValue::Array(_) => {
+                Err(Mp2JsonError::EnvNestedValue(name.to_string()))
+            }
+        }
+    }
+
+    /// Render one record as a line per variable
+    pub(crate) fn record(&self, v: &JsonValue) -> Result<String, Mp2JsonError> {
+        let JsonValue::Object(o) = v else {
+            return Err(Mp2JsonError::EnvRecordNotObject);
+        };
+        let mut out = String::new();
+        for (k, v) in o.iter() {
+            self.push_value(&mut out, k, v)?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::EnvStyle;
+    use crate::Mp2JsonError;
+
+    #[test]
+    fn test_env() {
+        let style = EnvStyle::default();
+        assert_eq!(
+            style
+                .record(&json::object! { "host": "x", "port": 8080 })
+                .unwrap(),
+            "host='x'\nport='8080'\n"
+        );
+        assert_eq!(
+            style
+                .record(&json::object! { "a": "it's", "b": null, "c": { "encoding": "base64", "value": "YmFy" } })
+                .unwrap(),
+            "a='it'\\''s'\nb=''\nc='YmFy'\n"
+        );
+        assert_matches!(
+            style.record(&json::object! { "a": [1] }),
+            Err(Mp2JsonError::EnvNestedValue(k)) if k == "a"
+        );
+        assert_matches!(
+            style.record(&json::object! { "db-host": 1 }),
+            Err(Mp2JsonError::EnvInvalidName(k)) if k == "db-host"
+        );
+        assert_matches!(
+            style.record(&json::array![1]),
+            Err(Mp2JsonError::EnvRecordNotObject)
+        );
+    }
+
+    #[test]
+    fn test_env_flatten_and_sanitize() {
+        let style = EnvStyle {
+            export: true,
+            flatten: true,
+            sanitize_keys: true,
+        };
+        assert_eq!(
+            style
+                .record(&json::object! { "db": { "host-name": "x", "ports": [1, 2] }, "1st": true })
+                .unwrap(),
+            concat!(
+                "export DB_HOST_NAME='x'\n",
+                "export DB_PORTS_0='1'\n",
+                "export DB_PORTS_1='2'\n",
+                "export _1ST='true'\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Built-in decoders for ext types whose payloads are plain numbers.
+
+use std::str::FromStr;
+
+/// How an ext payload is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtPayload {
+    /// A big-endian IEEE754 double
+    F64,
+    /// A big-endian IEEE754 single
+    F32,
+    /// A big-endian IEEE754 decimal128, with its coefficient as a binary
+    /// integer (the BID layout, as used by BSON), written as a decimal
+    /// string since a double can't hold it exactly
+    Decimal128,
+}
+
+/// A decoded ext payload
+pub(crate) enum Decoded {
+    Float(f64),
+    Decimal(String),
+}
+
+/// The bias of a decimal128 exponent
+const DECIMAL128_BIAS: i32 = 6176;
+
+/// The largest decimal128 coefficient, 10^34 - 1; larger encodings mean 0
+const DECIMAL128_MAX_COEFFICIENT: u128 = 10u128.pow(34) - 1;
+
+/// Write a decimal128 as the IEEE 754 scientific string of its value, as
+/// `Decimal128.toString` does: in plain notation unless the exponent is
+/// positive or the value is very small, and keeping trailing zeros, so
+/// that `1.0` and `1` are distinct
+fn decimal128_to_string(bytes: [u8; 16]) -> String {
+    let bits = u128::from_be_bytes(bytes);
+    let sign = if bits >> 127 == 1 { "-" } else { "" };
+    let (exponent, coefficient) = match (bits >> 122) & 0x1f {
+        0b11111 => return "NaN".to_string(),
+        0b11110 => return format!("{}Infinity", sign),
+        // The coefficient has an implicit leading 100, which always makes it
+        // too large to be valid
+        combination if combination >> 3 == 0b11 => ((bits >> 111) & 0x3fff, 0),
+        _ => ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1)),
+    };
+    let coefficient = if coefficient > DECIMAL128_MAX_COEFFICIENT {
+        0
+    } else {
+        coefficient
+    };
+    let exponent = exponent as i32 - DECIMAL128_BIAS;
+    let digits = coefficient.to_string();
+    let adjusted = exponent + digits.len() as i32 - 1;
+    if exponent <= 0 && adjusted >= -6 {
+        let point = digits.len() as i32 + exponent;
+        if exponent == 0 {
+            format!("{}{}", sign, digits)
+        } else if point > 0 {
+            let (whole, fraction) = digits.split_at(point as usize);
+            format!("{}{}.{}", sign, whole, fraction)
+        } else {
+            format!("{}0.{}{}", sign, "0".repeat(-point as usize), digits)
+        }
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let point = if rest.is_empty() { "" } else { "." };
+        format!("{}{}{}{}E{:+}", sign, first, point, rest, adjusted)
+    }
+}
+
+/// Decode ext values with a given type code as numbers, written `f64=N`,
+/// `f32=N` or `decimal128=N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtDecoder {
+    pub payload: ExtPayload,
+    pub type_code: i8,
+}
+
+impl ExtDecoder {
+    /// Decode `bytes` if this decoder applies to them; payloads of the wrong
+    /// length are left alone
+    pub(crate) fn decode(&self, type_code: i8, bytes: &[u8]) -> Option<Decoded> {
+        if type_code != self.type_code {
+            return None;
+        }
+        match self.payload {
+            ExtPayload::F64 => bytes
+                .try_into()
+                .ok()
+                .map(|b| Decoded::Float(f64::from_be_bytes(b))),
+            ExtPayload::F32 => bytes
+                .try_into()
+                .ok()
+                .map(|b| Decoded::Float(f32::from_be_bytes(b).into())),
+            ExtPayload::Decimal128 => bytes
+                .try_into()
+                .ok()
+                .map(|b| Decoded::Decimal(decimal128_to_string(b))),
+        }
+    }
+}
+
+impl FromStr for ExtDecoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (payload, type_code) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KIND=TYPE_CODE, got {:?}", s))?;
+        let payload = match payload {
+            "f64" => ExtPayload::F64,
+            "f32" => ExtPayload::F32,
+            "decimal128" => ExtPayload::Decimal128,
+            other => {
+                return Err(format!(
+                    "unknown ext payload {:?}; expected f64, f32 or decimal128",
+                    other
+                ))
+            }
+        };
+        let type_code = type_code
+            .parse()
+            .map_err(|_| format!("invalid ext type code {:?}", type_code))?;
+        Ok(ExtDecoder { payload, type_code })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decimal128_to_string, DECIMAL128_BIAS, DECIMAL128_MAX_COEFFICIENT};
+
+    fn decimal(negative: bool, exponent: i32, coefficient: u128) -> [u8; 16] {
+        let exponent = (exponent + DECIMAL128_BIAS) as u128;
+        (u128::from(negative) << 127 | exponent << 113 | coefficient).to_be_bytes()
+    }
+
+    #[test]
+    fn test_negative_zero() {
+        assert_eq!(decimal128_to_string(decimal(true, 0, 0)), "-0");
+        assert_eq!(decimal128_to_string(decimal(true, -2, 0)), "-0.00");
+        assert_eq!(decimal128_to_string(decimal(true, 5, 0)), "-0E+5");
+    }
+
+    #[test]
+    fn test_exponent_limits() {
+        assert_eq!(decimal128_to_string(decimal(false, 6111, 1)), "1E+6111");
+        assert_eq!(
+            decimal128_to_string(decimal(false, 6111, DECIMAL128_MAX_COEFFICIENT)),
+            "9.999999999999999999999999999999999E+6144"
+        );
+        assert_eq!(decimal128_to_string(decimal(true, -6176, 1)), "-1E-6176");
+        // With the coefficient's implicit leading 100, the exponent moves
+        // down two bits; the value is 0 whatever it is
+        let large = (0b11u128 << 125 | 0x2fff << 111).to_be_bytes();
+        assert_eq!(decimal128_to_string(large), "0E+6111");
+    }
+
+    #[test]
+    fn test_special_values() {
+        let special = |bits: u128| decimal128_to_string((bits << 112).to_be_bytes());
+        assert_eq!(special(0x7800), "Infinity");
+        assert_eq!(special(0xf800), "-Infinity");
+        // Quiet and signalling NaNs, of either sign and with any payload
+        assert_eq!(special(0x7c00), "NaN");
+        assert_eq!(special(0x7e00), "NaN");
+        assert_eq!(special(0xfc00), "NaN");
+        assert_eq!(special(0x7c00 | 0x1234), "NaN");
+    }
+}
@@ -0,0 +1,20 @@
+//! Inputs and outputs given as file descriptors already open in this
+//! process, for pipelines which pass data on descriptors other than standard
+//! input and output.
+
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Take ownership of the open descriptor `fd`, which is closed when the
+/// returned file is dropped. Nothing else in the process may use it after;
+/// the command line only allows descriptors from 3 up, leaving standard
+/// input, output and error alone.
+pub fn take(fd: RawFd) -> std::io::Result<File> {
+    // SAFETY: F_GETFD only reads the descriptor's flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor is open, and nothing else in this process owns
+    // it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
@@ -0,0 +1,283 @@
+//! Splitting a msgpack stream into the raw bytes of each top-level value
+//! without decoding it.
+
+use std::io::{self, Read};
+
+use rmp::Marker;
+use rmpv::decode::Error as DecodeError;
+
+use crate::Mp2JsonError;
+
+/// The least to allocate at once while reading data
+const MIN_CHUNK: usize = 64 * 1024;
+
+fn read_data<R: Read>(r: &mut R, buf: &mut Vec<u8>, len: u64) -> Result<(), DecodeError> {
+    // Don't trust the length enough to allocate for it up front, but still
+    // grow to fit it exactly, rather than to as much as twice the size
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(buf.capacity().max(MIN_CHUNK) as u64);
+        if chunk as usize > MIN_CHUNK {
+            buf.reserve_exact(chunk as usize);
+        } else {
+            buf.reserve(chunk as usize);
+        }
+        let read = r
+            .take(chunk)
+            .read_to_end(buf)
+            .map_err(DecodeError::InvalidDataRead)?;
+        if (read as u64) < chunk {
+            return Err(DecodeError::InvalidDataRead(
+                io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        remaining -= chunk;
+    }
+    Ok(())
+}
+
+fn read_len<R: Read>(r: &mut R, buf: &mut Vec<u8>, size: usize) -> Result<u64, DecodeError> {
+    let start = buf.len();
+    read_data(r, buf, size as u64)?;
+    Ok(buf[start..]
+        .iter()
+        .fold(0, |len, b| len << 8 | u64::from(*b)))
+}
+
+/// Append the bytes of the next msgpack value in `r` to `buf`, returning
+/// `false` if the input ended cleanly before the value began
+///
+/// Nesting is tracked with a counter rather than recursion, so arbitrarily
+/// deep values can be framed. If the input ends partway through the value,
+/// the error is [`Mp2JsonError::TruncatedInput`]; other read errors are
+/// reported as rmpv would report them. Either way, whatever was read of the
+/// value is left in `buf`.
+pub(crate) fn read_frame<R: Read>(r: &mut R, buf: &mut Vec<u8>) -> Result<bool, Mp2JsonError> {
+    read_value_frame(r, buf).map_err(|e| match e {
+        Mp2JsonError::RmpDecode(
+            DecodeError::InvalidMarkerRead(e) | DecodeError::InvalidDataRead(e),
+        ) if e.kind() == io::ErrorKind::UnexpectedEof => Mp2JsonError::TruncatedInput,
+        e => e,
+    })
+}
+
+/// Consume the byte which must follow each value when the values of `r` are
+/// delimited by `marker`
+pub(crate) fn read_marker<R: Read>(r: &mut R, marker: u8) -> Result<(), Mp2JsonError> {
+    let mut byte = [0];
+    match r.read_exact(&mut byte) {
+        Ok(()) if byte[0] == marker => Ok(()),
+        Ok(()) => Err(Mp2JsonError::MissingFrameMarker(marker)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(Mp2JsonError::MissingFrameMarker(marker))
+        }
+        Err(e) => Err(DecodeError::InvalidMarkerRead(e).into()),
+    }
+}
+
+/// Reads `buf` from `pos` on, then `inner`, keeping what it reads from
+/// `inner` in `buf` so that it can be read again from an earlier `pos`
+struct Replay<'a, R> {
+    buf: &'a mut Vec<u8>,
+    pos: usize,
+    inner: &'a mut R,
+}
+
+impl<R: Read> Read for Replay<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let read = if self.pos < self.buf.len() {
+            let read = out.len().min(self.buf.len() - self.pos);
+            out[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+            read
+        } else {
+            let read = self.inner.read(out)?;
+            self.buf.extend_from_slice(&out[..read]);
+            read
+        };
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// Find the first offset in `r`, within `limit` bytes of its start, at
+/// which a whole msgpack value decodes under `opts`, trying each offset in
+/// turn. Returns the offset, with the bytes from there on which were read
+/// in looking, to be read before the rest of `r`.
+///
+/// An input which is empty from the start needs no resynchronizing, but one
+/// which runs out before any value is found fails like one which has none
+/// within `limit` bytes.
+pub(crate) fn resync<R: Read>(
+    r: &mut R,
+    limit: u64,
+    opts: &crate::ConvertOptions,
+) -> Result<(u64, Vec<u8>), Mp2JsonError> {
+    let mut buf = Vec::new();
+    for offset in 0..=limit {
+        let pos = offset as usize;
+        let mut frame = Vec::new();
+        let found = read_frame(
+            &mut Replay {
+                buf: &mut buf,
+                pos,
+                inner: r,
+            },
+            &mut frame,
+        );
+        match found {
+            Ok(false) if offset == 0 => return Ok((0, buf)),
+            Ok(false) => break,
+            Ok(true) if crate::decode_frame(&frame, opts).is_ok() => {
+                return Ok((offset, buf.split_off(pos)))
+            }
+            Ok(true) => {}
+            // Failing to read at all, rather than finding the input cut off
+            Err(e @ Mp2JsonError::RmpDecode(_)) => return Err(e),
+            Err(_) => {}
+        }
+    }
+    Err(Mp2JsonError::ResyncFailed(limit))
+}
+
+fn read_value_frame<R: Read>(r: &mut R, buf: &mut Vec<u8>) -> Result<bool, Mp2JsonError> {
+    let mut pending: u64 = 1;
+    let mut first = true;
+    while pending > 0 {
+        pending -= 1;
+        let mut marker = [0];
+        if let Err(e) = r.read_exact(&mut marker) {
+            if first && e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(false);
+            }
+            return Err(DecodeError::InvalidMarkerRead(e).into());
+        }
+        first = false;
+        buf.push(marker[0]);
+        match Marker::from_u8(marker[0]) {
+            Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => {
+            }
+            // rmpv would decode this as nil
+            Marker::Reserved => return Err(Mp2JsonError::ReservedMarker(marker[0])),
+            Marker::U8 | Marker::I8 => read_data(r, buf, 1)?,
+            Marker::U16 | Marker::I16 => read_data(r, buf, 2)?,
+            Marker::U32 | Marker::I32 | Marker::F32 => read_data(r, buf, 4)?,
+            Marker::U64 | Marker::I64 | Marker::F64 => read_data(r, buf, 8)?,
+            Marker::FixStr(len) => read_data(r, buf, len.into())?,
+            Marker::Str8 | Marker::Bin8 => {
+                let len = read_len(r, buf, 1)?;
+                read_data(r, buf, len)?
+            }
+            Marker::Str16 | Marker::Bin16 => {
+                let len = read_len(r, buf, 2)?;
+                read_data(r, buf, len)?
+            }
+            Marker::Str32 | Marker::Bin32 => {
+                let len = read_len(r, buf, 4)?;
+                read_data(r, buf, len)?
+            }
+            Marker::FixArray(len) => pending += u64::from(len),
+            Marker::Array16 => pending += read_len(r, buf, 2)?,
+            Marker::Array32 => pending += read_len(r, buf, 4)?,
+            Marker::FixMap(len) => pending += 2 * u64::from(len),
+            Marker::Map16 => pending += 2 * read_len(r, buf, 2)?,
+            Marker::Map32 => pending += 2 * read_len(r, buf, 4)?,
+            Marker::FixExt1 => read_data(r, buf, 2)?,
+            Marker::FixExt2 => read_data(r, buf, 3)?,
+            Marker::FixExt4 => read_data(r, buf, 5)?,
+            Marker::FixExt8 => read_data(r, buf, 9)?,
+            Marker::FixExt16 => read_data(r, buf, 17)?,
+            Marker::Ext8 => {
+                let len = read_len(r, buf, 1)?;
+                read_data(r, buf, len + 1)?
+            }
+            Marker::Ext16 => {
+                let len = read_len(r, buf, 2)?;
+                read_data(r, buf, len + 1)?
+            }
+            Marker::Ext32 => {
+                let len = read_len(r, buf, 4)?;
+                read_data(r, buf, len + 1)?
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::{read_frame, resync};
+    use crate::{ConvertOptions, Mp2JsonError};
+
+    #[test]
+    fn test_frames() {
+        // {"a":[1,"xy"]}, 0x05, a bin8, a fixext4, an empty array
+        let input: &[u8] = b"\x81\xa1a\x92\x01\xa2xy\x05\xc4\x02\x00\x01\xd6\x01abcd\x90";
+        let mut r = input;
+        let mut frames = Vec::new();
+        loop {
+            let mut buf = Vec::new();
+            if !read_frame(&mut r, &mut buf).unwrap() {
+                break;
+            }
+            frames.push(buf);
+        }
+        assert_eq!(
+            frames,
+            vec![
+                b"\x81\xa1a\x92\x01\xa2xy".to_vec(),
+                b"\x05".to_vec(),
+                b"\xc4\x02\x00\x01".to_vec(),
+                b"\xd6\x01abcd".to_vec(),
+                b"\x90".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut buf = Vec::new();
+        assert_matches!(
+            read_frame(&mut &b"\x92\x01"[..], &mut buf),
+            Err(Mp2JsonError::TruncatedInput)
+        );
+        assert_matches!(
+            read_frame(&mut &b"\xc4\xff\x00"[..], &mut buf),
+            Err(Mp2JsonError::TruncatedInput)
+        );
+        assert_matches!(
+            read_frame(&mut &b"\x91\xc1"[..], &mut buf),
+            Err(Mp2JsonError::ReservedMarker(0xc1))
+        );
+    }
+
+    #[test]
+    fn test_resync() {
+        let opts = ConvertOptions::default();
+        let resync = |input: &[u8], limit| {
+            let mut r = input;
+            resync(&mut r, limit, &opts).map(|(offset, mut replay)| {
+                replay.extend_from_slice(r);
+                (offset, replay)
+            })
+        };
+        assert_eq!(resync(b"\x01\x02", 4).unwrap(), (0, b"\x01\x02".to_vec()));
+        assert_eq!(resync(b"", 4).unwrap(), (0, Vec::new()));
+        // A reserved marker, then the tail of a record cut off at its start
+        assert_eq!(
+            resync(b"\xc1\xc1\x91\x01\x02", 4).unwrap(),
+            (2, b"\x91\x01\x02".to_vec())
+        );
+        // A str8 whose length runs past the end of the input
+        assert_eq!(
+            resync(b"\xd9\x09\x01", 4).unwrap(),
+            (1, b"\x09\x01".to_vec())
+        );
+        assert_matches!(
+            resync(b"\xc1\xc1\xc1\x01", 2),
+            Err(Mp2JsonError::ResyncFailed(2))
+        );
+        assert_matches!(resync(b"\xc1\xc1", 4), Err(Mp2JsonError::ResyncFailed(4)));
+    }
+}
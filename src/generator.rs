@@ -0,0 +1,213 @@
+//! The JSON writer behind every output format, which extends the `json`
+//! crate's generators with control over how numbers are written.
+
+use std::io::{self, Write};
+
+use json::codegen::Generator;
+use json::number::Number;
+use json::object::Object;
+use json::JsonValue;
+
+use crate::FloatNotation;
+
+/// Render a non-integral number in the given notation, or return `None` if
+/// the `json` crate's own formatting should be used
+pub(crate) fn format_float(num: &Number, notation: FloatNotation) -> Option<String> {
+    let (positive, mantissa, exponent) = num.as_parts();
+    if notation == FloatNotation::Auto || exponent == 0 || num.is_nan() {
+        return None;
+    }
+    // Parse the decimal form rather than converting the parts with float
+    // arithmetic, which can be off by an ulp
+    let f: f64 = format!("{}e{}", mantissa, exponent).parse().ok()?;
+    let f = if positive { f } else { -f };
+    Some(match notation {
+        FloatNotation::Auto => unreachable!(),
+        FloatNotation::Fixed => format!("{}", f),
+        FloatNotation::Scientific => format!("{:e}", f),
+        FloatNotation::Ecmascript => crate::canonical::format_double(f),
+    })
+}
+
+/// The entries of `object`, in key order if `sorted` and otherwise as
+/// inserted
+pub(crate) fn entries(object: &Object, sorted: bool) -> Vec<(&str, &JsonValue)> {
+    let mut entries: Vec<_> = object.iter().collect();
+    if sorted {
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+    }
+    entries
+}
+
+/// A generator writing to `writer`, compactly or with `spaces` of indent
+pub(crate) struct OutputGenerator<'a, W> {
+    writer: &'a mut W,
+    spaces: Option<u16>,
+    dent: u16,
+    float_notation: FloatNotation,
+    sort_keys: bool,
+}
+
+impl<'a, W: Write> OutputGenerator<'a, W> {
+    pub(crate) fn new(
+        writer: &'a mut W,
+        spaces: Option<u16>,
+        float_notation: FloatNotation,
+    ) -> Self {
+        OutputGenerator {
+            writer,
+            spaces,
+            dent: 0,
+            float_notation,
+            sort_keys: false,
+        }
+    }
+
+    /// Write the keys of every object in sorted order as they are reached,
+    /// rather than needing a sorted copy of the value
+    pub(crate) fn sorted(mut self, sort_keys: bool) -> Self {
+        self.sort_keys = sort_keys;
+        self
+    }
+}
+
+impl<W: Write> Generator for OutputGenerator<'_, W> {
+    type T = W;
+
+    fn get_writer(&mut self) -> &mut W {
+        self.writer
+    }
+
+    fn write_min(&mut self, slice: &[u8], min: u8) -> io::Result<()> {
+        match self.spaces {
+            Some(_) => self.writer.write_all(slice),
+            None => self.writer.write_all(&[min]),
+        }
+    }
+
+    fn new_line(&mut self) -> io::Result<()> {
+        if let Some(spaces) = self.spaces {
+            write!(self.writer, "\n{:1$}", "", usize::from(self.dent * spaces))?;
+        }
+        Ok(())
+    }
+
+    fn indent(&mut self) {
+        self.dent += 1;
+    }
+
+    fn dedent(&mut self) {
+        self.dent -= 1;
+    }
+
+    // The `json` crate stores every non-finite float as NaN, which it writes
+    // as `null`, so the output is valid JSON however the value was built
+    fn write_number(&mut self, num: &Number) -> io::Result<()> {
+        match format_float(num, self.float_notation) {
+            Some(text) => self.writer.write_all(text.as_bytes()),
+            None => JsonValue::Number(*num).write(self.writer),
+        }
+    }
+
+    // As the default, but optionally in key order
+    fn write_object(&mut self, object: &Object) -> io::Result<()> {
+        self.write_char(b'{')?;
+        if object.is_empty() {
+            return self.write_char(b'}');
+        }
+        self.indent();
+        for (i, (key, value)) in entries(object, self.sort_keys).into_iter().enumerate() {
+            if i > 0 {
+                self.write_char(b',')?;
+            }
+            self.new_line()?;
+            self.write_string(key)?;
+            self.write_min(b": ", b':')?;
+            self.write_json(value)?;
+        }
+        self.dedent();
+        self.new_line()?;
+        self.write_char(b'}')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json::codegen::Generator;
+    use json::JsonValue;
+
+    use super::OutputGenerator;
+    use crate::FloatNotation;
+
+    fn render(v: &JsonValue, notation: FloatNotation) -> String {
+        let mut out = Vec::new();
+        OutputGenerator::new(&mut out, None, notation)
+            .write_json(v)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_float_notation() {
+        let v = json::array![1e100, 0.0001, 1.5, -2.5e-7, 42];
+        assert_eq!(render(&v, FloatNotation::Auto), v.dump());
+        assert_eq!(
+            render(&v, FloatNotation::Fixed),
+            format!("[1{},0.0001,1.5,-0.00000025,42]", "0".repeat(100))
+        );
+        assert_eq!(
+            render(&v, FloatNotation::Scientific),
+            "[1e100,1e-4,1.5e0,-2.5e-7,42]"
+        );
+        assert_eq!(
+            render(&v, FloatNotation::Ecmascript),
+            "[1e+100,0.0001,1.5,-2.5e-7,42]"
+        );
+        assert_eq!(
+            render(&(0.1 + 0.2).into(), FloatNotation::Ecmascript),
+            "0.30000000000000004"
+        );
+        for notation in [
+            FloatNotation::Fixed,
+            FloatNotation::Scientific,
+            FloatNotation::Ecmascript,
+        ] {
+            let parsed = json::parse(&render(&v, notation)).unwrap();
+            for (a, b) in parsed.members().zip(v.members()) {
+                assert_eq!(a.as_f64(), b.as_f64());
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_finite_is_null() {
+        // Built directly, without the conversion's non-finite policy
+        let v = json::array![f64::NAN, { "a": f64::INFINITY }, f64::NEG_INFINITY];
+        let expected = r#"[null,{"a":null},null]"#;
+        for notation in [
+            FloatNotation::Auto,
+            FloatNotation::Fixed,
+            FloatNotation::Scientific,
+            FloatNotation::Ecmascript,
+        ] {
+            assert_eq!(render(&v, notation), expected);
+            let mut out = Vec::new();
+            crate::pretty::write_width(&v, 80, notation, false, None, None, &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+        let mut out = Vec::new();
+        crate::canonical::write(&v, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_matches_json_crate() {
+        let v = json::object! { "a": [1, {}, [], "x\n"], "b": { "c": null } };
+        let mut out = Vec::new();
+        OutputGenerator::new(&mut out, Some(2), FloatNotation::Auto)
+            .write_json(&v)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), v.pretty(2));
+        assert_eq!(render(&v, FloatNotation::Auto), v.dump());
+    }
+}
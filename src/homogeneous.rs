@@ -0,0 +1,109 @@
+//! Checking that the elements of each array, and the values of each object,
+//! are all of one JSON type.
+
+use json::JsonValue;
+
+use crate::{is_binary_wrapper, is_typed_null};
+
+/// An array or object whose members aren't all of one type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mixed {
+    pub(crate) path: String,
+    /// The types of its members, in the order they first appear
+    pub(crate) types: Vec<&'static str>,
+}
+
+/// The type of `v` as written in JSON, except that the objects written for
+/// binary and ext values are "binary", and those written for typed nulls
+/// are "null"
+fn type_name(v: &JsonValue) -> &'static str {
+    match v {
+        JsonValue::Null => "null",
+        JsonValue::Boolean(_) => "boolean",
+        JsonValue::Number(_) => "number",
+        JsonValue::Short(_) | JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(o) if is_binary_wrapper(o) => "binary",
+        JsonValue::Object(o) if is_typed_null(o) => "null",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+fn find_within(v: &JsonValue, path: &str, found: &mut Vec<Mixed>) {
+    let members: Vec<(String, &JsonValue)> = match v {
+        JsonValue::Array(a) => a
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("{}[{}]", path, i), v))
+            .collect(),
+        JsonValue::Object(o) if type_name(v) == "object" => o
+            .iter()
+            .map(|(k, v)| (format!("{}.{}", path, k), v))
+            .collect(),
+        _ => return,
+    };
+    let mut types = Vec::new();
+    for (_, member) in &members {
+        let name = type_name(member);
+        if !types.contains(&name) {
+            types.push(name);
+        }
+    }
+    if types.len() > 1 {
+        found.push(Mixed {
+            path: path.to_string(),
+            types,
+        });
+    }
+    for (path, member) in members {
+        find_within(member, &path, found);
+    }
+}
+
+/// Every array and object within `v`, including `v` itself, whose members
+/// are of more than one type, outermost first
+pub(crate) fn find_mixed(v: &JsonValue) -> Vec<Mixed> {
+    let mut found = Vec::new();
+    find_within(v, "$", &mut found);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_mixed, Mixed};
+
+    #[test]
+    fn test_find_mixed() {
+        assert_eq!(
+            find_mixed(&json::array![1, "two"]),
+            vec![Mixed {
+                path: "$".to_string(),
+                types: vec!["number", "string"],
+            }]
+        );
+        assert!(find_mixed(&json::array![1, 2.5, -3]).is_empty());
+        assert!(find_mixed(&json::object! { "a": [], "b": [true] }).is_empty());
+        let v = json::object! {
+            "ok": [{ "x": 1 }, { "y": 2 }],
+            "bad": [{ "encoding": "base64", "value": "" }, "s", null, { "__nil__": true }],
+            "deep": { "a": { "b": [null, [1]] } },
+        };
+        assert_eq!(
+            find_mixed(&v),
+            vec![
+                Mixed {
+                    path: "$".to_string(),
+                    types: vec!["array", "object"],
+                },
+                Mixed {
+                    path: "$.bad".to_string(),
+                    types: vec!["binary", "string", "null"],
+                },
+                Mixed {
+                    path: "$.deep.a.b".to_string(),
+                    types: vec!["null", "array"],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Graceful handling of SIGINT: the first Ctrl-C asks the conversion loop to
+//! stop after the current record so that buffered output can be flushed; a
+//! second one exits immediately.
+//!
+//! The handler is installed without `SA_RESTART`, so that a read blocked on
+//! a pipe or terminal fails with EINTR and the conversion loop sees the flag
+//! rather than waiting for more input.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: _exit is async-signal-safe
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Install the SIGINT handler which sets [`INTERRUPTED`]
+#[cfg(unix)]
+pub fn install() {
+    // SAFETY: the handler only touches an atomic and calls _exit, and the
+    // sigaction is fully initialised before use
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigint as *const () as libc::sighandler_t;
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
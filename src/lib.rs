@@ -0,0 +1,4486 @@
+//! Conversion of msgpack values into JSON.
+//!
+//! [`convert`] and [`read_and_convert_one`] turn individual msgpack values into
+//! [`JsonValue`]s according to a set of [`ConvertOptions`], and [`Converter`]
+//! drives that conversion over a whole input stream.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+
+use clap::ValueEnum;
+use json::codegen::Generator;
+use json::object::Object as JsonObject;
+use json::JsonValue;
+use rmpv::Value as MpValue;
+use thiserror::Error;
+
+use crate::adapters::{
+    CountingReader, CountingWriter, HashingReader, HashingWriter, RetryReader, TeeWriter,
+};
+
+mod adapters;
+mod aggregate;
+mod canonical;
+mod coerce;
+mod csv;
+mod direct;
+mod encoding;
+mod env;
+mod ext;
+mod frame;
+mod generator;
+mod homogeneous;
+mod marker;
+mod minimal;
+mod order;
+mod path;
+mod pretty;
+mod redact;
+mod reverse;
+mod route;
+mod schema;
+mod sha256;
+mod sizes;
+mod summary;
+mod timestamp;
+
+pub use adapters::Diagnostics;
+pub use coerce::{CoerceType, Coercion};
+pub use encoding::OutputEncoding;
+pub use ext::{ExtDecoder, ExtPayload};
+pub use path::Path;
+pub use redact::{RedactMode, Redaction};
+pub use reverse::ReverseConverter;
+pub use route::Route;
+pub use schema::{Schema, Violation};
+pub use timestamp::TimestampUnit;
+
+#[derive(Debug, Error)]
+pub enum Mp2JsonError {
+    #[error("msgpack string was not UTF-8")]
+    InvalidString,
+    #[error("msgpack integer was not encodable in 64 bits")]
+    InvalidInteger(rmpv::Integer),
+    #[error("msgpack integer {0} cannot be represented exactly as a JSON number")]
+    InexactInteger(rmpv::Integer),
+    #[error("msgpack float was not finite")]
+    NonFiniteFloat,
+    #[error("Map key is not a string")]
+    MapKeyNotString,
+    #[error("duplicate map key {0:?}")]
+    DuplicateKey(String),
+    #[error("msgpack binary or ext value found where none is allowed")]
+    UnexpectedBinary,
+    #[error("value is nested more deeply than the maximum depth")]
+    MaxDepthExceeded,
+    #[error("invalid input document: {0}")]
+    InvalidInputDocument(String),
+    #[error("invalid element {index} of input array: {reason}")]
+    InvalidInputElement { index: usize, reason: String },
+    #[error("CSV output requires every record to be an object")]
+    CsvRecordNotObject,
+    #[error("key {0:?} was not in the first record, so has no CSV column")]
+    CsvUnexpectedKey(String),
+    #[error("value for key {0:?} is nested, which strict CSV output forbids")]
+    CsvNestedValue(String),
+    #[error("env output requires every record to be an object")]
+    EnvRecordNotObject,
+    #[error("value for key {0:?} is nested, which env output can't write without flattening")]
+    EnvNestedValue(String),
+    #[error("{0:?} is not a valid shell variable name")]
+    EnvInvalidName(String),
+    #[error("merging requires every record to be an object")]
+    MergeRecordNotObject,
+    #[error("listing keys or values requires every record to be an object")]
+    ProjectionRecordNotObject,
+    #[error("cannot coerce the value at {path} to a {to}")]
+    IncompatibleCoercion { path: String, to: CoerceType },
+    #[error("record {index} does not match the schema {violation}")]
+    SchemaViolation { index: u64, violation: Violation },
+    #[error("{} records do not match the schema{}", .0.len(), list_violations(.0))]
+    SchemaViolations(Vec<(u64, Violation)>),
+    #[error("record {index} has values of mixed types at {path}: {types}")]
+    MixedTypes {
+        index: u64,
+        path: String,
+        types: String,
+    },
+    #[error("msgpack value at byte {offset} is not in its smallest encoding: {description}")]
+    NonCanonical { offset: u64, description: String },
+    #[error("msgpack input contained the reserved marker byte {0:#04x}")]
+    ReservedMarker(u8),
+    #[error("JSON value at {path} has no exact msgpack equivalent: {reason}")]
+    NotRoundTrippable { path: String, reason: String },
+    #[error("msgpack input ended partway through a record")]
+    TruncatedInput,
+    #[error("msgpack record was not followed by the frame marker {0:#04x}")]
+    MissingFrameMarker(u8),
+    #[error("no msgpack value begins within the first {0} bytes of the input")]
+    ResyncFailed(u64),
+    #[error("{0} records could not be converted")]
+    RecordErrors(u64),
+    #[error("only {count} records were written, fewer than the minimum of {min}")]
+    TooFewRecords { count: u64, min: u64 },
+    #[error("{count} records were written, more than the maximum of {max}")]
+    TooManyRecords { count: u64, max: u64 },
+    #[error("{source}; conversion can resume at byte {resume_offset}")]
+    Resumable {
+        resume_offset: u64,
+        source: Box<Mp2JsonError>,
+    },
+    #[error("msgpack decode error: {0}")]
+    RmpDecode(#[from] rmpv::decode::Error),
+    #[error("error writing")]
+    Output(#[source] std::io::Error),
+}
+
+fn list_violations(violations: &[(u64, Violation)]) -> String {
+    violations
+        .iter()
+        .map(|(index, violation)| format!("\n  record {} {}", index, violation))
+        .collect()
+}
+
+/// How to handle integers whose magnitude exceeds 2^53, and which therefore
+/// cannot be read back exactly by parsers that store numbers as doubles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LargeIntegers {
+    /// Emit the integer as a JSON number anyway
+    #[default]
+    Number,
+    /// Fail the conversion
+    Error,
+    /// Emit the integer as a string of its decimal digits
+    String,
+}
+
+/// How to handle NaN and infinite floats, which JSON cannot represent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum NonFinite {
+    /// Emit `null`
+    #[default]
+    Null,
+    /// Fail the conversion
+    Error,
+}
+
+/// How to handle a map which contains the same key more than once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateKeys {
+    /// Keep the last value seen for the key
+    #[default]
+    Last,
+    /// Fail the conversion
+    Error,
+}
+
+/// What to put in place of an array or map nested beyond the maximum depth
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MaxDepthAction {
+    /// Fail the conversion
+    #[default]
+    Error,
+    /// Emit the string "__truncated__"
+    Truncate,
+    /// Emit `null`
+    Null,
+}
+
+/// Which integers to render as timestamps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TimestampScope {
+    /// Only integers which are themselves top-level values
+    #[default]
+    TopLevel,
+    /// Every integer, including those nested in arrays and maps
+    All,
+}
+
+/// The format in which records are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON value per line
+    #[default]
+    Json,
+    /// CSV, with a header row taken from the keys of the first record
+    Csv,
+    /// Shell variable assignments like `KEY='value'`, one per line, from
+    /// the fields of objects
+    Env,
+}
+
+/// How to write numbers which aren't integers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FloatNotation {
+    /// The json crate's shortest form, with an exponent only for very large or small values
+    #[default]
+    Auto,
+    /// Plain decimal digits, never an exponent
+    Fixed,
+    /// Always with an exponent, like 1.5e0
+    Scientific,
+    /// The shortest digits which round-trip, laid out as ECMAScript's
+    /// `Number.prototype.toString` does, as in canonical output. This is
+    /// fully specified, so the output doesn't depend on the `json` crate.
+    Ecmascript,
+}
+
+/// A case to rewrite every string map key to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyCase {
+    /// like_this
+    Snake,
+    /// likeThis
+    Camel,
+    /// like-this
+    Kebab,
+    /// Lowercased, without changing word separators
+    Lower,
+    /// Uppercased, without changing word separators
+    Upper,
+}
+
+impl KeyCase {
+    fn apply(self, key: &str) -> String {
+        use heck::{ToKebabCase, ToLowerCamelCase, ToSnakeCase};
+        match self {
+            KeyCase::Snake => key.to_snake_case(),
+            KeyCase::Camel => key.to_lower_camel_case(),
+            KeyCase::Kebab => key.to_kebab_case(),
+            KeyCase::Lower => key.to_lowercase(),
+            KeyCase::Upper => key.to_uppercase(),
+        }
+    }
+}
+
+/// How to write the bytes of binary values, in the `value` field of the
+/// object which wraps them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BinaryEncoding {
+    /// Standard base64 with padding
+    #[default]
+    Base64,
+    /// Lowercase hex digits
+    Hex,
+    /// Each byte as the Latin-1 character with the same code point, for
+    /// binary which is really legacy text
+    Latin1,
+}
+
+impl BinaryEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            BinaryEncoding::Base64 => "base64",
+            BinaryEncoding::Hex => "hex",
+            BinaryEncoding::Latin1 => "latin1",
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            BinaryEncoding::Base64 => base64::encode(bytes),
+            BinaryEncoding::Hex => to_hex(bytes),
+            BinaryEncoding::Latin1 => bytes.iter().copied().map(char::from).collect(),
+        }
+    }
+}
+
+/// How to write binary map keys as strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BinaryKeys {
+    /// Standard base64 with padding
+    Base64,
+    /// Lowercase hex digits
+    Hex,
+}
+
+/// Which way to order the entries of objects by their values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Smallest value first
+    #[default]
+    Asc,
+    /// Largest value first
+    Desc,
+}
+
+/// How to write maps as arrays which keep their entries in order, for
+/// consumers whose JSON parsers don't keep the order of object keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OrderedMaps {
+    /// `[[key, value], ...]`
+    #[default]
+    Pairs,
+    /// `[{key: value}, ...]`
+    Objects,
+}
+
+impl OrderedMaps {
+    fn entry(self, k: String, v: JsonValue) -> JsonValue {
+        match self {
+            OrderedMaps::Pairs => JsonValue::Array(vec![k.into(), v]),
+            OrderedMaps::Objects => {
+                let mut o = JsonObject::with_capacity(1);
+                o.insert(&k, v);
+                o.into()
+            }
+        }
+    }
+}
+
+/// How to mark a string shortened to `max_string_length`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TruncatedStrings {
+    /// End the string with "…"
+    #[default]
+    Ellipsis,
+    /// Wrap it as {"__truncated_string__": true, "len": ..., "value": ...},
+    /// giving its length in characters before truncation
+    Verbose,
+}
+
+/// What to do when the reader of the output goes away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnBrokenPipe {
+    /// Stop converting and succeed, as is right when piped into `head`
+    #[default]
+    ExitOk,
+    /// Stop converting and fail with the write error
+    Error,
+}
+
+impl OnBrokenPipe {
+    /// Check the result of writing output, giving `false` if the output
+    /// has gone away and that is to be ignored
+    pub(crate) fn check(self, written: std::io::Result<()>) -> Result<bool, Mp2JsonError> {
+        match written {
+            Ok(()) => Ok(true),
+            Err(e)
+                if e.kind() == std::io::ErrorKind::BrokenPipe && self == OnBrokenPipe::ExitOk =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(Mp2JsonError::Output(e)),
+        }
+    }
+}
+
+/// How to line up the values of objects when pretty-printing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    /// Pad keys to the same width, so that values start in the same column
+    Keys,
+    /// As `Keys`, and also right-align numbers with each other
+    Numbers,
+}
+
+/// The shape of the input stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// Any number of concatenated msgpack values
+    #[default]
+    Msgpack,
+    /// A JSON array whose elements are base64-encoded msgpack values
+    JsonArrayOfMsgpack,
+    /// JSON values, in any layout, to be reformatted
+    Json,
+}
+
+/// A part of each record-object to write in place of the whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    /// An array of the object's keys
+    Keys,
+    /// An array of the object's values
+    Values,
+}
+
+/// A handler for msgpack extension types, given the type code and payload of
+/// each ext value. Returning `None` falls back to the default representation.
+pub type ExtHandler = Box<dyn Fn(i8, &[u8]) -> Option<JsonValue> + Send + Sync>;
+
+/// Policies controlling how msgpack values are converted to JSON
+#[derive(Default)]
+pub struct ConvertOptions {
+    pub large_integers: LargeIntegers,
+    pub non_finite: NonFinite,
+    pub duplicate_keys: DuplicateKeys,
+    /// Render integers as RFC3339 timestamps, counted in `timestamp_unit`s
+    /// since the Unix epoch; those outside the years 0000 to 9999 are
+    /// converted as any other integer
+    pub int_as_timestamp: Option<TimestampScope>,
+    pub timestamp_unit: TimestampUnit,
+    /// Wrap floats as `{"$float": ...}`, since JSON output would otherwise
+    /// write an integral float like `1.0` identically to the integer `1`
+    pub preserve_number_type: bool,
+    pub binary_encoding: BinaryEncoding,
+    /// Write binary values of at most this many bytes as hex rather than in
+    /// `binary_encoding`; 0 always uses `binary_encoding`
+    pub binary_threshold: usize,
+    /// Write binary values which hold exactly one msgpack value as that
+    /// value converted, rather than as bytes. Each such value counts as a
+    /// level of nesting towards `max_depth`, and without one is only decoded
+    /// within the first 128 levels.
+    pub decode_nested_msgpack: bool,
+    /// Fail on binary and ext values rather than writing them wrapped as
+    /// `{"encoding": ..., "value": ...}`. Those which nested msgpack, an
+    /// ext decoder or the ext handler turn into other values are allowed.
+    pub reject_binary: bool,
+    /// Write string values which hold exactly one JSON object or array as
+    /// that value, parsing any such strings within it in turn. Other strings,
+    /// including those holding JSON scalars, are left alone, as are those
+    /// which would nest beyond `max_depth` (or 128 levels without one).
+    pub parse_json_strings: bool,
+    /// Write nil as `{"__nil__": true}` rather than `null`, for consumers
+    /// which need to tell it apart from a missing key or a null introduced
+    /// by another transformation. [`ReverseConverter::typed_nulls`] turns it
+    /// back into nil.
+    pub typed_nulls: bool,
+    /// Encode binary map keys as strings; otherwise they are rejected like
+    /// any other non-string key
+    pub binary_keys: Option<BinaryKeys>,
+    /// Rewrite string map keys to this case. Keys which then collide follow
+    /// `duplicate_keys`.
+    pub key_case: Option<KeyCase>,
+    /// Write maps as arrays of their entries in their original order. Every
+    /// entry is kept, so duplicate keys are only checked for with
+    /// [`DuplicateKeys::Error`].
+    pub ordered_maps: Option<OrderedMaps>,
+    /// The deepest nesting of arrays and maps to accept; both decoding and
+    /// conversion recurse once per level, so this bounds stack usage
+    pub max_depth: Option<usize>,
+    /// What to do with containers beyond `max_depth`. Anything other than
+    /// [`MaxDepthAction::Error`] has to decode the whole value first, so
+    /// only rmpv's own nesting limit applies while decoding.
+    pub on_max_depth: MaxDepthAction,
+    /// Shorten string values longer than this many characters, as a display
+    /// aid for records with huge fields
+    pub max_string_length: Option<usize>,
+    pub truncated_strings: TruncatedStrings,
+    /// Shorten map keys to `max_string_length` too, always with "…"
+    pub truncate_keys: bool,
+    /// Decoders for ext types holding raw numbers: floats, which are written
+    /// as numbers subject to the `non_finite` policy, and decimal128s, which
+    /// are written as strings. These are tried before
+    /// `ext_handler`, which only sees the ext values none of them decodes,
+    /// such as those whose payload is the wrong length.
+    pub ext_decoders: Vec<ExtDecoder>,
+    /// Consulted for every ext value left by `ext_decoders`, before it is
+    /// wrapped as base64.
+    ///
+    /// ```
+    /// use mp2json::{read_and_convert_one, ConvertOptions};
+    ///
+    /// let options = ConvertOptions {
+    ///     ext_handler: Some(Box::new(|type_code, bytes| {
+    ///         (type_code == 42).then(|| bytes.len().into())
+    ///     })),
+    ///     ..Default::default()
+    /// };
+    /// // fixext1 with type code 42
+    /// let v = read_and_convert_one(&mut &b"\xd4\x2a\x07"[..], &options).unwrap();
+    /// assert_eq!(v, 1);
+    /// ```
+    pub ext_handler: Option<ExtHandler>,
+}
+
+impl std::fmt::Debug for ConvertOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConvertOptions")
+            .field("large_integers", &self.large_integers)
+            .field("non_finite", &self.non_finite)
+            .field("duplicate_keys", &self.duplicate_keys)
+            .field("int_as_timestamp", &self.int_as_timestamp)
+            .field("timestamp_unit", &self.timestamp_unit)
+            .field("preserve_number_type", &self.preserve_number_type)
+            .field("binary_encoding", &self.binary_encoding)
+            .field("binary_threshold", &self.binary_threshold)
+            .field("decode_nested_msgpack", &self.decode_nested_msgpack)
+            .field("reject_binary", &self.reject_binary)
+            .field("parse_json_strings", &self.parse_json_strings)
+            .field("typed_nulls", &self.typed_nulls)
+            .field("binary_keys", &self.binary_keys)
+            .field("key_case", &self.key_case)
+            .field("ordered_maps", &self.ordered_maps)
+            .field("max_depth", &self.max_depth)
+            .field("on_max_depth", &self.on_max_depth)
+            .field("max_string_length", &self.max_string_length)
+            .field("truncated_strings", &self.truncated_strings)
+            .field("truncate_keys", &self.truncate_keys)
+            .field("ext_decoders", &self.ext_decoders)
+            .field("ext_handler", &self.ext_handler.is_some())
+            .finish()
+    }
+}
+
+impl ConvertOptions {
+    /// Options which reject every conversion that would lose information:
+    /// integers beyond ±2^53, non-finite floats, and duplicate map keys (as
+    /// well as non-string and non-UTF-8 keys, which are always rejected).
+    pub fn strict() -> Self {
+        ConvertOptions {
+            large_integers: LargeIntegers::Error,
+            non_finite: NonFinite::Error,
+            duplicate_keys: DuplicateKeys::Error,
+            ..Default::default()
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The largest integer magnitude which an IEEE754 double represents exactly
+const MAX_SAFE_INTEGER: u64 = 1 << 53;
+
+/// The JSON number equal to `i`. The `json` crate's own conversion negates
+/// negative values as an `i64`, which overflows for `i64::MIN`.
+pub(crate) fn integer_number(i: i64) -> json::number::Number {
+    json::number::Number::from_parts(i >= 0, i.unsigned_abs(), 0)
+}
+
+fn convert_float(f: f64, opts: &ConvertOptions) -> Result<JsonValue, Mp2JsonError> {
+    if !f.is_finite() && opts.non_finite == NonFinite::Error {
+        return Err(Mp2JsonError::NonFiniteFloat);
+    }
+    if opts.preserve_number_type {
+        let mut o = JsonObject::with_capacity(1);
+        o.insert("$float", f.into());
+        return Ok(o.into());
+    }
+    Ok(f.into())
+}
+
+/// The key of the object written for nil with
+/// [`ConvertOptions::typed_nulls`]
+const NIL_MARKER: &str = "__nil__";
+
+fn typed_null() -> JsonValue {
+    let mut o = JsonObject::with_capacity(1);
+    o.insert(NIL_MARKER, true.into());
+    o.into()
+}
+
+/// Whether `o` is the object which [`convert`] produces for nil with
+/// [`ConvertOptions::typed_nulls`]
+pub(crate) fn is_typed_null(o: &JsonObject) -> bool {
+    o.len() == 1 && o.get(NIL_MARKER) == Some(&JsonValue::Boolean(true))
+}
+
+/// Whether `o` is the object which [`convert`] produces for binary and ext
+/// values
+pub(crate) fn is_binary_wrapper(o: &JsonObject) -> bool {
+    let expected_len = if o.get("type_code").is_some() { 3 } else { 2 };
+    o.len() == expected_len && o["encoding"].is_string() && o["value"].is_string()
+}
+
+/// The nesting limit for values decoded from binary values when there is no
+/// `max_depth`. Each level decoded this way costs several stack frames, so
+/// this is well short of how deep rmpv itself decodes.
+const NESTED_MSGPACK_MAX_DEPTH: usize = 128;
+
+/// Convert `bytes` as a msgpack value nested within a value at `depth`, if
+/// they hold exactly one which converts successfully
+fn convert_nested(bytes: &[u8], opts: &ConvertOptions, depth: usize) -> Option<JsonValue> {
+    let max_depth = opts.max_depth.unwrap_or(NESTED_MSGPACK_MAX_DEPTH);
+    let depth = depth + 1;
+    if depth > max_depth {
+        return None;
+    }
+    // Framing first rejects reserved markers, and trailing bytes
+    let mut r = bytes;
+    if !matches!(frame::read_frame(&mut r, &mut Vec::new()), Ok(true)) || !r.is_empty() {
+        return None;
+    }
+    // Budgeted as in `read_one`, for the levels left
+    let budget = (max_depth - depth).saturating_mul(2).saturating_add(3);
+    let value = rmpv::decode::read_value_with_max_depth(&mut &bytes[..], budget).ok()?;
+    convert_inner(value, opts, depth).ok()
+}
+
+/// The nesting limit for JSON parsed from strings when there is no
+/// `max_depth`, since the parsed values are walked recursively
+const JSON_STRING_MAX_DEPTH: usize = 128;
+
+/// Parse `s`, a string at `depth`, as JSON if it holds exactly one object or
+/// array which fits within `max_depth`, and likewise parse any strings
+/// within that
+fn parse_json_string(s: &str, max_depth: usize, depth: usize) -> Option<JsonValue> {
+    if !s.trim_start().starts_with(['{', '[']) {
+        return None;
+    }
+    parse_json_strings_within(json::parse(s).ok()?, max_depth, depth)
+}
+
+fn parse_json_strings_within(v: JsonValue, max_depth: usize, depth: usize) -> Option<JsonValue> {
+    Some(match v {
+        JsonValue::Array(_) | JsonValue::Object(_) if depth >= max_depth => return None,
+        JsonValue::Array(a) => a
+            .into_iter()
+            .map(|v| parse_json_strings_within(v, max_depth, depth + 1))
+            .collect::<Option<Vec<_>>>()?
+            .into(),
+        JsonValue::Object(mut o) => {
+            for (_, v) in o.iter_mut() {
+                *v = parse_json_strings_within(v.take(), max_depth, depth + 1)?;
+            }
+            o.into()
+        }
+        JsonValue::Short(_) | JsonValue::String(_) => {
+            parse_json_string(v.as_str().unwrap_or_default(), max_depth, depth).unwrap_or(v)
+        }
+        v => v,
+    })
+}
+
+/// The value which replaces containers beyond the maximum depth with
+/// [`MaxDepthAction::Truncate`]
+const TRUNCATED: &str = "__truncated__";
+
+/// The marker ending a string shortened to `max_string_length`
+const ELLIPSIS: char = '…';
+
+/// Shorten `s` to `max` characters followed by [`ELLIPSIS`] if it is any
+/// longer, returning its original length in characters if so
+fn truncate_string(s: &mut String, max: usize) -> Option<usize> {
+    let (end, _) = s.char_indices().nth(max)?;
+    let len = max + s[end..].chars().count();
+    s.truncate(end);
+    s.push(ELLIPSIS);
+    Some(len)
+}
+
+/// Convert a map key to the string used as its JSON object key
+fn convert_key(k: MpValue, opts: &ConvertOptions) -> Result<String, Mp2JsonError> {
+    let mut s = match (k, opts.binary_keys) {
+        (MpValue::String(s), _) => {
+            let s = s.into_str().ok_or(Mp2JsonError::InvalidString)?;
+            match opts.key_case {
+                Some(case) => case.apply(&s),
+                None => s,
+            }
+        }
+        (MpValue::Binary(b), Some(BinaryKeys::Base64)) => base64::encode(b),
+        (MpValue::Binary(b), Some(BinaryKeys::Hex)) => to_hex(&b),
+        _ => return Err(Mp2JsonError::MapKeyNotString),
+    };
+    if let Some(max) = opts.max_string_length.filter(|_| opts.truncate_keys) {
+        truncate_string(&mut s, max);
+    }
+    Ok(s)
+}
+
+/// A value of the same shape as [`convert`] gives for `v`, but with each
+/// leaf replaced by the name of its msgpack type
+fn type_shape(v: &MpValue, opts: &ConvertOptions, depth: usize) -> Result<JsonValue, Mp2JsonError> {
+    let name = match v {
+        MpValue::Array(_) | MpValue::Map(_)
+            if opts.max_depth.is_some_and(|max_depth| depth >= max_depth) =>
+        {
+            return Ok(match v {
+                MpValue::Array(_) => "array",
+                _ => "map",
+            }
+            .into());
+        }
+        MpValue::Array(a) => {
+            return a
+                .iter()
+                .map(|v| type_shape(v, opts, depth + 1))
+                .collect::<Result<Vec<_>, _>>()
+                .map(JsonValue::Array);
+        }
+        MpValue::Map(m) => {
+            if let Some(ordered) = opts.ordered_maps {
+                return m
+                    .iter()
+                    .map(|(k, v)| {
+                        Ok(ordered.entry(
+                            convert_key(k.clone(), opts)?,
+                            type_shape(v, opts, depth + 1)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(JsonValue::Array);
+            }
+            let mut o = JsonObject::with_capacity(m.len());
+            for (k, v) in m {
+                o.insert(
+                    &convert_key(k.clone(), opts)?,
+                    type_shape(v, opts, depth + 1)?,
+                );
+            }
+            return Ok(o.into());
+        }
+        MpValue::Nil => "nil",
+        MpValue::Boolean(_) => "bool",
+        MpValue::Integer(_) => "int",
+        MpValue::F32(_) => "float32",
+        MpValue::F64(_) => "float64",
+        MpValue::String(_) => "str",
+        MpValue::Binary(_) => "bin",
+        MpValue::Ext(..) => "ext",
+    };
+    Ok(name.into())
+}
+
+/// Convert a single decoded msgpack value
+pub fn convert(r: MpValue, opts: &ConvertOptions) -> Result<JsonValue, Mp2JsonError> {
+    convert_inner(r, opts, 0)
+}
+
+fn convert_inner(
+    r: MpValue,
+    opts: &ConvertOptions,
+    depth: usize,
+) -> Result<JsonValue, Mp2JsonError> {
+    let jv = match r {
+        MpValue::Nil if opts.typed_nulls => typed_null(),
+        MpValue::Nil => JsonValue::Null,
+        MpValue::Boolean(b) => b.into(),
+        MpValue::Integer(i)
+            if opts.int_as_timestamp == Some(TimestampScope::All)
+                || (opts.int_as_timestamp == Some(TimestampScope::TopLevel) && depth == 0) =>
+        {
+            let value = i
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| i.as_u64().map(i128::from))
+                .ok_or(Mp2JsonError::InvalidInteger(i))?;
+            match timestamp::format_rfc3339(value, opts.timestamp_unit) {
+                Some(s) => s.into(),
+                None => convert_integer(i, opts)?,
+            }
+        }
+        MpValue::Integer(i) => convert_integer(i, opts)?,
+        MpValue::F32(f) => convert_float(f.into(), opts)?,
+        MpValue::F64(f) => convert_float(f, opts)?,
+        MpValue::String(s) => {
+            let mut s = s.into_str().ok_or(Mp2JsonError::InvalidString)?;
+            if opts.parse_json_strings {
+                let max_depth = opts.max_depth.unwrap_or(JSON_STRING_MAX_DEPTH);
+                if let Some(v) = parse_json_string(&s, max_depth, depth) {
+                    return Ok(v);
+                }
+            }
+            match opts
+                .max_string_length
+                .and_then(|max| truncate_string(&mut s, max))
+            {
+                Some(len) if opts.truncated_strings == TruncatedStrings::Verbose => {
+                    let mut o = JsonObject::with_capacity(3);
+                    o.insert("__truncated_string__", true.into());
+                    o.insert("len", len.into());
+                    o.insert("value", s.into());
+                    o.into()
+                }
+                _ => s.into(),
+            }
+        }
+        MpValue::Binary(b) => {
+            if opts.decode_nested_msgpack {
+                if let Some(v) = convert_nested(&b, opts, depth) {
+                    return Ok(v);
+                }
+            }
+            if opts.reject_binary {
+                return Err(Mp2JsonError::UnexpectedBinary);
+            }
+            let encoding = if opts.binary_threshold > 0 && b.len() <= opts.binary_threshold {
+                BinaryEncoding::Hex
+            } else {
+                opts.binary_encoding
+            };
+            let mut o = JsonObject::with_capacity(2);
+            o.insert("encoding", encoding.name().into());
+            o.insert("value", encoding.encode(&b).into());
+            JsonValue::Object(o)
+        }
+        MpValue::Array(_) | MpValue::Map(_)
+            if opts.max_depth.is_some_and(|max_depth| depth >= max_depth) =>
+        {
+            match opts.on_max_depth {
+                MaxDepthAction::Error => return Err(Mp2JsonError::MaxDepthExceeded),
+                MaxDepthAction::Truncate => TRUNCATED.into(),
+                MaxDepthAction::Null => JsonValue::Null,
+            }
+        }
+        MpValue::Array(v) => {
+            let depth = depth + 1;
+            v.into_iter()
+                .map(|v| convert_inner(v, opts, depth))
+                .collect::<Result<Vec<_>, _>>()?
+                .into()
+        }
+        MpValue::Map(m) if opts.ordered_maps.is_some() => {
+            let ordered = opts.ordered_maps.unwrap_or_default();
+            let depth = depth + 1;
+            let mut seen = HashSet::new();
+            let mut entries = Vec::with_capacity(m.len());
+            for (k, v) in m {
+                let s = convert_key(k, opts)?;
+                if opts.duplicate_keys == DuplicateKeys::Error && !seen.insert(s.clone()) {
+                    return Err(Mp2JsonError::DuplicateKey(s));
+                }
+                entries.push(ordered.entry(s, convert_inner(v, opts, depth)?));
+            }
+            entries.into()
+        }
+        MpValue::Map(m) => {
+            let depth = depth + 1;
+            let mut o = JsonObject::with_capacity(m.len());
+            for (k, v) in m {
+                let s = convert_key(k, opts)?;
+                if opts.duplicate_keys == DuplicateKeys::Error && o.get(&s).is_some() {
+                    return Err(Mp2JsonError::DuplicateKey(s));
+                }
+                o.insert(&s, convert_inner(v, opts, depth)?);
+            }
+            o.into()
+        }
+        MpValue::Ext(type_code, bytes) => {
+            match opts
+                .ext_decoders
+                .iter()
+                .find_map(|decoder| decoder.decode(type_code, &bytes))
+            {
+                Some(ext::Decoded::Float(f)) => return convert_float(f, opts),
+                Some(ext::Decoded::Decimal(s)) => return Ok(s.into()),
+                None => {}
+            }
+            if let Some(v) = opts
+                .ext_handler
+                .as_ref()
+                .and_then(|handler| handler(type_code, &bytes))
+            {
+                return Ok(v);
+            }
+            if opts.reject_binary {
+                return Err(Mp2JsonError::UnexpectedBinary);
+            }
+            let mut o = JsonObject::with_capacity(3);
+            o.insert("type_code", type_code.into());
+            o.insert("encoding", "base64".into());
+            o.insert("value", base64::encode(bytes).into());
+            o.into()
+        }
+    };
+    Ok(jv)
+}
+
+/// Convert an integer as a number, or as `opts.large_integers` says
+fn convert_integer(i: rmpv::Integer, opts: &ConvertOptions) -> Result<JsonValue, Mp2JsonError> {
+    if opts.large_integers == LargeIntegers::Number {
+        // The `json` crate keeps all 64 bits of an integer, so every msgpack
+        // integer is written exactly, and never by way of a float
+        return Ok(i
+            .as_i64()
+            .map(|i| JsonValue::Number(integer_number(i)))
+            .or_else(|| i.as_u64().map(JsonValue::from))
+            .unwrap_or_else(|| i.to_string().into()));
+    }
+    let magnitude = i
+        .as_u64()
+        .or_else(|| i.as_i64().map(i64::unsigned_abs))
+        .ok_or(Mp2JsonError::InvalidInteger(i))?;
+    if magnitude > MAX_SAFE_INTEGER {
+        match opts.large_integers {
+            LargeIntegers::String => Ok(i.to_string().into()),
+            _ => Err(Mp2JsonError::InexactInteger(i)),
+        }
+    } else {
+        i.as_u64()
+            .map(JsonValue::from)
+            .or_else(|| i.as_i64().map(JsonValue::from))
+            .ok_or(Mp2JsonError::InvalidInteger(i))
+    }
+}
+
+/// Decode one msgpack value from `r` and convert it
+///
+/// The value's bytes are read in full before decoding, so that reserved
+/// marker bytes (which rmpv would decode as nil) can be rejected.
+pub fn read_and_convert_one<R: Read>(
+    r: &mut R,
+    opts: &ConvertOptions,
+) -> Result<JsonValue, Mp2JsonError> {
+    convert(read_one(r, opts)?, opts)
+}
+
+/// How many levels of arrays and maps `v` has, 0 for a scalar
+fn nesting_depth(v: &MpValue) -> usize {
+    match v {
+        MpValue::Array(a) => 1 + a.iter().map(nesting_depth).max().unwrap_or(0),
+        MpValue::Map(m) => {
+            let items = m.iter().flat_map(|(k, v)| [k, v]);
+            1 + items.map(nesting_depth).max().unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Decode one msgpack value from `r` and describe it, as an object giving
+/// the number of bytes it took up as `length`, the name of its msgpack
+/// encoding as `type`, how deeply its arrays and maps nest as `depth`, and
+/// the value converted as `value`
+pub fn inspect_one<R: Read>(r: &mut R, opts: &ConvertOptions) -> Result<JsonValue, Mp2JsonError> {
+    let bytes = read_frame(r)?;
+    let value = decode_frame(&bytes, opts)?;
+    let mut o = JsonObject::with_capacity(4);
+    o.insert("length", bytes.len().into());
+    o.insert("type", marker::type_name(bytes[0]).into());
+    o.insert("depth", nesting_depth(&value).into());
+    o.insert("value", convert(value, opts)?);
+    Ok(o.into())
+}
+
+/// One msgpack record, read ready to be written
+enum Record {
+    /// The converted value, and its [`type_shape`] if asked for
+    Converted(JsonValue, Option<JsonValue>),
+    /// The decoded value, to be written by [`direct::write`]
+    Direct(MpValue),
+}
+
+/// Like [`read_and_convert_one`], also giving the [`type_shape`] of the
+/// value if `types` is set, or skipping conversion if `direct` is set and
+/// the value is [`direct::supported`]. If `check_minimal` is set, also gives
+/// every value in the record not in its smallest encoding.
+fn read_record<R: Read>(
+    r: &mut R,
+    opts: &ConvertOptions,
+    types: bool,
+    direct: bool,
+    check_minimal: bool,
+) -> Result<(Record, Vec<minimal::NonMinimal>), Mp2JsonError> {
+    let bytes = read_frame(r)?;
+    let non_minimal = if check_minimal {
+        minimal::find(&bytes)
+    } else {
+        Vec::new()
+    };
+    let value = decode_frame(&bytes, opts)?;
+    if direct && direct::supported(&value) {
+        return Ok((Record::Direct(value), non_minimal));
+    }
+    let shape = types.then(|| type_shape(&value, opts, 0));
+    let record = Record::Converted(convert(value, opts)?, shape.transpose()?);
+    Ok((record, non_minimal))
+}
+
+/// The bytes of the next msgpack value in `r`
+fn read_frame<R: Read>(r: &mut R) -> Result<Vec<u8>, Mp2JsonError> {
+    let mut record = Vec::new();
+    if !frame::read_frame(r, &mut record)? {
+        return Err(rmpv::decode::Error::InvalidMarkerRead(
+            std::io::ErrorKind::UnexpectedEof.into(),
+        )
+        .into());
+    }
+    Ok(record)
+}
+
+fn read_one<R: Read>(r: &mut R, opts: &ConvertOptions) -> Result<MpValue, Mp2JsonError> {
+    decode_frame(&read_frame(r)?, opts)
+}
+
+fn decode_frame(record: &[u8], opts: &ConvertOptions) -> Result<MpValue, Mp2JsonError> {
+    let r = &mut &record[..];
+    let value = match opts.max_depth {
+        // rmpv spends up to two units of its depth budget per level of
+        // nesting, and three on a leaf string
+        Some(max_depth) if opts.on_max_depth == MaxDepthAction::Error => {
+            rmpv::decode::read_value_with_max_depth(
+                r,
+                max_depth.saturating_mul(2).saturating_add(3),
+            )
+            .map_err(|e| match e {
+                rmpv::decode::Error::DepthLimitExceeded => Mp2JsonError::MaxDepthExceeded,
+                e => e.into(),
+            })?
+        }
+        _ => rmpv::decode::read_value(r)?,
+    };
+    Ok(value)
+}
+
+/// Converts every value in an input stream, writing one JSON record per line
+#[derive(Default)]
+pub struct Converter {
+    /// Buffer reads and writes; otherwise each record is written immediately
+    pub buffered: bool,
+    /// Buffer writes, but flush after every record
+    pub line_buffered: bool,
+    pub pretty: bool,
+    /// Pretty-print, only breaking containers which don't fit in this width
+    pub pretty_width: Option<usize>,
+    /// When pretty-printing, write containers this many levels deep as a
+    /// summary like `{… 5 keys}`, for reading rather than parsing
+    pub collapse_below: Option<usize>,
+    /// When pretty-printing, pad the keys of each object broken across
+    /// lines so that its values line up
+    pub align: Option<Align>,
+    pub float_notation: FloatNotation,
+    /// Write the keys of every object in sorted order
+    pub sort_keys: bool,
+    /// Write the entries of every object ordered by their values, breaking
+    /// ties by key. Values of different types order as null, booleans,
+    /// numbers, strings, arrays, then objects; arrays and objects compare
+    /// member by member.
+    pub sort_by_value: Option<SortOrder>,
+    /// Write canonical JSON (RFC 8785), with sorted keys and exactly
+    /// specified number and string formatting, in place of `pretty` and
+    /// `float_notation`
+    pub canonical: bool,
+    /// A final record to write when the input is completely consumed, with
+    /// [`OutputFormat::Json`]; the other formats have no room for it
+    pub eof_marker: Option<JsonValue>,
+    /// Write only the keys or values of each record
+    pub projection: Option<Projection>,
+    /// With a `projection`, skip records which aren't objects rather than
+    /// failing
+    pub skip_non_objects: bool,
+    /// Wrap each top-level value with the name of its msgpack encoding
+    pub annotate_types: bool,
+    /// Wrap each top-level value with the offset and length of its bytes in
+    /// the input. Only msgpack input has offsets; other records are unwrapped.
+    pub include_offsets: bool,
+    /// The names of the inputs concatenated to make the input, each with
+    /// the offset at which it starts. If there are any, each msgpack record
+    /// is wrapped as `{"file": ..., "value": ...}` naming the one it starts
+    /// in.
+    pub sources: Vec<(u64, String)>,
+    /// Treat msgpack input which ends partway through a record as ending
+    /// after the last whole record, rather than failing
+    pub allow_truncated: bool,
+    /// Expect every msgpack record to be followed by this byte, failing
+    /// where one isn't. Only 0xc1, which never begins a msgpack value, can
+    /// delimit records unambiguously. Records are then converted on the
+    /// calling thread, whatever `jobs` is.
+    pub frame_marker: Option<u8>,
+    /// If the msgpack input doesn't begin with a value, as when it was
+    /// captured partway through a record, skip ahead a byte at a time until
+    /// one decodes, giving up after this many bytes. The bytes skipped count
+    /// towards `skip_bytes`.
+    pub resync: Option<u64>,
+    /// Read at most this many bytes of the input, converting the msgpack
+    /// records wholly within them
+    pub input_limit: Option<u64>,
+    /// Skip this many bytes at the start of the input. Offsets still count
+    /// from the very start.
+    pub skip_bytes: u64,
+    /// On a msgpack record containing invalid UTF-8, fail with
+    /// [`Mp2JsonError::Resumable`], giving the offset just past the record
+    /// to pass as `skip_bytes` once it is fixed
+    pub resumable: bool,
+    /// The offset in the original input at which the input given to
+    /// [`Converter::run`] begins, as when it has already been seeked
+    pub start_offset: u64,
+    /// Keep this file up to date with the offset just past the last msgpack
+    /// record written, as a point from which to resume later
+    pub checkpoint: Option<std::path::PathBuf>,
+    /// Save the checkpoint after every this many records, as well as when
+    /// conversion stops
+    pub checkpoint_interval: u64,
+    /// Write each msgpack record which can't be converted as a record
+    /// `{"__error__": message, "offset": N}` and carry on, failing with
+    /// [`Mp2JsonError::RecordErrors`] at the end. Errors which leave the
+    /// rest of the input unreadable are written and then returned.
+    pub errors_as_json: bool,
+    /// Follow each msgpack record with a second record of the same shape,
+    /// whose leaves are the names of the msgpack types they were decoded
+    /// from, such as `"str"` or `"bin"`
+    pub types_sidecar: bool,
+    /// Write msgpack records straight to JSON without building each
+    /// converted value first, where the output would be the same. Has no
+    /// effect with options which need the converted value, such as
+    /// `pretty`, `sort_keys` or `coerce`. Binary values are base64-encoded
+    /// as they are written, never all at once.
+    pub direct: bool,
+    pub on_broken_pipe: OnBrokenPipe,
+    /// Write a warning line here for each msgpack value not in its smallest
+    /// encoding, as canonical msgpack requires, giving its offset. For
+    /// [`InputFormat::JsonArrayOfMsgpack`] the offset is within the element.
+    pub check_canonical: Option<Box<dyn Write + Send>>,
+    /// Fail with [`Mp2JsonError::NonCanonical`] on any msgpack value not in
+    /// its smallest encoding
+    pub strict_canonical: bool,
+    /// Write a warning line here for each array or object in a record whose
+    /// members aren't all of one JSON type, giving its path. The objects
+    /// written for binary and ext values count as their own type.
+    pub check_homogeneous: Option<Box<dyn Write + Send>>,
+    /// Fail with [`Mp2JsonError::MixedTypes`] on any array or object whose
+    /// members aren't all of one JSON type
+    pub strict_homogeneous: bool,
+    /// Write the records as the elements of a single JSON array, rather
+    /// than one per line. The array is closed even if conversion stops early.
+    /// Each record is written as soon as it is converted, so no more than
+    /// one is held in memory at a time.
+    pub array: bool,
+    /// Write this text before anything else in the output
+    pub prefix: Option<String>,
+    /// Write this text after everything else in the output, once the
+    /// conversion has finished without an error
+    pub suffix: Option<String>,
+    /// Write this text at the start of each JSON record, as for
+    /// Server-Sent Events' `data: `. In an array it follows the separator.
+    pub line_prefix: Option<String>,
+    /// Begin each JSON record written with its 1-based number and a tab, as
+    /// a debugging aid; the output is then no longer JSON. Only records of
+    /// the input are numbered, not their types under `types_sidecar`, the
+    /// errors written under `errors_as_json`, or the `eof_marker`. Ignored
+    /// with `array`.
+    pub line_numbers: bool,
+    pub input_format: InputFormat,
+    pub output_encoding: OutputEncoding,
+    pub format: OutputFormat,
+    /// In CSV output, reject nested values rather than writing them as JSON
+    pub strict_csv: bool,
+    /// In env output, begin each assignment with `export`
+    pub env_export: bool,
+    /// In env output, write nested values as a variable per leaf, named by
+    /// joining the keys and indices on the way to it with `_`
+    pub env_flatten: bool,
+    /// In env output, uppercase variable names and replace the characters
+    /// not allowed in them with `_`, rather than rejecting them
+    pub env_sanitize_keys: bool,
+    /// Stop after the first record which takes the output to this many bytes
+    pub max_output_bytes: Option<u64>,
+    /// Once the whole input is converted, fail with
+    /// [`Mp2JsonError::TooFewRecords`] if fewer records than this were
+    /// written, as a check against truncated inputs. Records written are
+    /// those left after `sample` and the like, or the groups of `group_by`,
+    /// but not types, errors or the eof marker.
+    pub min_records: Option<u64>,
+    /// Once the whole input is converted, fail with
+    /// [`Mp2JsonError::TooManyRecords`] if more records than this were
+    /// written, counted as for `min_records`
+    pub max_records: Option<u64>,
+    /// Collect all records, then write a single object mapping the value at
+    /// this path to an array of the records which have it
+    pub group_by: Option<Path>,
+    /// Collect all records, then write a single object deep-merging them,
+    /// with later records taking precedence
+    pub merge: bool,
+    /// When merging, append arrays rather than replacing them
+    pub merge_arrays: bool,
+    /// Collect a uniformly random sample of this many records, then write
+    /// them in their input order
+    pub sample: Option<usize>,
+    /// Seed the random choice of `sample`, so that it is reproducible
+    pub seed: Option<u64>,
+    /// Write a single object counting the records with each value at this
+    /// path, with those that have none counted under `"__missing__"`
+    pub count_by: Option<Path>,
+    /// Write each record to a file chosen by its value at a path, instead
+    /// of to the output
+    pub route: Option<Route>,
+    /// Rules forcing the values at particular paths of each record to a
+    /// particular type, failing if a value cannot be converted
+    pub coerce: Vec<Coercion>,
+    /// Replace every array in each record which has exactly one element by
+    /// that element, before any other change to the record. This loses the
+    /// difference between `[x]` and `x`.
+    pub unwrap_singletons: bool,
+    /// Remove the entries with these keys from every object in each record,
+    /// at any depth
+    pub drop_keys: Vec<String>,
+    /// Remove the values at these paths from each record, in turn
+    pub drop_paths: Vec<Path>,
+    /// Rules replacing the values at particular paths of each record,
+    /// keeping their keys, as for sharing dumps of sensitive data
+    pub redact: Vec<Redaction>,
+    /// Prefixed to each value before hashing it for [`RedactMode::Hash`],
+    /// so that the hashes of guessable values can't simply be looked up
+    pub redact_salt: String,
+    /// Fail on the first record which doesn't match this schema, before
+    /// writing it. Records are checked as converted and coerced, before
+    /// any other change.
+    pub schema: Option<Schema>,
+    /// Rather than failing on the first record not matching `schema`, write
+    /// every record and fail at the end with
+    /// [`Mp2JsonError::SchemaViolations`] listing them all
+    pub schema_continue: bool,
+    /// Write only the value at this path of each record, or `null` where
+    /// there is none
+    pub select: Option<Path>,
+    /// Write string records as their bare text, without quotes or escapes
+    pub raw_output: bool,
+    /// Once the input is finished, write a JSON object here giving the
+    /// number and total compact size of the values at each path of the
+    /// records, with array elements merged under `[*]`
+    pub size_report: Option<Box<dyn Write + Send>>,
+    /// Once the input is finished, write a JSON array here with an entry
+    /// `{"index": N, "out": [start, end], "in": [start, end]}` for each
+    /// msgpack record written to the output, giving the byte ranges of its
+    /// output (including any separator before it) and its input
+    pub source_map: Option<Box<dyn Write + Send>>,
+    /// Afterwards, write a line here giving how many records were converted
+    /// and skipped, how much input was read, and how long it took
+    pub summary: Option<Box<dyn Write + Send>>,
+    /// Also write a copy of every byte of the output here. This copy is
+    /// complete even if the main output's pipe is closed early.
+    pub tee: Option<Box<dyn Write + Send>>,
+    /// Afterwards, write a line here giving the SHA-256 digest of all the
+    /// input read, as `SHA256 (input) = <hex>`
+    pub hash_input: Option<Box<dyn Write + Send>>,
+    /// Afterwards, write a line here giving the SHA-256 digest of all the
+    /// output, as `SHA256 (output) = <hex>`
+    pub hash_output: Option<Box<dyn Write + Send>>,
+    /// Stop converting, as though the input had ended, once this is set
+    pub interrupt: Option<&'static AtomicBool>,
+    /// Convert msgpack records on this many threads at once, writing them in
+    /// their original order; 0 and 1 both convert on the calling thread
+    pub jobs: usize,
+    pub options: ConvertOptions,
+}
+
+/// Worker threads recurse once per level of nesting, so give them as much
+/// stack as the main thread usually gets
+const WORKER_STACK_SIZE: usize = 8 << 20;
+
+/// Whether a decoding error just means that the input has run out
+fn is_end_of_input(e: &Mp2JsonError) -> bool {
+    matches!(
+        e,
+        Mp2JsonError::RmpDecode(rmpv::decode::Error::InvalidMarkerRead(e))
+            if e.kind() == std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Whether an error only affects the record it occurred in, leaving the
+/// input positioned at the next record
+fn is_record_error(e: &Mp2JsonError) -> bool {
+    !matches!(
+        e,
+        Mp2JsonError::TruncatedInput
+            | Mp2JsonError::MissingFrameMarker(_)
+            | Mp2JsonError::ResyncFailed(_)
+            | Mp2JsonError::ReservedMarker(_)
+            | Mp2JsonError::RmpDecode(_)
+            | Mp2JsonError::Output(_)
+    )
+}
+
+/// Replace every array within `v` which has exactly one element by that
+/// element, repeatedly, so that `[[1]]` becomes `1`
+fn unwrap_singletons(v: &mut JsonValue) {
+    while let JsonValue::Array(a) = v {
+        if a.len() != 1 {
+            break;
+        }
+        *v = a.pop().expect("the array has an element");
+    }
+    match v {
+        JsonValue::Array(a) => a.iter_mut().for_each(unwrap_singletons),
+        JsonValue::Object(o) => o.iter_mut().for_each(|(_, v)| unwrap_singletons(v)),
+        _ => {}
+    }
+}
+
+/// Remove the entries with any of `keys` from every object within `v`,
+/// other than the objects written for binary and ext values
+fn drop_keys(v: &mut JsonValue, keys: &[String]) {
+    match v {
+        JsonValue::Array(a) => a.iter_mut().for_each(|v| drop_keys(v, keys)),
+        JsonValue::Object(o) if is_binary_wrapper(o) => {}
+        JsonValue::Object(o) => {
+            for key in keys {
+                o.remove(key);
+            }
+            o.iter_mut().for_each(|(_, v)| drop_keys(v, keys));
+        }
+        _ => {}
+    }
+}
+
+/// Recursively reorder the keys of every object in `v`
+fn sort_keys(v: &mut JsonValue) {
+    match v {
+        JsonValue::Array(a) => a.iter_mut().for_each(sort_keys),
+        JsonValue::Object(o) => {
+            let mut entries: Vec<(String, JsonValue)> = o
+                .iter_mut()
+                .map(|(k, v)| (k.to_string(), v.take()))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut sorted = JsonObject::with_capacity(entries.len());
+            for (k, mut v) in entries {
+                sort_keys(&mut v);
+                sorted.insert(&k, v);
+            }
+            *o = sorted;
+        }
+        _ => {}
+    }
+}
+
+/// Replace an object with an array of its keys or values, returning `None`
+/// for anything else
+fn project(v: JsonValue, projection: Projection) -> Option<JsonValue> {
+    let JsonValue::Object(mut o) = v else {
+        return None;
+    };
+    let items = match projection {
+        Projection::Keys => o.iter().map(|(k, _)| k.into()).collect(),
+        Projection::Values => o.iter_mut().map(|(_, v)| v.take()).collect(),
+    };
+    Some(JsonValue::Array(items))
+}
+
+/// The mutable state of a single run of a [`Converter`]
+#[derive(Default)]
+struct RunState {
+    aggregate: Option<aggregate::Aggregate>,
+    sizes: Option<sizes::SizeReport>,
+    router: Option<route::Router>,
+    csv: csv::CsvWriter,
+    /// The number of records written to the output
+    written: u64,
+    /// The number of records written, or routed to their own outputs,
+    /// other than types, errors and the eof marker: those numbered by
+    /// `line_numbers` and bounded by `min_records` and `max_records`
+    numbered: u64,
+    /// The number of records written as errors under `errors_as_json`
+    errors: u64,
+    /// The number of records converted, including those then skipped
+    records: u64,
+    /// The number of records skipped as not being objects to project
+    skipped: u64,
+    /// Records not matching the schema, by their index in the input
+    violations: Vec<(u64, Violation)>,
+    /// Where to warn of msgpack values not in their smallest encoding
+    non_canonical: Option<Box<dyn Write + Send>>,
+    /// Where to warn of arrays and objects with members of mixed types
+    mixed_types: Option<Box<dyn Write + Send>>,
+    /// The entries of the source map, if one is to be written
+    source_map: Option<Vec<JsonValue>>,
+    /// The bytes of the record being written, kept to reuse the allocation
+    line: Vec<u8>,
+    /// The offset just past the last msgpack record written
+    consumed: u64,
+    /// Records written since the last checkpoint
+    since_checkpoint: u64,
+}
+
+impl Converter {
+    /// Write `v` as JSON, sorting the keys of its objects on the way if
+    /// `sort_keys` is set
+    fn write_json<W: Write>(
+        &self,
+        v: &JsonValue,
+        sort_keys: bool,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        if let (true, Some(s)) = (self.raw_output, v.as_str()) {
+            output.write_all(s.as_bytes())
+        } else if self.canonical {
+            canonical::write(v, output)
+        } else if self.pretty_width.is_some()
+            || (self.pretty && (self.collapse_below.is_some() || self.align.is_some()))
+        {
+            // Breaking every container at width 0 is the same as `pretty`
+            pretty::write_width(
+                v,
+                self.pretty_width.unwrap_or(0),
+                self.float_notation,
+                sort_keys,
+                self.collapse_below,
+                self.align,
+                output,
+            )
+        } else {
+            let spaces = self.pretty.then_some(2);
+            generator::OutputGenerator::new(output, spaces, self.float_notation)
+                .sorted(sort_keys)
+                .write_json(v)
+        }
+    }
+
+    fn write_value<W: Write>(
+        &self,
+        v: &JsonValue,
+        sort_keys: bool,
+        output: &mut W,
+    ) -> std::io::Result<()> {
+        self.write_json(v, sort_keys, output)?;
+        output.write_all(&[0x0a])
+    }
+
+    fn annotate(&self, marker: Option<u8>, v: JsonValue) -> JsonValue {
+        match marker {
+            Some(m) if self.annotate_types => {
+                let mut o = JsonObject::with_capacity(2);
+                o.insert("type", marker::type_name(m).into());
+                o.insert("value", v);
+                o.into()
+            }
+            _ => v,
+        }
+    }
+
+    fn attribute(&self, span: Option<(u64, u64)>, v: JsonValue) -> JsonValue {
+        let Some((offset, _)) = span else {
+            return v;
+        };
+        let index = self.sources.partition_point(|(start, _)| *start <= offset);
+        match index.checked_sub(1) {
+            Some(index) => {
+                let mut o = JsonObject::with_capacity(2);
+                o.insert("file", self.sources[index].1.as_str().into());
+                o.insert("value", v);
+                o.into()
+            }
+            None => v,
+        }
+    }
+
+    fn locate(&self, span: Option<(u64, u64)>, v: JsonValue) -> JsonValue {
+        match span {
+            Some((offset, length)) if self.include_offsets => {
+                let mut o = JsonObject::with_capacity(3);
+                o.insert("offset", offset.into());
+                o.insert("length", length.into());
+                o.insert("value", v);
+                o.into()
+            }
+            _ => v,
+        }
+    }
+
+    /// Write one converted record, returning `false` if the output has gone
+    /// away and no further records should be produced
+    fn emit<W: Write>(
+        &self,
+        state: &mut RunState,
+        marker: Option<u8>,
+        span: Option<(u64, u64)>,
+        v: JsonValue,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        state.records += 1;
+        let mut v = v;
+        if self.unwrap_singletons {
+            unwrap_singletons(&mut v);
+        }
+        for coercion in &self.coerce {
+            coercion.apply(&mut v)?;
+        }
+        if !self.drop_keys.is_empty() {
+            drop_keys(&mut v, &self.drop_keys);
+        }
+        for path in &self.drop_paths {
+            path.remove(&mut v);
+        }
+        for redaction in &self.redact {
+            redaction.apply(&mut v, &self.redact_salt);
+        }
+        if let Some(schema) = &self.schema {
+            if let Err(violation) = schema.validate(&v) {
+                let index = state.records + state.errors - 1;
+                if !self.schema_continue {
+                    return Err(Mp2JsonError::SchemaViolation { index, violation });
+                }
+                state.violations.push((index, violation));
+            }
+        }
+        if self.strict_homogeneous || state.mixed_types.is_some() {
+            let index = state.records + state.errors - 1;
+            for mixed in homogeneous::find_mixed(&v) {
+                let types = mixed.types.join(", ");
+                if self.strict_homogeneous {
+                    return Err(Mp2JsonError::MixedTypes {
+                        index,
+                        path: mixed.path,
+                        types,
+                    });
+                }
+                if let Some(warnings) = &mut state.mixed_types {
+                    writeln!(
+                        warnings,
+                        "warning: record {} has values of mixed types at {}: {}",
+                        index, mixed.path, types
+                    )
+                    .map_err(Mp2JsonError::Output)?;
+                }
+            }
+        }
+        if let Some(path) = &self.select {
+            v = match path.lookup_mut(&mut v) {
+                Some(selected) => selected.take(),
+                None => JsonValue::Null,
+            };
+        }
+        // Where nothing else depends on the order of the keys, leave sorting
+        // them to the writer rather than rebuilding every object first
+        let sort_while_writing = self.sort_keys
+            && self.format == OutputFormat::Json
+            && self.projection.is_none()
+            && !self.annotate_types
+            && !self.include_offsets
+            && self.sources.is_empty()
+            && state.sizes.is_none()
+            && state.aggregate.is_none();
+        if self.sort_keys && !sort_while_writing {
+            sort_keys(&mut v);
+        }
+        if let Some(order) = self.sort_by_value {
+            order::sort_by_value(&mut v, order);
+        }
+        if let Some(projection) = self.projection {
+            v = match project(v, projection) {
+                Some(v) => v,
+                None if self.skip_non_objects => {
+                    state.skipped += 1;
+                    return Ok(true);
+                }
+                None => return Err(Mp2JsonError::ProjectionRecordNotObject),
+            };
+        }
+        let v = self.attribute(span, self.locate(span, self.annotate(marker, v)));
+        if let Some(sizes) = &mut state.sizes {
+            sizes.add(&v);
+        }
+        if let Some(aggregate) = &mut state.aggregate {
+            aggregate.add(v)?;
+            return Ok(true);
+        }
+        if let Some(router) = &mut state.router {
+            router
+                .output_for(&v)
+                .and_then(|file| self.write_value(&v, sort_while_writing, file))
+                .map_err(Mp2JsonError::Output)?;
+            state.numbered += 1;
+            return Ok(true);
+        }
+        if !self.write_record(state, &v, sort_while_writing, true, output)? {
+            return Ok(false);
+        }
+        Ok(self.max_output_bytes.is_none_or(|max| output.count() < max))
+    }
+
+    /// Like [`Converter::emit`] for a [`Record`], following it with its
+    /// msgpack types if there are any, and noting where it was written in
+    /// the source map
+    fn emit_record<W: Write>(
+        &self,
+        state: &mut RunState,
+        marker: Option<u8>,
+        span: Option<(u64, u64)>,
+        record: Record,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        let start = output.count();
+        let more = self.emit_record_inner(state, marker, span, record, output)?;
+        let end = output.count();
+        if let (Some(entries), Some((offset, length))) = (&mut state.source_map, span) {
+            if end > start {
+                let mut o = JsonObject::with_capacity(3);
+                o.insert("index", (state.records + state.errors - 1).into());
+                o.insert("out", json::array![start, end]);
+                o.insert("in", json::array![offset, offset + length]);
+                entries.push(o.into());
+            }
+        }
+        Ok(more)
+    }
+
+    fn emit_record_inner<W: Write>(
+        &self,
+        state: &mut RunState,
+        marker: Option<u8>,
+        span: Option<(u64, u64)>,
+        record: Record,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        match record {
+            Record::Converted(v, types) => {
+                if !self.emit(state, marker, span, v, output)? {
+                    return Ok(false);
+                }
+                match types {
+                    Some(mut types) => {
+                        if self.sort_keys {
+                            sort_keys(&mut types);
+                        }
+                        self.write_record(state, &types, false, false, output)
+                    }
+                    None => Ok(true),
+                }
+            }
+            Record::Direct(v) => {
+                state.records += 1;
+                let written = if direct::has_large_binary(&v) {
+                    self.write_line_streamed(state, output, true, |output| {
+                        direct::write(&v, output)
+                    })
+                } else {
+                    self.write_line(state, output, true, |output| direct::write(&v, output))
+                };
+                if !self.record_written(state, written, true, output)? {
+                    return Ok(false);
+                }
+                Ok(self.max_output_bytes.is_none_or(|max| output.count() < max))
+            }
+        }
+    }
+
+    fn checks_canonical(&self, state: &RunState) -> bool {
+        self.strict_canonical || state.non_canonical.is_some()
+    }
+
+    /// Warn of or fail on the values of the msgpack record at `offset`
+    /// which aren't in their smallest encoding
+    fn report_non_minimal(
+        &self,
+        state: &mut RunState,
+        non_minimal: Vec<minimal::NonMinimal>,
+        offset: u64,
+    ) -> Result<(), Mp2JsonError> {
+        for found in non_minimal {
+            let offset = offset + found.offset;
+            if self.strict_canonical {
+                return Err(Mp2JsonError::NonCanonical {
+                    offset,
+                    description: found.description,
+                });
+            }
+            if let Some(warnings) = &mut state.non_canonical {
+                writeln!(
+                    warnings,
+                    "warning: non-canonical msgpack at byte {}: {}",
+                    offset, found.description
+                )
+                .map_err(Mp2JsonError::Output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether [`Converter::direct`] applies: nothing needs the converted
+    /// value other than writing it compactly
+    fn writes_directly(&self) -> bool {
+        let opts = &self.options;
+        self.direct
+            && self.format == OutputFormat::Json
+            && !self.pretty
+            && self.pretty_width.is_none()
+            && self.float_notation == FloatNotation::Auto
+            && !self.sort_keys
+            && self.sort_by_value.is_none()
+            && !self.canonical
+            && self.projection.is_none()
+            && !self.annotate_types
+            && !self.include_offsets
+            && self.sources.is_empty()
+            && !self.types_sidecar
+            && self.group_by.is_none()
+            && !self.merge
+            && self.sample.is_none()
+            && self.count_by.is_none()
+            && self.route.is_none()
+            && self.coerce.is_empty()
+            && !self.unwrap_singletons
+            && self.drop_keys.is_empty()
+            && self.drop_paths.is_empty()
+            && self.redact.is_empty()
+            && self.schema.is_none()
+            && self.check_homogeneous.is_none()
+            && !self.strict_homogeneous
+            && self.select.is_none()
+            && !self.raw_output
+            && self.size_report.is_none()
+            && opts.int_as_timestamp.is_none()
+            && opts.large_integers == LargeIntegers::Number
+            && !opts.preserve_number_type
+            && opts.binary_encoding == BinaryEncoding::Base64
+            && opts.binary_threshold == 0
+            && !opts.decode_nested_msgpack
+            && !opts.reject_binary
+            && !opts.parse_json_strings
+            && !opts.typed_nulls
+            && opts.max_depth.is_none()
+            && opts.max_string_length.is_none()
+            && opts.key_case.is_none()
+            && opts.ordered_maps.is_none()
+    }
+
+    /// Write a record in the output format, returning `false` if the output
+    /// has gone away. Only records of the input are `numbered`.
+    fn write_record<W: Write>(
+        &self,
+        state: &mut RunState,
+        v: &JsonValue,
+        sort_keys: bool,
+        numbered: bool,
+        output: &mut W,
+    ) -> Result<bool, Mp2JsonError> {
+        let written = match self.format {
+            OutputFormat::Json => self.write_line(state, output, numbered, |output| {
+                self.write_json(v, sort_keys, output)
+            }),
+            OutputFormat::Csv => {
+                let rows = state.csv.record(v, self.strict_csv)?;
+                output.write_all(rows.as_bytes())
+            }
+            OutputFormat::Env => {
+                let style = env::EnvStyle {
+                    export: self.env_export,
+                    flatten: self.env_flatten,
+                    sanitize_keys: self.env_sanitize_keys,
+                };
+                output.write_all(style.record(v)?.as_bytes())
+            }
+        };
+        self.record_written(state, written, numbered, output)
+    }
+
+    /// Write one JSON record with `write`, separated from the others as
+    /// the output requires
+    fn write_line<W: Write>(
+        &self,
+        state: &mut RunState,
+        output: &mut W,
+        numbered: bool,
+        write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        // The record goes to the output in a single `write_all`, so that a
+        // `BufWriter` copies small records into its buffer but flushes and
+        // passes those larger than the buffer straight through, rather than
+        // copying them through it piece by piece
+        let mut line = std::mem::take(&mut state.line);
+        line.clear();
+        self.start_line(state, numbered, &mut line);
+        write(&mut line)?;
+        if !self.array {
+            line.push(b'\n');
+        }
+        let written = output.write_all(&line);
+        state.line = line;
+        written
+    }
+
+    /// Like [`Converter::write_line`], but with `write` writing straight
+    /// to the output, for records too large to be worth collecting first
+    fn write_line_streamed<W: Write>(
+        &self,
+        state: &mut RunState,
+        output: &mut W,
+        numbered: bool,
+        write: impl FnOnce(&mut W) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut line = std::mem::take(&mut state.line);
+        line.clear();
+        self.start_line(state, numbered, &mut line);
+        let written = output.write_all(&line).and_then(|_| write(output));
+        state.line = line;
+        written?;
+        if self.array {
+            Ok(())
+        } else {
+            output.write_all(b"\n")
+        }
+    }
+
+    /// Begin a JSON record with whatever separates it from the others
+    fn start_line(&self, state: &RunState, numbered: bool, line: &mut Vec<u8>) {
+        if self.array {
+            line.extend_from_slice(if state.written == 0 { b"[\n" } else { b",\n" });
+        } else if self.line_numbers && numbered {
+            line.extend_from_slice(format!("{}\t", state.numbered + 1).as_bytes());
+        }
+        if let Some(prefix) = &self.line_prefix {
+            line.extend_from_slice(prefix.as_bytes());
+        }
+    }
+
+    /// Finish writing a record, `numbered` if it is a record of the input,
+    /// returning `false` if the output has gone away
+    fn record_written<W: Write>(
+        &self,
+        state: &mut RunState,
+        written: std::io::Result<()>,
+        numbered: bool,
+        output: &mut W,
+    ) -> Result<bool, Mp2JsonError> {
+        let written = written.and_then(|_| {
+            if self.line_buffered {
+                output.flush()
+            } else {
+                Ok(())
+            }
+        });
+        let written = self.on_broken_pipe.check(written)?;
+        if written {
+            state.written += 1;
+            state.numbered += u64::from(numbered);
+        }
+        Ok(written)
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    /// Write an error as a record of the output. Returns `Ok(true)` to carry
+    /// on with the next record, `Ok(false)` if the output has gone away, or
+    /// the error itself if it left the input unreadable.
+    fn write_error<W: Write>(
+        &self,
+        state: &mut RunState,
+        e: Mp2JsonError,
+        offset: u64,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        let mut o = JsonObject::with_capacity(2);
+        o.insert("__error__", e.to_string().into());
+        o.insert("offset", offset.into());
+        state.errors += 1;
+        if !self.write_record(state, &o.into(), false, false, output)? {
+            return Ok(false);
+        }
+        if is_record_error(&e) {
+            Ok(true)
+        } else {
+            Err(e)
+        }
+    }
+
+    /// Where the input starts, counting any part of it skipped
+    fn base_offset(&self) -> u64 {
+        self.start_offset + self.skip_bytes
+    }
+
+    /// Note that the msgpack record at `span` has been written in full,
+    /// saving a checkpoint if one is due
+    fn progress<W: Write>(
+        &self,
+        state: &mut RunState,
+        (offset, length): (u64, u64),
+        output: &mut W,
+    ) -> Result<(), Mp2JsonError> {
+        state.consumed = offset + length;
+        state.since_checkpoint += 1;
+        if state.since_checkpoint >= self.checkpoint_interval.max(1) {
+            self.save_checkpoint(state, output)?;
+        }
+        Ok(())
+    }
+
+    /// Record in the checkpoint file the offset just past the last record
+    /// written, once everything before it is safely out of our buffers
+    fn save_checkpoint<W: Write>(
+        &self,
+        state: &mut RunState,
+        output: &mut W,
+    ) -> Result<(), Mp2JsonError> {
+        let Some(path) = &self.checkpoint else {
+            return Ok(());
+        };
+        state.since_checkpoint = 0;
+        output.flush().map_err(Mp2JsonError::Output)?;
+        // Replace the file in one step, so that it is never found half-written
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        std::fs::write(&partial, format!("{}\n", state.consumed))
+            .and_then(|_| std::fs::rename(&partial, path))
+            .map_err(Mp2JsonError::Output)
+    }
+
+    /// Note where to resume after a record with invalid UTF-8, if asked to
+    fn resumable(&self, e: Mp2JsonError, (offset, length): (u64, u64)) -> Mp2JsonError {
+        match e {
+            Mp2JsonError::InvalidString if self.resumable => Mp2JsonError::Resumable {
+                resume_offset: offset + length,
+                source: Box::new(e),
+            },
+            e => e,
+        }
+    }
+
+    /// Convert a stream of concatenated msgpack values, returning `true` if
+    /// the whole stream was consumed
+    fn convert_stream<R: Read, W: Write>(
+        &self,
+        state: &mut RunState,
+        input: R,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        if self.jobs > 1 && self.frame_marker.is_none() {
+            return self.convert_stream_parallel(state, input, output);
+        }
+        // Counting above any buffering makes the offsets exact, since each
+        // value is read byte for byte
+        let mut input = CountingReader::new(marker::MarkerReader::new(input));
+        let direct = self.writes_directly();
+        let check_minimal = self.checks_canonical(state);
+        loop {
+            if self.interrupted() {
+                return Ok(false);
+            }
+            let offset = self.base_offset() + input.count();
+            let result = read_record(
+                &mut input,
+                &self.options,
+                self.types_sidecar,
+                direct,
+                check_minimal,
+            )
+            .and_then(|(record, non_minimal)| {
+                self.report_non_minimal(state, non_minimal, offset)?;
+                Ok(record)
+            });
+            let span = (offset, self.base_offset() + input.count() - offset);
+            // Every whole record, even one which couldn't be converted, must
+            // be followed by the marker
+            let result = match self.frame_marker {
+                Some(frame_marker) if result.as_ref().err().is_none_or(is_record_error) => {
+                    frame::read_marker(&mut input, frame_marker).and(result)
+                }
+                _ => result,
+            };
+            // Only now, as otherwise the frame marker would be taken as the
+            // next record's first byte
+            let marker = input.get_mut().take_marker();
+            let read = (offset, self.base_offset() + input.count() - offset);
+            match result {
+                Ok(record) => {
+                    if !self.emit_record(state, marker, Some(span), record, output)? {
+                        return Ok(false);
+                    }
+                    self.progress(state, read, output)?;
+                }
+                Err(_) if self.interrupted() => return Ok(false),
+                Err(e) if is_end_of_input(&e) => return Ok(true),
+                Err(Mp2JsonError::TruncatedInput) if self.allow_truncated => return Ok(true),
+                Err(e) if self.errors_as_json => {
+                    if !self.write_error(state, e, span.0, output)? {
+                        return Ok(false);
+                    }
+                    self.progress(state, read, output)?;
+                }
+                Err(e) => return Err(self.resumable(e, read)),
+            }
+        }
+    }
+
+    /// Like [`Converter::convert_stream`], but splitting the input into raw
+    /// records on this thread and converting them on a pool of workers.
+    ///
+    /// Results are written in input order, so at most a window of records
+    /// ahead of the oldest unwritten one are read before it is finished. A
+    /// record which cannot be framed is converted as-is, so that the error
+    /// it produces is the same as without workers.
+    fn convert_stream_parallel<R: Read, W: Write>(
+        &self,
+        state: &mut RunState,
+        mut input: R,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        let window = self.jobs * 16;
+        let (work_tx, work_rx) = mpsc::channel::<(u64, u64, Vec<u8>)>();
+        let work_rx = Mutex::new(work_rx);
+        let (done_tx, done_rx) = mpsc::channel();
+        let options = &self.options;
+        let types_sidecar = self.types_sidecar;
+        let direct = self.writes_directly();
+        let check_minimal = self.checks_canonical(state);
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs {
+                let work_rx = &work_rx;
+                let done_tx = done_tx.clone();
+                std::thread::Builder::new()
+                    .stack_size(WORKER_STACK_SIZE)
+                    .spawn_scoped(scope, move || loop {
+                        let job = work_rx.lock().expect("worker panicked").recv();
+                        let Ok((seq, offset, record)) = job else {
+                            break;
+                        };
+                        let result = read_record(
+                            &mut record.as_slice(),
+                            options,
+                            types_sidecar,
+                            direct,
+                            check_minimal,
+                        );
+                        let span = (offset, record.len() as u64);
+                        if done_tx
+                            .send((seq, record.first().copied(), span, result))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    })
+                    .expect("failed to spawn conversion worker");
+            }
+            drop(done_tx);
+
+            let mut work_tx = Some(work_tx);
+            let mut read_error = None;
+            let mut finished = BTreeMap::new();
+            let (mut next_read, mut next_write) = (0u64, 0u64);
+            let mut offset = self.base_offset();
+            loop {
+                while next_read - next_write < window as u64 {
+                    let Some(tx) = &work_tx else {
+                        break;
+                    };
+                    // Records already read are still finished and written
+                    if self.interrupted() {
+                        work_tx = None;
+                        break;
+                    }
+                    let mut record = Vec::new();
+                    let more = match frame::read_frame(&mut input, &mut record) {
+                        Ok(more) => more,
+                        Err(_) if self.interrupted() => {
+                            record.clear();
+                            false
+                        }
+                        Err(Mp2JsonError::TruncatedInput) => false,
+                        Err(e) => {
+                            read_error = Some((offset, e));
+                            record.clear();
+                            false
+                        }
+                    };
+                    if !record.is_empty() {
+                        let length = record.len() as u64;
+                        tx.send((next_read, offset, record))
+                            .expect("conversion workers exited early");
+                        next_read += 1;
+                        offset += length;
+                    }
+                    if !more {
+                        work_tx = None;
+                    }
+                }
+                if next_write == next_read {
+                    break;
+                }
+                let (seq, marker, span, result) =
+                    done_rx.recv().expect("conversion workers exited early");
+                finished.insert(seq, (marker, span, result));
+                while let Some((marker, span, result)) = finished.remove(&next_write) {
+                    next_write += 1;
+                    let result = result.and_then(|(record, non_minimal)| {
+                        self.report_non_minimal(state, non_minimal, span.0)?;
+                        Ok(record)
+                    });
+                    match result {
+                        Ok(record) => {
+                            if !self.emit_record(state, marker, Some(span), record, output)? {
+                                return Ok(false);
+                            }
+                            self.progress(state, span, output)?;
+                        }
+                        Err(e) if is_end_of_input(&e) => return Ok(true),
+                        Err(Mp2JsonError::TruncatedInput) if self.allow_truncated => {
+                            return Ok(true)
+                        }
+                        Err(e) if self.errors_as_json => {
+                            if !self.write_error(state, e, span.0, output)? {
+                                return Ok(false);
+                            }
+                            self.progress(state, span, output)?;
+                        }
+                        Err(e) => return Err(self.resumable(e, span)),
+                    }
+                }
+            }
+            match read_error {
+                Some((offset, e)) if self.errors_as_json => {
+                    self.write_error(state, e, offset, output)
+                }
+                Some((_, e)) => Err(e),
+                None => Ok(!self.interrupted()),
+            }
+        })
+    }
+
+    /// Convert a JSON array whose elements are base64-encoded msgpack
+    /// values, returning `true` if every element was converted
+    fn convert_json_array<R: Read, W: Write>(
+        &self,
+        state: &mut RunState,
+        mut input: R,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        let mut document = String::new();
+        input
+            .read_to_string(&mut document)
+            .map_err(|e| Mp2JsonError::InvalidInputDocument(e.to_string()))?;
+        let direct = self.writes_directly();
+        let elements = match json::parse(&document) {
+            Ok(JsonValue::Array(elements)) => elements,
+            Ok(_) => {
+                return Err(Mp2JsonError::InvalidInputDocument(
+                    "expected an array".to_string(),
+                ))
+            }
+            Err(e) => return Err(Mp2JsonError::InvalidInputDocument(e.to_string())),
+        };
+        for (index, element) in elements.iter().enumerate() {
+            if self.interrupted() {
+                return Ok(false);
+            }
+            let element_error =
+                |reason: String| Mp2JsonError::InvalidInputElement { index, reason };
+            let encoded = element
+                .as_str()
+                .ok_or_else(|| element_error("not a string".to_string()))?;
+            let bytes = base64::decode(encoded)
+                .map_err(|e| element_error(format!("invalid base64: {e}")))?;
+            let mut reader = marker::MarkerReader::new(bytes.as_slice());
+            let record = read_record(
+                &mut reader,
+                &self.options,
+                self.types_sidecar,
+                direct,
+                self.checks_canonical(state),
+            )
+            .and_then(|(record, non_minimal)| {
+                self.report_non_minimal(state, non_minimal, 0)?;
+                Ok(record)
+            })
+            .map_err(|e| element_error(e.to_string()))?;
+            if !self.emit_record(state, reader.take_marker(), None, record, output)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reformat a stream of JSON values, returning `true` if every value
+    /// was converted
+    fn convert_json_values<R: Read, W: Write>(
+        &self,
+        state: &mut RunState,
+        input: R,
+        output: &mut CountingWriter<W>,
+    ) -> Result<bool, Mp2JsonError> {
+        for v in reverse::JsonValues::new(std::io::BufReader::new(input)) {
+            if self.interrupted() {
+                return Ok(false);
+            }
+            if !self.emit(state, None, None, v?, output)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn run_inner<R: Read, W: Write>(mut self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        let mut input = RetryReader::interruptible(input, self.interrupt);
+        std::io::copy(
+            &mut (&mut input).take(self.skip_bytes),
+            &mut std::io::sink(),
+        )
+        .map_err(|e| Mp2JsonError::InvalidInputDocument(e.to_string()))?;
+        let replay = match self.resync {
+            Some(limit) if self.input_format == InputFormat::Msgpack => {
+                let (skipped, replay) = frame::resync(&mut input, limit, &self.options)?;
+                self.skip_bytes += skipped;
+                replay
+            }
+            _ => Vec::new(),
+        };
+        let input = std::io::Cursor::new(replay).chain(input);
+        let mut input = CountingReader::new(input.take(self.input_limit.unwrap_or(u64::MAX)));
+        let mut output = CountingWriter::new(output);
+        if let Some(prefix) = &self.prefix {
+            self.on_broken_pipe
+                .check(output.write_all(prefix.as_bytes()))?;
+        }
+        let size_report = self.size_report.take();
+        let source_map = self.source_map.take();
+        let summary = self.summary.take();
+        let start = std::time::Instant::now();
+        let mut state = RunState {
+            aggregate: self
+                .group_by
+                .clone()
+                .map(aggregate::Aggregate::group_by)
+                .or_else(|| {
+                    self.merge
+                        .then(|| aggregate::Aggregate::merge(self.merge_arrays))
+                })
+                .or_else(|| {
+                    self.sample
+                        .map(|size| aggregate::Aggregate::sample(size, self.seed))
+                })
+                .or_else(|| self.count_by.clone().map(aggregate::Aggregate::count_by)),
+            sizes: size_report.is_some().then(sizes::SizeReport::default),
+            router: self.route.clone().map(route::Router::new),
+            non_canonical: self.check_canonical.take(),
+            mixed_types: self.check_homogeneous.take(),
+            source_map: source_map.is_some().then(Vec::new),
+            consumed: self.base_offset(),
+            ..Default::default()
+        };
+        let result = match self.input_format {
+            InputFormat::Msgpack => {
+                let result = self.convert_stream(&mut state, &mut input, &mut output);
+                // However the conversion ended, everything up to here is out
+                self.save_checkpoint(&mut state, &mut output)?;
+                match result {
+                    // A record cut off by the input limit isn't a truncated input
+                    Err(Mp2JsonError::TruncatedInput)
+                        if self.input_limit.is_some() && input.get_mut().limit() == 0 =>
+                    {
+                        Ok(true)
+                    }
+                    result => result,
+                }
+            }
+            InputFormat::JsonArrayOfMsgpack => {
+                self.convert_json_array(&mut state, &mut input, &mut output)
+            }
+            InputFormat::Json => self.convert_json_values(&mut state, &mut input, &mut output),
+        };
+        let completed = match result {
+            // A read cut short by the interrupt stops the conversion like any
+            // other, keeping everything written so far
+            Err(_) if self.interrupted() => false,
+            result => result?,
+        };
+        if let Some(router) = &mut state.router {
+            router.flush().map_err(Mp2JsonError::Output)?;
+        }
+        if completed {
+            let finals = state
+                .aggregate
+                .take()
+                .map(aggregate::Aggregate::finish)
+                .into_iter()
+                .flatten()
+                .map(|v| (v, true))
+                .chain(
+                    self.eof_marker
+                        .clone()
+                        .filter(|_| self.format == OutputFormat::Json)
+                        .map(|v| (v, false)),
+                );
+            for (v, numbered) in finals {
+                if !self.write_record(&mut state, &v, false, numbered, &mut output)? {
+                    break;
+                }
+            }
+        }
+        if self.array && self.format == OutputFormat::Json {
+            let close: &[u8] = if state.written == 0 {
+                b"[]\n"
+            } else {
+                b"\n]\n"
+            };
+            self.on_broken_pipe.check(output.write_all(close))?;
+        }
+        if let Some(suffix) = &self.suffix {
+            self.on_broken_pipe
+                .check(output.write_all(suffix.as_bytes()))?;
+        }
+        if let (Some(mut report_output), Some(sizes)) = (size_report, state.sizes) {
+            sizes
+                .finish()
+                .write_pretty(&mut report_output, 2)
+                .and_then(|_| report_output.write_all(b"\n"))
+                .and_then(|_| report_output.flush())
+                .map_err(Mp2JsonError::Output)?;
+        }
+        if let (Some(mut map_output), Some(entries)) = (source_map, state.source_map) {
+            JsonValue::Array(entries)
+                .write(&mut map_output)
+                .and_then(|_| map_output.write_all(b"\n"))
+                .and_then(|_| map_output.flush())
+                .map_err(Mp2JsonError::Output)?;
+        }
+        if let Some(mut summary_output) = summary {
+            let summary = summary::Summary {
+                converted: state.records - state.skipped,
+                skipped: state.skipped + state.errors,
+                bytes_in: input.count(),
+                elapsed: start.elapsed(),
+            };
+            writeln!(summary_output, "{}", summary)
+                .and_then(|_| summary_output.flush())
+                .map_err(Mp2JsonError::Output)?;
+        }
+        let count = state.numbered;
+        match (self.min_records, self.max_records) {
+            (Some(min), _) if completed && count < min => {
+                return Err(Mp2JsonError::TooFewRecords { count, min });
+            }
+            (_, Some(max)) if completed && count > max => {
+                return Err(Mp2JsonError::TooManyRecords { count, max });
+            }
+            _ => {}
+        }
+        if !state.violations.is_empty() {
+            return Err(Mp2JsonError::SchemaViolations(state.violations));
+        }
+        match state.errors {
+            0 => Ok(()),
+            errors => Err(Mp2JsonError::RecordErrors(errors)),
+        }
+    }
+
+    fn run_buffered<R: Read, W: Write>(self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        if self.buffered || self.line_buffered {
+            let on_broken_pipe = self.on_broken_pipe;
+            let mut output = std::io::BufWriter::new(output);
+            self.run_inner(std::io::BufReader::new(input), &mut output)?;
+            on_broken_pipe.check(output.flush())?;
+            Ok(())
+        } else {
+            self.run_inner(input, output)
+        }
+    }
+
+    fn run_encoded<R: Read, W: Write>(self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        match self.output_encoding {
+            OutputEncoding::Utf8 => self.run_buffered(input, output),
+            encoding => self.run_buffered(input, encoding::EncodingWriter::new(output, encoding)),
+        }
+    }
+
+    fn run_teed<R: Read, W: Write>(mut self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        match self.tee.take() {
+            Some(tee) => {
+                let on_broken_pipe = self.on_broken_pipe;
+                let mut output = TeeWriter::new(output, tee);
+                self.run_encoded(input, &mut output)?;
+                output.flush().map_err(Mp2JsonError::Output)?;
+                // The tee carries on after the output goes away
+                if output.primary_closed() {
+                    on_broken_pipe.check(Err(std::io::ErrorKind::BrokenPipe.into()))?;
+                }
+                Ok(())
+            }
+            None => self.run_encoded(input, output),
+        }
+    }
+
+    /// Convert all of `input`, writing the results to `output`
+    pub fn run<R: Read, W: Write>(mut self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        let hash_input = self.hash_input.take();
+        let hash_output = self.hash_output.take();
+        let mut input = HashingReader::new(input, hash_input.as_ref().map(|_| Default::default()));
+        let mut output =
+            HashingWriter::new(output, hash_output.as_ref().map(|_| Default::default()));
+        self.run_teed(&mut input, &mut output)?;
+        let digests = [
+            ("input", hash_input, input.into_hash()),
+            ("output", hash_output, output.into_hash()),
+        ];
+        for (name, report_output, hash) in digests {
+            if let (Some(mut report_output), Some(hash)) = (report_output, hash) {
+                writeln!(report_output, "SHA256 ({}) = {}", name, hash.finish_hex())
+                    .and_then(|_| report_output.flush())
+                    .map_err(Mp2JsonError::Output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use assert_matches::assert_matches;
+    use json::JsonValue;
+
+    use super::{
+        inspect_one, read_and_convert_one, BinaryEncoding, BinaryKeys, ConvertOptions, Converter,
+        DuplicateKeys, InputFormat, KeyCase, LargeIntegers, MaxDepthAction, Mp2JsonError,
+        OnBrokenPipe, OrderedMaps, OutputFormat, Projection, Route, Schema, TimestampScope,
+        TimestampUnit, TruncatedStrings,
+    };
+
+    #[test]
+    fn test_smoke() {
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\x01"), &ConvertOptions::default()).unwrap(),
+            JsonValue::Number(1.into())
+        );
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\xc0"), &ConvertOptions::default()).unwrap(),
+            JsonValue::Null
+        );
+        assert_eq!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\x81\xa3foo\xc4\x03bar"),
+                &ConvertOptions::default()
+            )
+            .unwrap()
+            .dump(),
+            r#"{"foo":{"encoding":"base64","value":"YmFy"}}"#.to_string(),
+        );
+    }
+
+    #[test]
+    fn test_empty_containers() {
+        let opts = ConvertOptions::default();
+        let convert = |input: &[u8]| read_and_convert_one(&mut Cursor::new(input), &opts).unwrap();
+        assert_eq!(convert(b"\x90"), JsonValue::new_array());
+        assert_eq!(convert(b"\x80"), JsonValue::new_object());
+        // Also as array 16/32 and map 16/32
+        assert_eq!(convert(b"\xdc\x00\x00"), JsonValue::new_array());
+        assert_eq!(convert(b"\xdf\x00\x00\x00\x00"), JsonValue::new_object());
+        // Pretty-printed without breaking empty containers open, even when
+        // every other container has to be
+        let input = b"\x90\x80\x82\xa1a\x90\xa1b\x80";
+        for (pretty, pretty_width) in [(true, None), (false, Some(0))] {
+            let c = Converter {
+                pretty,
+                pretty_width,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "[]\n{}\n{\n  \"a\": [],\n  \"b\": {}\n}\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_non_stringy_map() {
+        assert_matches!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\x81\x01\x02"),
+                &ConvertOptions::default()
+            ),
+            Err(Mp2JsonError::MapKeyNotString)
+        );
+    }
+
+    #[test]
+    fn test_max_string_length() {
+        let convert = |input: &[u8], opts: &ConvertOptions| {
+            read_and_convert_one(&mut Cursor::new(input), opts)
+                .unwrap()
+                .dump()
+        };
+        let opts = ConvertOptions {
+            max_string_length: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(convert(b"\xaaabcdefghij", &opts), "\"abcde\u{2026}\"");
+        assert_eq!(convert(b"\xa5abcde", &opts), "\"abcde\"");
+        // Counted in characters, never splitting one
+        assert_eq!(
+            convert(&[b"\xae", "\u{e9}".repeat(7).as_bytes()].concat(), &opts),
+            "\"\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{2026}\""
+        );
+        // Keys are left alone unless asked for
+        let input = b"\x81\xa7abcdefg\xa1x";
+        assert_eq!(convert(input, &opts), r#"{"abcdefg":"x"}"#);
+        let opts = ConvertOptions {
+            max_string_length: Some(5),
+            truncate_keys: true,
+            ..Default::default()
+        };
+        assert_eq!(convert(input, &opts), "{\"abcde\u{2026}\":\"x\"}");
+        let opts = ConvertOptions {
+            max_string_length: Some(5),
+            truncated_strings: TruncatedStrings::Verbose,
+            ..Default::default()
+        };
+        assert_eq!(
+            convert(b"\xaaabcdefghij", &opts),
+            "{\"__truncated_string__\":true,\"len\":10,\"value\":\"abcde\u{2026}\"}"
+        );
+        assert_eq!(convert(b"\xa5abcde", &opts), "\"abcde\"");
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let input = b"\x81\xc4\x02\x00\xff\x01";
+        let convert = |binary_keys| {
+            let opts = ConvertOptions {
+                binary_keys,
+                duplicate_keys: DuplicateKeys::Error,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(input), &opts).map(|v| v.dump())
+        };
+        assert_matches!(convert(None), Err(Mp2JsonError::MapKeyNotString));
+        assert_eq!(convert(Some(BinaryKeys::Base64)).unwrap(), r#"{"AP8=":1}"#);
+        assert_eq!(convert(Some(BinaryKeys::Hex)).unwrap(), r#"{"00ff":1}"#);
+        // Collisions follow the duplicate key policy
+        let opts = ConvertOptions {
+            binary_keys: Some(BinaryKeys::Hex),
+            duplicate_keys: DuplicateKeys::Error,
+            ..Default::default()
+        };
+        assert_matches!(
+            read_and_convert_one(&mut Cursor::new(b"\x82\xa2ab\x01\xc4\x01\xab\x02"), &opts),
+            Err(Mp2JsonError::DuplicateKey(k)) if k == "ab"
+        );
+    }
+
+    #[test]
+    fn test_ordered_maps() {
+        let convert = |input: &[u8], ordered_maps, duplicate_keys| {
+            let opts = ConvertOptions {
+                ordered_maps: Some(ordered_maps),
+                duplicate_keys,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(input), &opts).map(|v| v.dump())
+        };
+        // {"b": 1, "a": 2}
+        let input = b"\x82\xa1b\x01\xa1a\x02";
+        assert_eq!(
+            convert(input, OrderedMaps::Pairs, DuplicateKeys::Last).unwrap(),
+            r#"[["b",1],["a",2]]"#
+        );
+        assert_eq!(
+            convert(input, OrderedMaps::Objects, DuplicateKeys::Last).unwrap(),
+            r#"[{"b":1},{"a":2}]"#
+        );
+        // Nested, and with every duplicate kept
+        let input = b"\x82\xa1a\x81\xa1c\x01\xa1a\x02";
+        assert_eq!(
+            convert(input, OrderedMaps::Pairs, DuplicateKeys::Last).unwrap(),
+            r#"[["a",[["c",1]]],["a",2]]"#
+        );
+        assert_matches!(
+            convert(input, OrderedMaps::Pairs, DuplicateKeys::Error),
+            Err(Mp2JsonError::DuplicateKey(k)) if k == "a"
+        );
+    }
+
+    #[test]
+    fn test_key_case() {
+        let convert = |input: &[u8], key_case, duplicate_keys| {
+            let opts = ConvertOptions {
+                key_case: Some(key_case),
+                duplicate_keys,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(input), &opts).map(|v| v.dump())
+        };
+        let input = b"\x81\xa6fooBar\x01";
+        for (key_case, expected) in [
+            (KeyCase::Snake, r#"{"foo_bar":1}"#),
+            (KeyCase::Camel, r#"{"fooBar":1}"#),
+            (KeyCase::Kebab, r#"{"foo-bar":1}"#),
+            (KeyCase::Lower, r#"{"foobar":1}"#),
+            (KeyCase::Upper, r#"{"FOOBAR":1}"#),
+        ] {
+            assert_eq!(
+                convert(input, key_case, DuplicateKeys::Last).unwrap(),
+                expected
+            );
+        }
+        // Nested keys are rewritten, but not those of binary wrappers
+        assert_eq!(
+            convert(
+                b"\x81\xa5a_map\x81\xa5b_bin\xc4\x01\x00",
+                KeyCase::Camel,
+                DuplicateKeys::Last
+            )
+            .unwrap(),
+            r#"{"aMap":{"bBin":{"encoding":"base64","value":"AA=="}}}"#
+        );
+        // {"fooBar": 1, "foo_bar": 2}
+        let colliding = b"\x82\xa6fooBar\x01\xa7foo_bar\x02";
+        assert_eq!(
+            convert(colliding, KeyCase::Snake, DuplicateKeys::Last).unwrap(),
+            r#"{"foo_bar":2}"#
+        );
+        assert_matches!(
+            convert(colliding, KeyCase::Snake, DuplicateKeys::Error),
+            Err(Mp2JsonError::DuplicateKey(k)) if k == "foo_bar"
+        );
+    }
+
+    #[test]
+    fn test_reject_binary() {
+        let opts = ConvertOptions {
+            reject_binary: true,
+            ..Default::default()
+        };
+        let convert = |input: &[u8], opts: &ConvertOptions| {
+            read_and_convert_one(&mut Cursor::new(input), opts).map(|v| v.dump())
+        };
+        assert_matches!(
+            convert(b"\xc4\x03bar", &opts),
+            Err(Mp2JsonError::UnexpectedBinary)
+        );
+        assert_matches!(
+            convert(b"\x91\xd4\x01\x00", &opts),
+            Err(Mp2JsonError::UnexpectedBinary)
+        );
+        // Binary which converts to something else is fine
+        let nested = ConvertOptions {
+            decode_nested_msgpack: true,
+            ..opts
+        };
+        assert_eq!(convert(b"\xc4\x01\x07", &nested).unwrap(), "7");
+        // As a record error, it can be written in place of the record
+        let c = Converter {
+            options: ConvertOptions {
+                reject_binary: true,
+                ..Default::default()
+            },
+            errors_as_json: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert_matches!(
+            c.run(Cursor::new(b"\x01\xc4\x03bar\x02"), &mut output),
+            Err(Mp2JsonError::RecordErrors(1))
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "1\n",
+                r#"{"__error__":"msgpack binary or ext value found where none is allowed","offset":1}"#,
+                "\n2\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_typed_nulls() {
+        let opts = ConvertOptions {
+            typed_nulls: true,
+            ..Default::default()
+        };
+        // {"a": nil}
+        let v = read_and_convert_one(&mut Cursor::new(b"\x81\xa1a\xc0"), &opts).unwrap();
+        assert_eq!(v.dump(), r#"{"a":{"__nil__":true}}"#);
+        let v = read_and_convert_one(&mut Cursor::new(b"\xc0"), &opts).unwrap();
+        assert_eq!(v.dump(), r#"{"__nil__":true}"#);
+        let c = Converter {
+            direct: true,
+            options: opts,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(&b"\x92\xc0\x01"[..], &mut output).unwrap();
+        assert_eq!(output, b"[{\"__nil__\":true},1]\n");
+    }
+
+    #[test]
+    fn test_parse_json_strings() {
+        let convert = |input: &[u8], max_depth| {
+            let opts = ConvertOptions {
+                parse_json_strings: true,
+                max_depth,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(input), &opts)
+                .unwrap()
+                .dump()
+        };
+        // {"payload": "{\"x\":1}"}
+        assert_eq!(
+            convert(b"\x81\xa7payload\xa7{\"x\":1}", None),
+            r#"{"payload":{"x":1}}"#
+        );
+        // Doubly encoded
+        let doubly = br#"["{\"y\":[true]}"]"#;
+        let mut input = vec![0xa0 | doubly.len() as u8];
+        input.extend_from_slice(doubly);
+        assert_eq!(convert(&input, None), r#"[{"y":[true]}]"#);
+        // The inner string would nest too deeply, so is left alone
+        assert_eq!(convert(&input, Some(2)), r#"["{\"y\":[true]}"]"#);
+        // Scalars, and not JSON at all
+        assert_eq!(convert(b"\xa3123", None), r#""123""#);
+        assert_eq!(convert(b"\xa3{x}", None), r#""{x}""#);
+        assert_eq!(convert(b"\xa4[1]x", None), r#""[1]x""#);
+    }
+
+    #[test]
+    fn test_decode_nested_msgpack() {
+        let convert = |input: &[u8], max_depth| {
+            let opts = ConvertOptions {
+                decode_nested_msgpack: true,
+                max_depth,
+                on_max_depth: MaxDepthAction::Null,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(input), &opts)
+                .unwrap()
+                .dump()
+        };
+        // {"a": bin({"x": 1})}
+        let input = b"\x81\xa1a\xc4\x04\x81\xa1x\x01";
+        assert_eq!(convert(input, None), r#"{"a":{"x":1}}"#);
+        // The nested value is a level deeper than the binary value
+        assert_eq!(
+            convert(input, Some(1)),
+            r#"{"a":{"encoding":"base64","value":"gaF4AQ=="}}"#
+        );
+        // Binary values which aren't exactly one valid msgpack value
+        for (bytes, base64) in [
+            (&b"\xc4\x02\x01\x02"[..], "AQI="),
+            (b"\xc4\x01\xc1", "wQ=="),
+            (b"\xc4\x02\x92\x01", "kgE="),
+            (b"\xc4\x00", ""),
+        ] {
+            assert_eq!(
+                convert(bytes, None),
+                format!(r#"{{"encoding":"base64","value":"{}"}}"#, base64)
+            );
+        }
+        // Binary values nested far too deeply in each other stop being
+        // decoded rather than overflowing the stack
+        let mut deep = b"\x01".to_vec();
+        for _ in 0..2000 {
+            let len = deep.len() as u16;
+            deep = [&[0xc5][..], &len.to_be_bytes(), &deep].concat();
+        }
+        assert!(convert(&deep, None).contains("base64"));
+    }
+
+    #[test]
+    fn test_invalid_string() {
+        assert_matches!(
+            read_and_convert_one(&mut Cursor::new(b"\xa2\xc3("), &ConvertOptions::default()),
+            Err(Mp2JsonError::InvalidString)
+        );
+    }
+
+    #[test]
+    fn test_frame_marker() {
+        let run = |input: &[u8], errors_as_json| {
+            let c = Converter {
+                frame_marker: Some(0xc1),
+                errors_as_json,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let result = c.run(Cursor::new(input.to_vec()), &mut output);
+            (result, String::from_utf8(output).unwrap())
+        };
+        // {"a":1} then "\xc1" inside a string, then the end of the input
+        let (result, output) = run(b"\x81\xa1a\x01\xc1\xc4\x01\xc1\xc1", false);
+        result.unwrap();
+        assert_eq!(
+            output,
+            "{\"a\":1}\n{\"encoding\":\"base64\",\"value\":\"wQ==\"}\n"
+        );
+        assert_eq!(run(b"", false).1, "");
+        // Misaligned, and unterminated
+        for input in [&b"\x01\x02\xc1"[..], b"\x01\xc1\x02"] {
+            let (result, output) = run(input, false);
+            assert_matches!(result, Err(Mp2JsonError::MissingFrameMarker(0xc1)));
+            assert_eq!(output, if input[1] == 0xc1 { "1\n" } else { "" });
+        }
+        // A record which can't be converted still has its marker consumed
+        let (result, output) = run(b"\x81\x01\x02\xc1\x03\xc1", true);
+        assert_matches!(result, Err(Mp2JsonError::RecordErrors(1)));
+        assert_eq!(
+            output,
+            "{\"__error__\":\"Map key is not a string\",\"offset\":0}\n3\n"
+        );
+    }
+
+    #[test]
+    fn test_resync() {
+        let run = |input: &[u8], limit| {
+            let c = Converter {
+                resync: Some(limit),
+                include_offsets: true,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input.to_vec()), &mut output)
+                .map(|_| String::from_utf8(output).unwrap())
+        };
+        // Two junk bytes, then {"a":1} and 2
+        assert_eq!(
+            run(b"\xc1\xc1\x81\xa1a\x01\x02", 16).unwrap(),
+            concat!(
+                r#"{"offset":2,"length":4,"value":{"a":1}}"#,
+                "\n",
+                r#"{"offset":6,"length":1,"value":2}"#,
+                "\n"
+            )
+        );
+        assert_matches!(run(b"\xc1\xc1\x01", 1), Err(Mp2JsonError::ResyncFailed(1)));
+        // Junk after the start is left to the usual error handling
+        assert_matches!(
+            run(b"\x01\xc1\x02", 16),
+            Err(Mp2JsonError::ReservedMarker(0xc1))
+        );
+    }
+
+    #[test]
+    fn test_reserved_marker() {
+        for input in [&b"\xc1"[..], b"\x92\x01\xc1", b"\x81\xa1a\xc1"] {
+            assert_matches!(
+                read_and_convert_one(&mut Cursor::new(input), &ConvertOptions::default()),
+                Err(Mp2JsonError::ReservedMarker(0xc1))
+            );
+        }
+        let mut output = Vec::new();
+        assert_matches!(
+            Converter::default().run(Cursor::new(b"\x01\xc1\x02"), &mut output),
+            Err(Mp2JsonError::ReservedMarker(0xc1))
+        );
+        assert_eq!(output, b"1\n");
+    }
+
+    #[test]
+    fn test_eof_marker() {
+        let c = Converter {
+            eof_marker: Some(json::object! { "__eof__": true }),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(Cursor::new(b"\x01\x02"), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\n2\n{\"__eof__\":true}\n"
+        );
+    }
+
+    #[test]
+    fn test_eof_marker_json_only() {
+        for (format, expected) in [
+            (OutputFormat::Csv, "a\n1\n"),
+            (OutputFormat::Env, "a='1'\n"),
+        ] {
+            let c = Converter {
+                eof_marker: Some(json::object! { "__eof__": true }),
+                format,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(b"\x81\xa1a\x01"), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_eof_marker_not_written_on_error() {
+        let c = Converter {
+            eof_marker: Some(json::object! { "__eof__": true }),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert!(c
+            .run(Cursor::new(b"\x01\x81\x01\x02"), &mut output)
+            .is_err());
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn test_strict_rejects_inexact_integer() {
+        // 2^53 + 1
+        let input = b"\xcf\x00\x20\x00\x00\x00\x00\x00\x01";
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &ConvertOptions::default())
+                .unwrap()
+                .dump(),
+            "9007199254740993"
+        );
+        assert_matches!(
+            read_and_convert_one(&mut Cursor::new(input), &ConvertOptions::strict()),
+            Err(Mp2JsonError::InexactInteger(_))
+        );
+        assert_eq!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\xcf\x00\x20\x00\x00\x00\x00\x00\x00"),
+                &ConvertOptions::strict()
+            )
+            .unwrap()
+            .dump(),
+            "9007199254740992"
+        );
+    }
+
+    #[test]
+    fn test_i64_min_does_not_overflow() {
+        // Negating i64::MIN as an i64 panicked in debug builds; every path
+        // the fuzz target takes must convert it, alone and nested
+        let inputs: [&[u8]; 2] = [
+            b"\xd3\x80\x00\x00\x00\x00\x00\x00\x00",
+            b"\x81\xa1a\x91\xd3\x80\x00\x00\x00\x00\x00\x00\x00",
+        ];
+        for input in inputs {
+            for opts in [ConvertOptions::default(), ConvertOptions::strict()] {
+                let _ = read_and_convert_one(&mut Cursor::new(input), &opts);
+            }
+            for c in [
+                Converter::default(),
+                Converter {
+                    annotate_types: true,
+                    ..Default::default()
+                },
+                Converter {
+                    pretty_width: Some(40),
+                    ..Default::default()
+                },
+            ] {
+                let mut output = Vec::new();
+                c.run(input, &mut output).unwrap();
+                assert!(String::from_utf8(output)
+                    .unwrap()
+                    .contains("-9223372036854775808"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_integers_stay_exact() {
+        for (input, expected) in [
+            (
+                &b"\xcf\xff\xff\xff\xff\xff\xff\xff\xff"[..],
+                "18446744073709551615",
+            ),
+            (
+                b"\xcf\x80\x00\x00\x00\x00\x00\x00\x00",
+                "9223372036854775808",
+            ),
+            (
+                b"\xd3\x80\x00\x00\x00\x00\x00\x00\x00",
+                "-9223372036854775808",
+            ),
+            (b"\xcf\x00\x20\x00\x00\x00\x00\x00\x01", "9007199254740993"),
+        ] {
+            let v =
+                read_and_convert_one(&mut Cursor::new(input), &ConvertOptions::default()).unwrap();
+            assert_eq!(v.dump(), expected);
+            let (_, _, exponent) = v.as_number().unwrap().as_parts();
+            assert_eq!(exponent, 0, "{} went through a float", expected);
+        }
+    }
+
+    #[test]
+    fn test_large_integers_as_strings() {
+        let opts = ConvertOptions {
+            large_integers: LargeIntegers::String,
+            ..Default::default()
+        };
+        let convert = |input: &[u8]| {
+            read_and_convert_one(&mut Cursor::new(input), &opts)
+                .unwrap()
+                .dump()
+        };
+        // 2^53, and 2^53 + 1 both ways
+        assert_eq!(
+            convert(b"\xcf\x00\x20\x00\x00\x00\x00\x00\x00"),
+            "9007199254740992"
+        );
+        assert_eq!(
+            convert(b"\xcf\x00\x20\x00\x00\x00\x00\x00\x01"),
+            r#""9007199254740993""#
+        );
+        assert_eq!(
+            convert(b"\xd3\xff\xe0\x00\x00\x00\x00\x00\x00"),
+            "-9007199254740992"
+        );
+        assert_eq!(
+            convert(b"\xd3\xff\xdf\xff\xff\xff\xff\xff\xff"),
+            r#""-9007199254740993""#
+        );
+        assert_eq!(convert(b"\xd0\x85"), "-123");
+    }
+
+    #[test]
+    fn test_strict_rejects_non_finite_and_duplicates() {
+        assert_matches!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\xcb\x7f\xf8\x00\x00\x00\x00\x00\x00"),
+                &ConvertOptions::strict()
+            ),
+            Err(Mp2JsonError::NonFiniteFloat)
+        );
+        assert_matches!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\x82\xa1a\x01\xa1a\x02"),
+                &ConvertOptions::strict()
+            ),
+            Err(Mp2JsonError::DuplicateKey(k)) if k == "a"
+        );
+    }
+
+    #[test]
+    fn test_annotate_types() {
+        let c = Converter {
+            annotate_types: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(Cursor::new(b"\x01\xcc\x01"), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"type":"positive_fixint","value":1}"#,
+                "\n",
+                r#"{"type":"uint8","value":1}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        // A map whose value never arrives
+        let input = b"\x01\x81\xa3foo";
+        for jobs in [1, 2] {
+            let c = Converter {
+                jobs,
+                eof_marker: Some(JsonValue::Null),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(input), &mut output),
+                Err(Mp2JsonError::TruncatedInput)
+            );
+            assert_eq!(output, b"1\n");
+
+            let c = Converter {
+                jobs,
+                allow_truncated: true,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            assert_eq!(output, b"1\n");
+        }
+        let mut output = Vec::new();
+        Converter::default()
+            .run(Cursor::new(b""), &mut output)
+            .unwrap();
+        assert_eq!(output, b"");
+    }
+
+    #[test]
+    fn test_projection() {
+        let input = b"\x82\xa1a\x01\xa1b\x02\x05";
+        let run = |projection, skip_non_objects| {
+            let c = Converter {
+                projection: Some(projection),
+                skip_non_objects,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let result = c.run(Cursor::new(input), &mut output);
+            (String::from_utf8(output).unwrap(), result)
+        };
+        let (output, result) = run(Projection::Keys, false);
+        assert_eq!(output, "[\"a\",\"b\"]\n");
+        assert_matches!(result, Err(Mp2JsonError::ProjectionRecordNotObject));
+        let (output, result) = run(Projection::Values, true);
+        assert_eq!(output, "[1,2]\n");
+        assert_matches!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_resumable() {
+        // The second record is a string of invalid UTF-8
+        let input = b"\x01\xa2\xff\xfe\x02";
+        for jobs in [1, 2] {
+            let c = Converter {
+                resumable: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(input), &mut output),
+                Err(Mp2JsonError::Resumable { resume_offset: 4, source })
+                    if matches!(*source, Mp2JsonError::InvalidString)
+            );
+            assert_eq!(output, b"1\n");
+
+            let c = Converter {
+                skip_bytes: 4,
+                include_offsets: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "{\"offset\":4,\"length\":1,\"value\":2}\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_types_sidecar() {
+        // The same record with its value as a str and as a bin
+        for (input, expected) in [
+            (&b"\x81\xa1a\xa1x"[..], r#"{"a":"x"}"#, r#"{"a":"str"}"#),
+            (
+                &b"\x81\xa1a\xc4\x01x"[..],
+                r#"{"a":{"encoding":"base64","value":"eA=="}}"#,
+                r#"{"a":"bin"}"#,
+            ),
+        ]
+        .map(|(input, value, types)| (input, format!("{}\n{}\n", value, types)))
+        {
+            for jobs in [1, 2] {
+                let c = Converter {
+                    types_sidecar: true,
+                    jobs,
+                    ..Default::default()
+                };
+                let mut output = Vec::new();
+                c.run(Cursor::new(input), &mut output).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), expected);
+            }
+        }
+        // A whole float64 is written like an integer, so only the types tell
+        let c = Converter {
+            types_sidecar: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(
+            Cursor::new(b"\x93\xc0\xcb\x3f\xf0\x00\x00\x00\x00\x00\x00\x91\xc3"),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[null,1,[true]]\n[\"nil\",\"float64\",[\"bool\"]]\n"
+        );
+    }
+
+    #[test]
+    fn test_errors_as_json() {
+        // The second record has an integer key
+        let input = b"\x01\x81\x01\x02\x03";
+        for jobs in [1, 2] {
+            let c = Converter {
+                errors_as_json: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(input), &mut output),
+                Err(Mp2JsonError::RecordErrors(1))
+            );
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "1\n{\"__error__\":\"Map key is not a string\",\"offset\":1}\n3\n"
+            );
+        }
+        // Errors in framing can't be skipped past
+        for jobs in [1, 2] {
+            let c = Converter {
+                errors_as_json: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(b"\x01\x92\x01\xc1"), &mut output),
+                Err(Mp2JsonError::ReservedMarker(0xc1))
+            );
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                concat!(
+                    "1\n",
+                    r#"{"__error__":"msgpack input contained the reserved marker byte 0xc1","offset":1}"#,
+                    "\n"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_input_limit() {
+        let input = b"\x01\x81\xa1a\x02";
+        for (limit, expected) in [(1, "1\n"), (3, "1\n"), (5, "1\n{\"a\":2}\n")] {
+            for buffered in [false, true] {
+                let c = Converter {
+                    input_limit: Some(limit),
+                    buffered,
+                    ..Default::default()
+                };
+                let mut output = Vec::new();
+                c.run(Cursor::new(input), &mut output).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_include_offsets() {
+        for jobs in [1, 2] {
+            let c = Converter {
+                include_offsets: true,
+                buffered: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(b"\x01\x81\xa1a\x02"), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                concat!(
+                    r#"{"offset":0,"length":1,"value":1}"#,
+                    "\n",
+                    r#"{"offset":1,"length":4,"value":{"a":2}}"#,
+                    "\n"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_interrupt_flushes_partial_output() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        /// Raises the interrupt flag as soon as anything is read
+        struct Interrupting(Cursor<&'static [u8]>);
+
+        impl Read for Interrupting {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+                self.0.read(buf)
+            }
+        }
+
+        let c = Converter {
+            buffered: true,
+            interrupt: Some(&INTERRUPTED),
+            eof_marker: Some(JsonValue::Null),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(Interrupting(Cursor::new(b"\x01\x02")), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn test_interrupt_stops_blocked_read() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        /// Gives its bytes, then blocks until a signal raises the interrupt
+        /// flag and fails the read with EINTR
+        struct Blocking(Cursor<&'static [u8]>);
+
+        impl Read for Blocking {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.position() < self.0.get_ref().len() as u64 {
+                    return self.0.read(buf);
+                }
+                assert!(
+                    !INTERRUPTED.swap(true, Ordering::SeqCst),
+                    "read retried after the interrupt"
+                );
+                Err(std::io::ErrorKind::Interrupted.into())
+            }
+        }
+
+        for (input_format, input, jobs) in [
+            (InputFormat::Msgpack, &b"\x01\x02"[..], 1),
+            (InputFormat::Msgpack, b"\x01\x02", 2),
+            (InputFormat::Json, b"1 2 ", 1),
+        ] {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+            let c = Converter {
+                buffered: true,
+                input_format,
+                jobs,
+                errors_as_json: input_format == InputFormat::Msgpack,
+                interrupt: Some(&INTERRUPTED),
+                eof_marker: Some(JsonValue::Null),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Blocking(Cursor::new(input)), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "1\n2\n");
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_and_resume() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        /// Raises the interrupt flag once three bytes have been read
+        struct Interrupting(Cursor<&'static [u8]>);
+
+        impl Read for Interrupting {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.0.read(buf)?;
+                if self.0.position() >= 3 {
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                }
+                Ok(n)
+            }
+        }
+
+        // 1, "a", [1, 2], 3
+        let input: &[u8] = b"\x01\xa1a\x92\x01\x02\x03";
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        let mut output = Vec::new();
+        Converter {
+            interrupt: Some(&INTERRUPTED),
+            checkpoint: Some(checkpoint.clone()),
+            ..Default::default()
+        }
+        .run(Interrupting(Cursor::new(input)), &mut output)
+        .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output), "1\n\"a\"\n");
+        let offset: u64 = std::fs::read_to_string(&checkpoint)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(offset, 3);
+
+        Converter {
+            skip_bytes: offset,
+            checkpoint: Some(checkpoint.clone()),
+            checkpoint_interval: 1,
+            ..Default::default()
+        }
+        .run(Cursor::new(input), &mut output)
+        .unwrap();
+        let mut single = Vec::new();
+        Converter::default()
+            .run(Cursor::new(input), &mut single)
+            .unwrap();
+        assert_eq!(output, single);
+        assert_eq!(std::fs::read_to_string(&checkpoint).unwrap(), "7\n");
+    }
+
+    #[test]
+    fn test_interrupted_reads_are_retried() {
+        /// Fails every other read with EINTR
+        struct Flaky<R> {
+            inner: R,
+            fail: bool,
+        }
+
+        impl<R: Read> Read for Flaky<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.fail = !self.fail;
+                if self.fail {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                self.inner.read(&mut buf[..1])
+            }
+        }
+
+        for (input_format, input) in [
+            (InputFormat::Msgpack, &b"\x81\xa1a\x92\x01\xa2xy\x05"[..]),
+            (InputFormat::Json, b"{\"a\":[1,\"xy\"]} 5"),
+        ] {
+            for buffered in [false, true] {
+                let c = Converter {
+                    buffered,
+                    input_format,
+                    ..Default::default()
+                };
+                let mut output = Vec::new();
+                let input = Flaky {
+                    inner: input,
+                    fail: false,
+                };
+                c.run(input, &mut output).unwrap();
+                assert_eq!(
+                    String::from_utf8(output).unwrap(),
+                    "{\"a\":[1,\"xy\"]}\n5\n"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_array_of_msgpack() {
+        let c = Converter {
+            input_format: InputFormat::JsonArrayOfMsgpack,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // base64 of \x01 and of {"a": "b"}
+        c.run(Cursor::new(br#"["AQ==", "gaFhoWI="]"#), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n{\"a\":\"b\"}\n");
+
+        let c = Converter {
+            input_format: InputFormat::JsonArrayOfMsgpack,
+            ..Default::default()
+        };
+        assert_matches!(
+            c.run(Cursor::new(br#"["AQ==", 2]"#), Vec::new()),
+            Err(Mp2JsonError::InvalidInputElement { index: 1, .. })
+        );
+    }
+
+    #[test]
+    fn test_json_input_sort_keys() {
+        let c = Converter {
+            input_format: InputFormat::Json,
+            sort_keys: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(
+            Cursor::new("{\"b\":1,\"a\":2}\n{\n  \"d\": {\"z\": 1, \"y\": [{\"c\":0,\"b\":0}]},\n  \"c\": 3\n}"),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"a\":2,\"b\":1}\n{\"c\":3,\"d\":{\"y\":[{\"b\":0,\"c\":0}],\"z\":1}}\n"
+        );
+    }
+
+    #[test]
+    fn test_sort_while_writing_matches_sorting_first() {
+        let records = [
+            json::object! { "b": 1, "a": { "y": [], "x": {} }, "é": null, "A": "s" },
+            json::object! {
+                "z": [{ "d": 1.5, "c": [{ "k2": true, "k1": false }] }, 3],
+                "m": { "long key to force wrapping": "a long value to force wrapping", "b": 2 }
+            },
+            json::array![{ "b": 1, "a": 2 }, []],
+            json::object! {},
+        ];
+        let input: String = records.iter().map(|v| v.dump() + "\n").collect();
+        let presorted: String = records
+            .iter()
+            .map(|v| {
+                let mut v = v.clone();
+                super::sort_keys(&mut v);
+                v.dump() + "\n"
+            })
+            .collect();
+        let run = |input: &str, sort_keys, pretty, pretty_width, array| {
+            let c = Converter {
+                input_format: InputFormat::Json,
+                sort_keys,
+                pretty,
+                pretty_width,
+                array,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input.to_string()), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        for (pretty, pretty_width) in [(false, None), (true, None), (true, Some(30))] {
+            for array in [false, true] {
+                assert_eq!(
+                    run(&input, true, pretty, pretty_width, array),
+                    run(&presorted, false, pretty, pretty_width, array)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonical() {
+        let c = Converter {
+            canonical: true,
+            pretty: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"b": 1e30, "a": "\u{7f}"}
+        c.run(
+            Cursor::new(b"\x82\xa1b\xcb\x46\x29\x3e\x59\x39\xa0\x8c\xea\xa1a\xa1\x7f"),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"a\":\"\u{7f}\",\"b\":1e+30}\n"
+        );
+    }
+
+    #[test]
+    fn test_ext_handler_fallback() {
+        let options = ConvertOptions {
+            ext_handler: Some(Box::new(|type_code, _| {
+                (type_code == 42).then(|| "handled".into())
+            })),
+            ..Default::default()
+        };
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\xd4\x2a\x07"), &options).unwrap(),
+            "handled"
+        );
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\xd4\x2b\x07"), &options)
+                .unwrap()
+                .dump(),
+            r#"{"type_code":43,"encoding":"base64","value":"Bw=="}"#
+        );
+    }
+
+    #[test]
+    fn test_ext_decoders() {
+        let options = ConvertOptions {
+            ext_decoders: vec!["f64=1".parse().unwrap(), "f32=2".parse().unwrap()],
+            ..Default::default()
+        };
+        let convert = |input: &[u8], options: &ConvertOptions| {
+            read_and_convert_one(&mut Cursor::new(input), options).map(|v| v.dump())
+        };
+        // fixext8 of 1.5, fixext4 of 0.25, and fixext4 of type 1
+        let f64_payload = b"\xd7\x01\x3f\xf8\x00\x00\x00\x00\x00\x00";
+        assert_eq!(convert(f64_payload, &options).unwrap(), "1.5");
+        assert_eq!(
+            convert(b"\xd6\x02\x3e\x80\x00\x00", &options).unwrap(),
+            "0.25"
+        );
+        assert_eq!(
+            convert(b"\xd6\x01\x3e\x80\x00\x00", &options).unwrap(),
+            r#"{"type_code":1,"encoding":"base64","value":"PoAAAA=="}"#
+        );
+        // NaN follows the non-finite policy
+        let nan = b"\xd7\x01\x7f\xf8\x00\x00\x00\x00\x00\x00";
+        assert_eq!(convert(nan, &options).unwrap(), "null");
+        let strict = ConvertOptions {
+            ext_decoders: options.ext_decoders.clone(),
+            ..ConvertOptions::strict()
+        };
+        assert_matches!(convert(nan, &strict), Err(Mp2JsonError::NonFiniteFloat));
+    }
+
+    #[test]
+    fn test_ext_decoders_before_handler() {
+        let options = ConvertOptions {
+            ext_decoders: vec!["f64=1".parse().unwrap()],
+            ext_handler: Some(Box::new(|_, bytes| Some(bytes.len().into()))),
+            ..Default::default()
+        };
+        let convert = |input: &[u8]| {
+            read_and_convert_one(&mut Cursor::new(input), &options)
+                .unwrap()
+                .dump()
+        };
+        // fixext8 of 1.5 is decoded, while fixext4 of type 1 can't be and
+        // falls through to the handler, as does any other type code
+        assert_eq!(convert(b"\xd7\x01\x3f\xf8\x00\x00\x00\x00\x00\x00"), "1.5");
+        assert_eq!(convert(b"\xd6\x01\x3e\x80\x00\x00"), "4");
+        assert_eq!(convert(b"\xd4\x02\x07"), "1");
+    }
+
+    #[test]
+    fn test_decimal128() {
+        let options = ConvertOptions {
+            ext_decoders: vec!["decimal128=5".parse().unwrap()],
+            ..Default::default()
+        };
+        let convert = |payload: u128| {
+            let mut input = b"\xd8\x05".to_vec();
+            input.extend_from_slice(&payload.to_be_bytes());
+            read_and_convert_one(&mut Cursor::new(input), &options)
+                .unwrap()
+                .dump()
+        };
+        // The exponent is biased by 6176 and stored above a 113-bit
+        // coefficient
+        let decimal = |negative: bool, exponent: i32, coefficient: u128| {
+            u128::from(negative) << 127 | ((exponent + 6176) as u128) << 113 | coefficient
+        };
+        assert_eq!(convert(0x3040_0000_0000_0000_0000_0000_0000_0001), "\"1\"");
+        assert_eq!(
+            convert(0x303e_0000_0000_0000_0000_0000_0000_0005),
+            "\"0.5\""
+        );
+        assert_eq!(convert(decimal(false, -1, 10)), "\"1.0\"");
+        assert_eq!(convert(decimal(true, -2, 12345)), "\"-123.45\"");
+        assert_eq!(convert(decimal(false, -7, 5)), "\"5E-7\"");
+        assert_eq!(convert(decimal(false, -6, 5)), "\"0.000005\"");
+        assert_eq!(convert(decimal(false, 3, 12)), "\"1.2E+4\"");
+        assert_eq!(convert(decimal(true, 0, 0)), "\"-0\"");
+        assert_eq!(convert(0x7800 << 112), "\"Infinity\"");
+        assert_eq!(convert(0x7c00 << 112), "\"NaN\"");
+        // A coefficient beyond 34 digits means 0
+        assert_eq!(convert(decimal(false, 0, 10u128.pow(34))), "\"0\"");
+        // Only a 16-byte payload is a decimal128
+        assert_eq!(
+            read_and_convert_one(
+                &mut Cursor::new(b"\xd7\x05\x00\x00\x00\x00\x00\x00\x00\x01"),
+                &options
+            )
+            .unwrap()
+            .dump(),
+            r#"{"type_code":5,"encoding":"base64","value":"AAAAAAAAAAE="}"#
+        );
+    }
+
+    #[test]
+    fn test_decimal128_before_handler() {
+        let options = ConvertOptions {
+            ext_decoders: vec!["decimal128=5".parse().unwrap()],
+            ext_handler: Some(Box::new(|_, bytes| Some(bytes.len().into()))),
+            ..Default::default()
+        };
+        let convert = |input: &[u8]| {
+            read_and_convert_one(&mut Cursor::new(input), &options)
+                .unwrap()
+                .dump()
+        };
+        let mut decimal = b"\xd8\x05".to_vec();
+        decimal.extend_from_slice(&0x3040_0000_0000_0000_0000_0000_0000_0001u128.to_be_bytes());
+        assert_eq!(convert(&decimal), "\"1\"");
+        // Only payloads which aren't 16 bytes reach the handler
+        assert_eq!(convert(b"\xd7\x05\x00\x00\x00\x00\x00\x00\x00\x01"), "8");
+    }
+
+    #[test]
+    fn test_on_broken_pipe() {
+        /// An output whose reader has gone away
+        struct ClosedPipe;
+
+        impl Write for ClosedPipe {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for (buffered, array) in [(false, false), (true, false), (false, true)] {
+            let run = |on_broken_pipe| {
+                Converter {
+                    buffered,
+                    array,
+                    on_broken_pipe,
+                    ..Default::default()
+                }
+                .run(Cursor::new(b"\x01\x02"), ClosedPipe)
+            };
+            assert_matches!(run(OnBrokenPipe::ExitOk), Ok(()));
+            assert_matches!(
+                run(OnBrokenPipe::Error),
+                Err(Mp2JsonError::Output(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+            );
+        }
+    }
+
+    #[test]
+    fn test_line_buffered_flushes_each_record() {
+        /// Records how much had been written at each flush
+        #[derive(Default)]
+        struct FlushRecorder {
+            written: Vec<u8>,
+            flushed_at: Vec<usize>,
+        }
+
+        impl Write for FlushRecorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushed_at.push(self.written.len());
+                Ok(())
+            }
+        }
+
+        let c = Converter {
+            line_buffered: true,
+            ..Default::default()
+        };
+        let mut output = FlushRecorder::default();
+        c.run(Cursor::new(b"\x01\x02\x03"), &mut output).unwrap();
+        assert_eq!(output.written, b"1\n2\n3\n");
+        assert_eq!(output.flushed_at, vec![2, 4, 6, 6]);
+    }
+
+    #[test]
+    fn test_int_as_timestamp() {
+        let seconds = ConvertOptions {
+            int_as_timestamp: Some(TimestampScope::TopLevel),
+            ..Default::default()
+        };
+        let millis = ConvertOptions {
+            int_as_timestamp: Some(TimestampScope::TopLevel),
+            timestamp_unit: TimestampUnit::Ms,
+            ..Default::default()
+        };
+        // 1700000000
+        let input = b"\xce\x65\x53\xf1\x00";
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &seconds).unwrap(),
+            "2023-11-14T22:13:20Z"
+        );
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &millis).unwrap(),
+            "1970-01-20T16:13:20.000Z"
+        );
+        // i64::MAX seconds is long past the year 9999, and stays a number
+        let input = b"\xd3\x7f\xff\xff\xff\xff\xff\xff\xff";
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &seconds)
+                .unwrap()
+                .dump(),
+            "9223372036854775807"
+        );
+    }
+
+    #[test]
+    fn test_int_as_timestamp_scope() {
+        let input = b"\x81\xa1t\xce\x65\x53\xf1\x00";
+        let top_level = ConvertOptions {
+            int_as_timestamp: Some(TimestampScope::TopLevel),
+            ..Default::default()
+        };
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &top_level)
+                .unwrap()
+                .dump(),
+            r#"{"t":1700000000}"#
+        );
+        let all = ConvertOptions {
+            int_as_timestamp: Some(TimestampScope::All),
+            ..Default::default()
+        };
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(input), &all)
+                .unwrap()
+                .dump(),
+            r#"{"t":"2023-11-14T22:13:20Z"}"#
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let options = ConvertOptions {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\x91\x91\xa1a"), &options)
+                .unwrap()
+                .dump(),
+            r#"[["a"]]"#
+        );
+        assert_matches!(
+            read_and_convert_one(&mut Cursor::new(b"\x91\x91\x91\x01"), &options),
+            Err(Mp2JsonError::MaxDepthExceeded)
+        );
+        let mut deep = vec![0x91; 100_000];
+        deep.push(0x01);
+        assert_matches!(
+            read_and_convert_one(&mut Cursor::new(deep), &options),
+            Err(Mp2JsonError::MaxDepthExceeded)
+        );
+    }
+
+    #[test]
+    fn test_on_max_depth() {
+        // [[[1,[[2,3]]]]]
+        let depth_5 = b"\x91\x91\x92\x01\x91\x92\x02\x03";
+        let run = |on_max_depth| {
+            let options = ConvertOptions {
+                max_depth: Some(3),
+                on_max_depth,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(depth_5), &options).map(|v| v.dump())
+        };
+        assert_matches!(
+            run(MaxDepthAction::Error),
+            Err(Mp2JsonError::MaxDepthExceeded)
+        );
+        assert_eq!(
+            run(MaxDepthAction::Truncate).unwrap(),
+            r#"[[[1,"__truncated__"]]]"#
+        );
+        assert_eq!(run(MaxDepthAction::Null).unwrap(), "[[[1,null]]]");
+    }
+
+    #[test]
+    fn test_coerce() {
+        let c = Converter {
+            coerce: vec!["id=string".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"id":5} {"id":"6"}
+        c.run(Cursor::new(b"\x81\xa2id\x05\x81\xa2id\xa16"), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"id\":\"5\"}\n{\"id\":\"6\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_unwrap_singletons() {
+        let c = Converter {
+            unwrap_singletons: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"a":[1]}, {"b":[1,2]}, {"c":[[[]]],"d":[{"e":["x"]}]}
+        let input = b"\x81\xa1a\x91\x01\x81\xa1b\x92\x01\x02\x82\xa1c\x91\x91\x90\xa1d\x91\x81\xa1e\x91\xa1x";
+        c.run(Cursor::new(input), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"a\":1}\n{\"b\":[1,2]}\n{\"c\":[],\"d\":{\"e\":\"x\"}}\n"
+        );
+    }
+
+    #[test]
+    fn test_drop_keys() {
+        let run = |c: Converter, input: &[u8]| {
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        // {"user":"x","password":"y"}
+        let input = b"\x82\xa4user\xa1x\xa8password\xa1y";
+        let c = Converter {
+            drop_keys: vec!["password".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(run(c, input), "{\"user\":\"x\"}\n");
+        // {"a":[{"value":1}],"b":bin8 "z"}
+        let input = b"\x82\xa1a\x91\x81\xa5value\x01\xa1b\xc4\x01z";
+        let c = Converter {
+            drop_keys: vec!["value".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            run(c, input),
+            "{\"a\":[{}],\"b\":{\"encoding\":\"base64\",\"value\":\"eg==\"}}\n"
+        );
+        let c = Converter {
+            drop_paths: vec!["a[0].value".parse().unwrap(), "b.value".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            run(c, input),
+            "{\"a\":[{}],\"b\":{\"encoding\":\"base64\",\"value\":\"eg==\"}}\n"
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        let c = Converter {
+            redact: vec!["ssn".parse().unwrap(), "tags=length".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"ssn":"123","tags":[1,2]}
+        c.run(
+            Cursor::new(b"\x82\xa3ssn\xa3123\xa4tags\x92\x01\x02"),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"ssn\":\"***\",\"tags\":2}\n"
+        );
+    }
+
+    #[test]
+    fn test_route() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = Converter {
+            route: Some(Route {
+                path: "type".parse().unwrap(),
+                template: dir.path().join("out-{value}.json").display().to_string(),
+            }),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"type":"a","n":1} {"type":"b","n":2} {"type":"a","n":3} {"n":4}
+        let input = b"\x82\xa4type\xa1a\xa1n\x01\x82\xa4type\xa1b\xa1n\x02\
+                      \x82\xa4type\xa1a\xa1n\x03\x81\xa1n\x04";
+        c.run(Cursor::new(input), &mut output).unwrap();
+        assert!(output.is_empty());
+        let read = |value: &str| {
+            std::fs::read_to_string(dir.path().join(format!("out-{}.json", value))).unwrap()
+        };
+        assert_eq!(
+            read("a"),
+            "{\"type\":\"a\",\"n\":1}\n{\"type\":\"a\",\"n\":3}\n"
+        );
+        assert_eq!(read("b"), "{\"type\":\"b\",\"n\":2}\n");
+        assert_eq!(read("__unrouted__"), "{\"n\":4}\n");
+    }
+
+    #[test]
+    fn test_array() {
+        let run = |input: &[u8], max_output_bytes| {
+            let c = Converter {
+                array: true,
+                max_output_bytes,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input.to_vec()), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(b"", None), "[]\n");
+        assert_eq!(run(b"\x01", None), "[\n1\n]\n");
+        assert_eq!(run(b"\x01\x91\x02\xc0", None), "[\n1,\n[2],\nnull\n]\n");
+        assert_eq!(run(b"\x01\x02\x03", Some(4)), "[\n1,\n2\n]\n");
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let run = |c: Converter| {
+            let mut output = Vec::new();
+            c.run(&b"\x81\xa1a\x01\x02"[..], &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            run(Converter {
+                line_prefix: Some("data: ".to_string()),
+                ..Default::default()
+            }),
+            "data: {\"a\":1}\ndata: 2\n"
+        );
+        assert_eq!(
+            run(Converter {
+                prefix: Some("{\"records\":".to_string()),
+                suffix: Some("}\n".to_string()),
+                array: true,
+                ..Default::default()
+            }),
+            "{\"records\":[\n{\"a\":1},\n2\n]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let c = Converter {
+            line_numbers: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(&b"\x81\xa1a\x01\x02\x90"[..], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\t{\"a\":1}\n2\t2\n3\t[]\n"
+        );
+
+        // 1, a map with a nil key, 2, then the end of the input
+        let c = Converter {
+            line_numbers: true,
+            errors_as_json: true,
+            types_sidecar: true,
+            eof_marker: Some(JsonValue::Null),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert_matches!(
+            c.run(&b"\x01\x81\xc0\x01\x02"[..], &mut output),
+            Err(Mp2JsonError::RecordErrors(1))
+        );
+        let lines: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                line.split_once('\t')
+                    .map_or("", |(number, _)| number)
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(lines, ["1", "", "", "2", "", ""]);
+    }
+
+    #[test]
+    fn test_record_count_bounds() {
+        let run = |min_records, max_records| {
+            let c = Converter {
+                min_records,
+                max_records,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(&b"\x01"[..], &mut output).map(|_| output)
+        };
+        assert_matches!(
+            run(Some(2), None),
+            Err(Mp2JsonError::TooFewRecords { count: 1, min: 2 })
+        );
+        assert_eq!(run(Some(1), Some(1)).unwrap(), b"1\n");
+        assert_matches!(
+            run(None, Some(0)),
+            Err(Mp2JsonError::TooManyRecords { count: 1, max: 0 })
+        );
+
+        // Only records written count, not those read
+        let input = b"\x81\xa1t\x01\x81\xa1t\x01\x81\xa1t\x01";
+        let mut output = Vec::new();
+        Converter {
+            group_by: Some("t".parse().unwrap()),
+            max_records: Some(1),
+            ..Default::default()
+        }
+        .run(&input[..], &mut output)
+        .unwrap();
+        assert_eq!(output.iter().filter(|&&b| b == b'\n').count(), 1);
+        let sample = |min_records, max_records| {
+            Converter {
+                sample: Some(1),
+                min_records,
+                max_records,
+                ..Default::default()
+            }
+            .run(&b"\x01\x02\x03"[..], std::io::sink())
+        };
+        assert_matches!(sample(None, Some(1)), Ok(()));
+        assert_matches!(
+            sample(Some(2), None),
+            Err(Mp2JsonError::TooFewRecords { count: 1, min: 2 })
+        );
+        // Nor do errors written as records
+        let c = Converter {
+            errors_as_json: true,
+            max_records: Some(1),
+            ..Default::default()
+        };
+        assert_matches!(
+            c.run(&b"\x01\x81\xc0\x01"[..], std::io::sink()),
+            Err(Mp2JsonError::RecordErrors(1))
+        );
+    }
+
+    #[test]
+    fn test_array_streams() {
+        // Gives the input a byte at a time, checking that each record is
+        // already in the output by the time the next one is read
+        struct Checked {
+            input: Vec<u8>,
+            at: usize,
+            output: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+        }
+        impl Read for Checked {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let Some(&byte) = self.input.get(self.at) else {
+                    return Ok(0);
+                };
+                let written = String::from_utf8(self.output.lock().unwrap().clone()).unwrap();
+                let expected = ["", "[\n1", "[\n1,\n2"][self.at];
+                assert_eq!(written, expected, "before reading byte {}", self.at);
+                self.at += 1;
+                buf[0] = byte;
+                Ok(1)
+            }
+        }
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let c = Converter {
+            array: true,
+            ..Default::default()
+        };
+        let input = Checked {
+            input: b"\x01\x02\x03".to_vec(),
+            at: 0,
+            output: output.clone(),
+        };
+        c.run(input, Shared(output.clone())).unwrap();
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            "[\n1,\n2,\n3\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_group_by() {
+        let c = Converter {
+            group_by: Some("type".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"type":"a","n":1} {"type":"b","n":2} {"type":"a","n":3} {"n":4}
+        let input = b"\x82\xa4type\xa1a\xa1n\x01\x82\xa4type\xa1b\xa1n\x02\
+                      \x82\xa4type\xa1a\xa1n\x03\x81\xa1n\x04";
+        c.run(Cursor::new(input), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"a":[{"type":"a","n":1},{"type":"a","n":3}],"#,
+                r#""b":[{"type":"b","n":2}],"__ungrouped__":[{"n":4}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_csv_output() {
+        let c = Converter {
+            format: OutputFormat::Csv,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"id":1,"name":"a"} {"id":2,"name":"b"}
+        let input = b"\x82\xa2id\x01\xa4name\xa1a\x82\xa2id\x02\xa4name\xa1b";
+        c.run(Cursor::new(input), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "id,name\n1,a\n2,b\n");
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let c = Converter {
+            max_output_bytes: Some(3),
+            eof_marker: Some(JsonValue::Null),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(Cursor::new(b"\xa5hello\xa5world"), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "\"hello\"\n");
+    }
+
+    #[test]
+    fn test_inspect_one() {
+        // {"a":[1,{"b":2}]}, then another record
+        let mut input = Cursor::new(b"\x81\xa1a\x92\x01\x81\xa1b\x02\xc0");
+        let report = inspect_one(&mut input, &ConvertOptions::default()).unwrap();
+        assert_eq!(
+            report.dump(),
+            r#"{"length":9,"type":"fixmap","depth":3,"value":{"a":[1,{"b":2}]}}"#
+        );
+        assert_eq!(input.position(), 9);
+        let report = inspect_one(&mut input, &ConvertOptions::default()).unwrap();
+        assert_eq!(
+            report.dump(),
+            r#"{"length":1,"type":"nil","depth":0,"value":null}"#
+        );
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        let convert = |binary_encoding| {
+            let options = ConvertOptions {
+                binary_encoding,
+                ..Default::default()
+            };
+            read_and_convert_one(&mut Cursor::new(b"\xc4\x02\xe9\x41"), &options)
+                .unwrap()
+                .dump()
+        };
+        assert_eq!(
+            convert(BinaryEncoding::Latin1),
+            "{\"encoding\":\"latin1\",\"value\":\"\u{e9}A\"}"
+        );
+        assert_eq!(
+            convert(BinaryEncoding::Hex),
+            r#"{"encoding":"hex","value":"e941"}"#
+        );
+        assert_eq!(
+            convert(BinaryEncoding::Base64),
+            r#"{"encoding":"base64","value":"6UE="}"#
+        );
+    }
+
+    #[test]
+    fn test_binary_threshold() {
+        let options = ConvertOptions {
+            binary_threshold: 16,
+            ..Default::default()
+        };
+        let mut small = b"\xc4\x03".to_vec();
+        small.extend_from_slice(&[0x00, 0xab, 0xff]);
+        let mut large = b"\xc4\x64".to_vec();
+        large.extend_from_slice(&[0xff; 100]);
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(&small), &options)
+                .unwrap()
+                .dump(),
+            r#"{"encoding":"hex","value":"00abff"}"#
+        );
+        let large = read_and_convert_one(&mut Cursor::new(&large), &options).unwrap();
+        assert_eq!(large["encoding"], "base64");
+        assert_eq!(large["value"].as_str().unwrap().len(), 136);
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(&small), &ConvertOptions::default())
+                .unwrap()
+                .dump(),
+            r#"{"encoding":"base64","value":"AKv/"}"#
+        );
+    }
+
+    #[test]
+    fn test_preserve_number_type() {
+        let options = ConvertOptions {
+            preserve_number_type: true,
+            ..Default::default()
+        };
+        let float = b"\xcb\x3f\xf0\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(float), &ConvertOptions::default()).unwrap(),
+            read_and_convert_one(&mut Cursor::new(b"\x01"), &ConvertOptions::default()).unwrap(),
+        );
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(float), &options)
+                .unwrap()
+                .dump(),
+            r#"{"$float":1}"#
+        );
+        assert_eq!(
+            read_and_convert_one(&mut Cursor::new(b"\x01"), &options)
+                .unwrap()
+                .dump(),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_jobs_output_matches_single_threaded() {
+        let run = |jobs: usize, input: &[u8]| {
+            let c = Converter {
+                jobs,
+                annotate_types: true,
+                include_offsets: true,
+                eof_marker: Some(JsonValue::Null),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let result = c.run(Cursor::new(input.to_vec()), &mut output);
+            (
+                String::from_utf8(output).unwrap(),
+                result.map_err(|e| e.to_string()),
+            )
+        };
+        let mut input = Vec::new();
+        for i in 0..500u32 {
+            rmpv::encode::write_value(
+                &mut input,
+                &rmpv::Value::Map(vec![
+                    (i.into(), rmpv::Value::Binary(vec![i as u8; 3])),
+                    (
+                        "deep".into(),
+                        rmpv::Value::Array(vec![rmpv::Value::Array(vec![i.into()])]),
+                    ),
+                ]),
+            )
+            .unwrap();
+            input.push(0xa1);
+            input.push(b'a' + (i % 26) as u8);
+        }
+        for tail in [&b""[..], b"\x92\x01", b"\xa3ab", b"\x81\x01\x02\x03"] {
+            let mut input = input.clone();
+            input.extend_from_slice(tail);
+            let expected = run(1, &input);
+            assert_eq!(run(4, &input), expected);
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = tempfile::NamedTempFile::new().unwrap();
+        let c = Converter {
+            summary: Some(Box::new(summary.reopen().unwrap())),
+            projection: Some(Projection::Keys),
+            skip_non_objects: true,
+            errors_as_json: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"a":1} 1 {1:2} {"b":2}
+        let input = b"\x81\xa1a\x01\x01\x81\x01\x02\x81\xa1b\x02";
+        assert_matches!(
+            c.run(Cursor::new(input), &mut output),
+            Err(Mp2JsonError::RecordErrors(1))
+        );
+        let summary = std::fs::read_to_string(summary.path()).unwrap();
+        assert!(
+            summary.starts_with("converted 2 records, 2 skipped, 12 B in, "),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with("s\n"), "{}", summary);
+    }
+
+    #[test]
+    fn test_schema() {
+        let run = |schema_continue: bool| {
+            let c = Converter {
+                schema: Some(Schema::parse(r#"{"type": "object", "required": ["id"]}"#).unwrap()),
+                schema_continue,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            // {"id":1} {"name":"a"} {"id":2} []
+            let input = b"\x81\xa2id\x01\x81\xa4name\xa1a\x81\xa2id\x02\x90";
+            let result = c.run(Cursor::new(input), &mut output);
+            (String::from_utf8(output).unwrap(), result)
+        };
+        let (output, result) = run(false);
+        assert_eq!(output, "{\"id\":1}\n");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "record 1 does not match the schema at $: missing required property \"id\""
+        );
+        let (output, result) = run(true);
+        assert_eq!(output, "{\"id\":1}\n{\"name\":\"a\"}\n{\"id\":2}\n[]\n");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "2 records do not match the schema\n  \
+             record 1 at $: missing required property \"id\"\n  \
+             record 3 at $: expected object, found []"
+        );
+    }
+
+    #[test]
+    fn test_homogeneous() {
+        // [1,2], then [1,"two"], then {"a":[nil,{}]}
+        let input = b"\x92\x01\x02\x92\x01\xa3two\x81\xa1a\x92\xc0\x80";
+        for jobs in [1, 2] {
+            let warnings = tempfile::NamedTempFile::new().unwrap();
+            let c = Converter {
+                check_homogeneous: Some(Box::new(warnings.reopen().unwrap())),
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            assert_eq!(output, b"[1,2]\n[1,\"two\"]\n{\"a\":[null,{}]}\n");
+            assert_eq!(
+                std::fs::read_to_string(warnings.path()).unwrap(),
+                "warning: record 1 has values of mixed types at $: number, string\n\
+                 warning: record 2 has values of mixed types at $.a: null, object\n"
+            );
+            let c = Converter {
+                strict_homogeneous: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(input), &mut output),
+                Err(Mp2JsonError::MixedTypes { index: 1, path, types })
+                    if path == "$" && types == "number, string"
+            );
+            assert_eq!(output, b"[1,2]\n");
+        }
+    }
+
+    #[test]
+    fn test_check_canonical() {
+        // 1, then 1 as a uint64, then {"a":"b"} with a str8 key
+        let input = b"\x01\xcf\x00\x00\x00\x00\x00\x00\x00\x01\x81\xd9\x01a\xa1b";
+        for jobs in [1, 2] {
+            let warnings = tempfile::NamedTempFile::new().unwrap();
+            let c = Converter {
+                check_canonical: Some(Box::new(warnings.reopen().unwrap())),
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            c.run(Cursor::new(input), &mut output).unwrap();
+            assert_eq!(output, b"1\n1\n{\"a\":\"b\"}\n");
+            assert_eq!(
+                std::fs::read_to_string(warnings.path()).unwrap(),
+                "warning: non-canonical msgpack at byte 1: uint64 for 1 could be positive_fixint\n\
+                 warning: non-canonical msgpack at byte 11: str8 of length 1 could be fixstr\n"
+            );
+            let c = Converter {
+                strict_canonical: true,
+                jobs,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            assert_matches!(
+                c.run(Cursor::new(input), &mut output),
+                Err(Mp2JsonError::NonCanonical { offset: 1, .. })
+            );
+            assert_eq!(output, b"1\n");
+        }
+    }
+
+    #[test]
+    fn test_size_report() {
+        let report = tempfile::NamedTempFile::new().unwrap();
+        let c = Converter {
+            size_report: Some(Box::new(report.reopen().unwrap())),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"a":[1,2]} {"a":[]}
+        c.run(
+            Cursor::new(b"\x81\xa1a\x92\x01\x02\x81\xa1a\x90"),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"a\":[1,2]}\n{\"a\":[]}\n"
+        );
+        let report = json::parse(&std::fs::read_to_string(report.path()).unwrap()).unwrap();
+        assert_eq!(
+            report.dump(),
+            concat!(
+                r#"{"$":{"count":2,"bytes":19,"length":2},"#,
+                r#""$.a":{"count":2,"bytes":7,"length":2},"#,
+                r#""$.a[*]":{"count":2,"bytes":2}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let map = tempfile::NamedTempFile::new().unwrap();
+        let c = Converter {
+            source_map: Some(Box::new(map.reopen().unwrap())),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        // {"a":1} "xy"
+        c.run(Cursor::new(b"\x81\xa1a\x01\xa2xy"), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"a\":1}\n\"xy\"\n");
+        assert_eq!(
+            std::fs::read_to_string(map.path()).unwrap(),
+            concat!(
+                r#"[{"index":0,"out":[0,8],"in":[0,4]},"#,
+                r#"{"index":1,"out":[8,13],"in":[4,7]}]"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_hashes() {
+        let input_digest = tempfile::NamedTempFile::new().unwrap();
+        let output_digest = tempfile::NamedTempFile::new().unwrap();
+        let c = Converter {
+            hash_input: Some(Box::new(input_digest.reopen().unwrap())),
+            hash_output: Some(Box::new(output_digest.reopen().unwrap())),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        c.run(Cursor::new(b"abc"), &mut output).unwrap();
+        // Each byte of "abc" is a positive fixint
+        assert_eq!(output, b"97\n98\n99\n");
+        assert_eq!(
+            std::fs::read_to_string(input_digest.path()).unwrap(),
+            "SHA256 (input) = ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(output_digest.path()).unwrap(),
+            "SHA256 (output) = e300ee88d2be76254019a764330354f828e31f8312132384f7c792f9941b7ccc\n"
+        );
+    }
+}
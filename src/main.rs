@@ -1,202 +1,1472 @@
-use std::io::{Read, Write};
+use std::ffi::OsString;
+use std::io::{Seek, SeekFrom, Write};
 
-use clap::Parser;
-use json::object::Object as JsonObject;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser};
 use json::JsonValue;
-use rmpv::Value as MpValue;
-use thiserror::Error;
-
-#[derive(Debug, Error)]
-pub enum Mp2JsonError {
-    #[error("msgpack string was not UTF-8")]
-    InvalidString,
-    #[error("msgpack integer was not encodable in 64 bits")]
-    InvalidInteger(rmpv::Integer),
-    #[error("Map key is not a string")]
-    MapKeyNotString,
-    #[error("msgpack decode error: {0}")]
-    RmpDecode(#[from] rmpv::decode::Error),
-    #[error("error writing")]
-    Output(#[source] std::io::Error),
-}
-
-fn convert(r: MpValue) -> Result<JsonValue, Mp2JsonError> {
-    let jv = match r {
-        MpValue::Nil => JsonValue::Null,
-        MpValue::Boolean(b) => b.into(),
-        MpValue::Integer(i) => {
-            if let Some(i) = i.as_i64() {
-                JsonValue::from(i)
-            } else if let Some(i) = i.as_u64() {
-                JsonValue::from(i)
-            } else if let Some(i) = i.as_f64() {
-                JsonValue::from(i)
-            } else {
-                return Err(Mp2JsonError::InvalidInteger(i));
+use mp2json::{
+    Align, BinaryEncoding, BinaryKeys, Coercion, ConvertOptions, Converter, Diagnostics,
+    DuplicateKeys, ExtDecoder, FloatNotation, InputFormat, KeyCase, LargeIntegers, MaxDepthAction,
+    Mp2JsonError, NonFinite, OnBrokenPipe, OrderedMaps, OutputEncoding, OutputFormat, Path,
+    Projection, Redaction, ReverseConverter, Route, Schema, SortOrder, TimestampScope,
+    TimestampUnit, TruncatedStrings,
+};
+
+#[cfg(feature = "clipboard")]
+mod clipboard;
+mod dir;
+#[cfg(unix)]
+mod fd;
+mod interrupt;
+mod watch;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, args_override_self = true)]
+struct Args {
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Read options from a JSON object keyed by long option name; options on the command line take precedence"
+    )]
+    config: Option<std::path::PathBuf>,
+    #[clap(short = 'p', long)]
+    pretty: bool,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Pretty-print, only breaking lines when a value would not fit in N columns"
+    )]
+    pretty_width: Option<usize>,
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with = "canonical",
+        help = "Pretty-print, as with --pretty unless --pretty-width is given, showing containers more than N levels deep only as a summary like {… 5 keys}; the output is for reading, and no longer JSON"
+    )]
+    collapse_below: Option<usize>,
+    #[clap(
+        long,
+        conflicts_with = "canonical",
+        help = "Pretty-print, as with --pretty unless --pretty-width is given, padding the keys of each object broken across lines so that its values line up"
+    )]
+    align: bool,
+    #[clap(
+        long,
+        requires = "align",
+        help = "With --align, also right-align the numbers among each object's values"
+    )]
+    align_numbers: bool,
+    #[clap(long, help = "Write the keys of every object in sorted order")]
+    sort_keys: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_name = "ORDER",
+        num_args = 0..=1,
+        default_missing_value = "asc",
+        conflicts_with_all = ["sort_keys", "canonical"],
+        help = "Write the entries of every object ordered by their values, breaking ties by key: null, then booleans, numbers, strings, arrays and objects, each in their natural order"
+    )]
+    sort_by_value: Option<SortOrder>,
+    #[clap(
+        long,
+        conflicts_with_all = ["pretty", "pretty_width", "float_notation", "deterministic_floats"],
+        help = "Write canonical JSON (RFC 8785) with sorted keys and exact number formatting, for hashing or signing"
+    )]
+    canonical: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to write numbers which aren't integers"
+    )]
+    float_notation: FloatNotation,
+    #[clap(
+        long,
+        conflicts_with = "float_notation",
+        help = "Write numbers which aren't integers in a fully specified form, the shortest digits which round-trip laid out as in ECMAScript, so that the output is the same on every platform; the same as --float-notation=ecmascript"
+    )]
+    deterministic_floats: bool,
+    #[clap(short = 'U', long, help = "Flush input after each message")]
+    unbuffered: bool,
+    #[clap(
+        long,
+        conflicts_with = "unbuffered",
+        help = "Buffer output, but flush it after each message"
+    )]
+    line_buffered: bool,
+    #[clap(
+        short,
+        long,
+        default_value = "-",
+        help = "Input path of file to convert from msgpack to JSON (or - for stdin)"
+    )]
+    input: clio::Input,
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["input", "hex", "base64", "watch"],
+        help = "Convert the files in DIR, in sorted order, as one concatenated input"
+    )]
+    input_dir: Option<std::path::PathBuf>,
+    #[cfg(unix)]
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(i32).range(3..),
+        conflicts_with_all = ["input", "input_dir", "hex", "base64", "watch"],
+        help = "Read the input from file descriptor N, already open, instead of from --input; mp2json takes ownership of it and closes it when done"
+    )]
+    fd: Option<i32>,
+    #[cfg(unix)]
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(i32).range(3..),
+        conflicts_with = "watch",
+        help = "Write the output to file descriptor N, already open, instead of to standard output; mp2json takes ownership of it and closes it when done"
+    )]
+    output_fd: Option<i32>,
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        requires = "input_dir",
+        help = "With --input-dir, only read files whose names match PATTERN, where * matches any characters and ? any one"
+    )]
+    glob: Option<String>,
+    #[clap(
+        long,
+        requires = "input_dir",
+        help = "With --input-dir, also read files in its subdirectories"
+    )]
+    recursive: bool,
+    #[clap(
+        long,
+        requires = "input_dir",
+        conflicts_with = "reverse",
+        help = "With --input-dir, wrap each record as {\"file\":...,\"value\":...} naming the file it came from"
+    )]
+    include_filename: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["hex", "base64"],
+        help = "Convert the input file again each time it changes, until interrupted; each run is a full reconversion"
+    )]
+    watch: bool,
+    #[clap(
+        long,
+        value_parser = parse_hex,
+        conflicts_with_all = ["input", "base64"],
+        help = "Convert these hex-encoded bytes instead of reading an input"
+    )]
+    hex: Option<InlineInput>,
+    #[clap(
+        long,
+        value_parser = parse_base64,
+        conflicts_with = "input",
+        help = "Convert these base64-encoded bytes instead of reading an input"
+    )]
+    base64: Option<InlineInput>,
+    #[cfg(feature = "clipboard")]
+    #[clap(
+        long,
+        conflicts_with_all = ["input", "input_dir", "hex", "base64"],
+        help = "Convert the contents of the system clipboard instead of reading an input"
+    )]
+    clipboard: bool,
+    #[cfg(feature = "clipboard")]
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How the clipboard text encodes the input"
+    )]
+    clipboard_format: clipboard::ClipboardFormat,
+    #[clap(
+        long,
+        help = "Convert JSON values, in any layout, from the input to concatenated msgpack instead"
+    )]
+    reverse: bool,
+    #[clap(
+        long,
+        requires = "reverse",
+        help = "In reverse mode, write floats as float32 whenever that is exact, so every value takes its smallest encoding"
+    )]
+    reverse_canonical: bool,
+    #[clap(
+        long,
+        requires = "reverse",
+        conflicts_with = "reverse_canonical",
+        help = "In reverse mode, write every number as a float64, rather than integral ones as integers"
+    )]
+    reverse_all_floats: bool,
+    #[clap(
+        long,
+        alias = "from",
+        value_enum,
+        default_value_t,
+        help = "Format of the input"
+    )]
+    input_format: InputFormat,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Character encoding of the output"
+    )]
+    output_encoding: OutputEncoding,
+    #[clap(
+        long,
+        alias = "to",
+        value_enum,
+        default_value_t,
+        help = "Format of the output"
+    )]
+    format: OutputFormat,
+    #[clap(
+        long,
+        help = "With --format=csv, reject nested values instead of writing them as JSON"
+    )]
+    strict_csv: bool,
+    #[clap(long, help = "With --format=env, begin each assignment with export")]
+    export: bool,
+    #[clap(
+        long,
+        help = "With --format=env, write nested values as a variable per leaf, named like PARENT_CHILD_0, rather than failing"
+    )]
+    flatten: bool,
+    #[clap(
+        long,
+        help = "With --format=env, uppercase variable names and replace characters a shell doesn't allow in them with _"
+    )]
+    sanitize_keys: bool,
+    #[clap(
+        long,
+        num_args = 0..=1,
+        default_missing_value = r#"{"__eof__":true}"#,
+        value_parser = parse_json,
+        help = "Write this JSON value as a final record when the input ends cleanly; ignored with --format=csv or env"
+    )]
+    eof_marker: Option<JsonValue>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to handle integers beyond ±2^53, which doubles cannot represent exactly"
+    )]
+    large_integers: LargeIntegers,
+    #[clap(
+        long,
+        conflicts_with_all = ["large_integers", "strict"],
+        help = "Write integers beyond ±2^53 as strings, so that JavaScript and other double-based parsers read them exactly; short for --large-integers=string"
+    )]
+    json_safe_numbers: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to handle NaN and infinite floats"
+    )]
+    non_finite: NonFinite,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to handle maps which repeat a key"
+    )]
+    duplicate_keys: DuplicateKeys,
+    #[clap(
+        long,
+        help = "Reject all lossy conversions: equivalent to --large-integers=error --non-finite=error --duplicate-keys=error, or with --reverse, reject integers beyond 64 bits and numbers a float64 cannot hold exactly"
+    )]
+    strict: bool,
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "top-level",
+        help = "Render integers as RFC3339 timestamps, either only at the top level or everywhere; integers outside the years 0000 to 9999 are left as numbers"
+    )]
+    int_as_timestamp: Option<TimestampScope>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Unit of integers rendered by --int-as-timestamp"
+    )]
+    timestamp_unit: TimestampUnit,
+    #[clap(
+        long,
+        help = "Wrap floats as {\"$float\":...} so that they stay distinguishable from integers"
+    )]
+    preserve_number_type: bool,
+    #[clap(
+        long,
+        value_name = "KIND=TYPE_CODE",
+        help = "Write ext values of TYPE_CODE whose payload is a big-endian f64 or f32 as numbers, or a big-endian BID decimal128 as a decimal string"
+    )]
+    decode_ext: Vec<ExtDecoder>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to write the bytes of binary values, named in the \"encoding\" field of the object wrapping them"
+    )]
+    binary_encoding: BinaryEncoding,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Write binary values of at most N bytes as hex rather than in the --binary-encoding"
+    )]
+    binary_threshold: usize,
+    #[clap(
+        long,
+        help = "Write binary values which hold exactly one msgpack value as that value, converted; others are still written as bytes"
+    )]
+    decode_nested_msgpack: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["binary_encoding", "binary_threshold"],
+        help = "Fail on binary and ext values instead of writing them wrapped, unless they are converted otherwise; with --errors-as-json, the records holding them are written as errors"
+    )]
+    no_binary: bool,
+    #[clap(
+        long,
+        help = "Write strings which hold a JSON object or array as that value, parsing any such strings within it too, within --max-depth"
+    )]
+    parse_json_strings: bool,
+    #[clap(
+        long,
+        help = "Write nil as {\"__nil__\":true} instead of null, so it can be told apart from a null written otherwise; with --reverse, write such objects as nil"
+    )]
+    typed_nulls: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "Write binary map keys as base64 or hex strings instead of rejecting them; keys which collide follow --duplicate-keys"
+    )]
+    binary_keys: Option<BinaryKeys>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Rewrite every map key to this case; keys which then collide follow --duplicate-keys"
+    )]
+    key_case: Option<KeyCase>,
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "pairs",
+        conflicts_with = "sort_keys",
+        help = "Write maps as arrays of their entries in their original order, as [key, value] pairs or single-key objects, keeping any duplicate keys"
+    )]
+    ordered_maps: Option<OrderedMaps>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Limit arrays and maps to N levels of nesting, handling deeper ones per --on-max-depth"
+    )]
+    max_depth: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "What to do with arrays and maps nested beyond --max-depth"
+    )]
+    on_max_depth: MaxDepthAction,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Shorten strings longer than N characters to N, ending them with \"…\""
+    )]
+    max_string_length: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        requires = "max_string_length",
+        help = "How to mark strings shortened by --max-string-length"
+    )]
+    truncated_strings: TruncatedStrings,
+    #[clap(
+        long,
+        requires = "max_string_length",
+        help = "Shorten map keys per --max-string-length too"
+    )]
+    truncate_keys: bool,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Stop after the record which takes the output to N bytes or more"
+    )]
+    max_output_bytes: Option<u64>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Fail if fewer than N records are written once the whole input is converted, as when it was cut off upstream; records dropped by --sample and the like don't count, and --group-by and --count-by write one per group"
+    )]
+    min_records: Option<u64>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Fail if more than N records are written once the whole input is converted, counted as for --min-records"
+    )]
+    max_records: Option<u64>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Write one object mapping the value at PATH to arrays of the records which have it (holds the whole input in memory)"
+    )]
+    group_by: Option<Path>,
+    #[clap(
+        long,
+        conflicts_with = "group_by",
+        help = "Write one object deep-merging all records, which must be objects, with later ones taking precedence"
+    )]
+    merge: bool,
+    #[clap(
+        long,
+        requires = "merge",
+        help = "With --merge, append arrays instead of replacing them"
+    )]
+    merge_arrays: bool,
+    #[clap(
+        long,
+        visible_alias = "reservoir-sample",
+        value_name = "N",
+        conflicts_with_all = ["group_by", "count_by", "merge"],
+        help = "Write a uniformly random sample of N records, in their input order, once the input is finished"
+    )]
+    sample: Option<usize>,
+    #[clap(
+        long,
+        requires = "sample",
+        help = "Seed the random choice of --sample, to make it reproducible"
+    )]
+    seed: Option<u64>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["group_by", "merge", "sample"],
+        help = "Write one object counting the records with each value at PATH, counting those without one under \"__missing__\""
+    )]
+    count_by: Option<Path>,
+    #[clap(
+        long,
+        value_name = "PATH=TYPE",
+        value_delimiter = ',',
+        help = "Convert the value at PATH in each record to a string, number, or bool, failing if it cannot be"
+    )]
+    coerce: Vec<Coercion>,
+    #[clap(
+        long,
+        help = "Replace every array with exactly one element by that element, at any depth, before --coerce and the other changes to each record; [x] and x can then no longer be told apart"
+    )]
+    unwrap_singletons: bool,
+    #[clap(
+        long,
+        value_name = "KEY",
+        value_delimiter = ',',
+        help = "Remove the entries with KEY from every object in each record, at any depth; the objects written for binary values are left whole"
+    )]
+    drop_keys: Vec<String>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        value_delimiter = ',',
+        help = "Remove the value at PATH from each record, if there is one"
+    )]
+    drop_paths: Vec<Path>,
+    #[clap(
+        long,
+        value_name = "PATH[=MODE]",
+        value_delimiter = ',',
+        help = "Replace the value at PATH in each record, keeping its key: with \"***\" for mask (the default), a salted SHA-256 of its JSON text for hash, or its length in characters or members for length"
+    )]
+    redact: Vec<Redaction>,
+    #[clap(
+        long,
+        value_name = "TEXT",
+        requires = "redact",
+        help = "Salt the hashes of --redact PATH=hash with TEXT, so that they match across runs; by default the salt is random for each run"
+    )]
+    redact_salt: Option<String>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["group_by", "count_by", "merge", "sample"],
+        help = "Write each record to the file named by --route-template for its value at PATH, instead of to stdout"
+    )]
+    route: Option<Path>,
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        default_value = "{value}.json",
+        value_parser = parse_route_template,
+        help = "File name for --route, in which {value} is replaced by the record's value, or __unrouted__ if it has none"
+    )]
+    route_template: String,
+    #[clap(
+        long,
+        help = "Afterwards, write to stderr the count and total size of the values at each JSONPath of the records"
+    )]
+    size_report: bool,
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["reverse", "group_by", "count_by", "merge", "sample", "route"],
+        help = "Afterwards, write to FILE a JSON array mapping the byte range of each record in the output to its byte range in the input"
+    )]
+    source_map: Option<std::path::PathBuf>,
+    #[clap(
+        long,
+        help = "Afterwards, write to stderr a line giving the records converted and skipped, the input size, and the time taken"
+    )]
+    summary: bool,
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with = "reverse",
+        help = "Also write the output to this file, in full even if standard output is closed early"
+    )]
+    tee: Option<std::path::PathBuf>,
+    #[clap(
+        long,
+        value_name = "ALGORITHM",
+        value_parser = ["sha256"],
+        num_args = 0..=1,
+        default_missing_value = "sha256",
+        conflicts_with = "reverse",
+        help = "Afterwards, write to stderr the SHA-256 digest of all the input read"
+    )]
+    hash_input: Option<String>,
+    #[clap(
+        long,
+        value_name = "ALGORITHM",
+        value_parser = ["sha256"],
+        num_args = 0..=1,
+        default_missing_value = "sha256",
+        conflicts_with = "reverse",
+        help = "Afterwards, write to stderr the SHA-256 digest of all the output"
+    )]
+    hash_output: Option<String>,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        help = "Convert msgpack records on N threads at once, still writing them in input order"
+    )]
+    jobs: usize,
+    #[clap(
+        long,
+        visible_alias = "assume-array",
+        conflicts_with_all = ["group_by", "count_by", "merge", "route"],
+        help = "Write all records as the elements of one JSON array, for inputs whose values are the elements of a headerless array"
+    )]
+    array: bool,
+    #[clap(
+        long,
+        value_name = "TEXT",
+        help = "Write TEXT before anything else in the output, as with --prefix '{\"records\":' --array --suffix '}'"
+    )]
+    prefix: Option<String>,
+    #[clap(
+        long,
+        value_name = "TEXT",
+        help = "Write TEXT after everything else in the output, if the conversion finishes without an error"
+    )]
+    suffix: Option<String>,
+    #[clap(
+        long,
+        value_name = "TEXT",
+        help = "Write TEXT at the start of each JSON record, as with --line-prefix 'data: ' for Server-Sent Events"
+    )]
+    line_prefix: Option<String>,
+    #[clap(
+        long,
+        conflicts_with = "array",
+        help = "Begin each record written with its number, counting from 1, and a tab, for cross-referencing; the output is then no longer JSON; types, errors and the eof marker written alongside the records are not numbered"
+    )]
+    line_numbers: bool,
+    #[clap(
+        long,
+        help = "Wrap each top-level value as {\"type\":...,\"value\":...} naming its msgpack encoding"
+    )]
+    annotate_types: bool,
+    #[clap(
+        long,
+        help = "Wrap each top-level value as {\"offset\":...,\"length\":...,\"value\":...} giving its byte range in the input"
+    )]
+    include_offsets: bool,
+    #[clap(
+        long,
+        help = "Write an array of the keys of each record, which must be an object"
+    )]
+    keys_only: bool,
+    #[clap(
+        long,
+        conflicts_with = "keys_only",
+        help = "Write an array of the values of each record, which must be an object"
+    )]
+    values_only: bool,
+    #[clap(
+        long,
+        help = "With --keys-only or --values-only, skip records which aren't objects instead of failing"
+    )]
+    skip_non_objects: bool,
+    #[clap(
+        long,
+        help = "Stop without an error if the input ends partway through a record, as from a dump which was cut off"
+    )]
+    allow_truncated: bool,
+    #[clap(
+        long,
+        value_name = "BYTE",
+        num_args = 0..=1,
+        default_missing_value = "0xc1",
+        value_parser = parse_frame_marker,
+        conflicts_with_all = ["jobs", "input_format"],
+        help = "Expect every msgpack record to be followed by the byte 0xc1, which msgpack never uses, failing where one isn't"
+    )]
+    frame_marker: Option<u8>,
+    #[clap(
+        long,
+        conflicts_with = "input_format",
+        help = "If the input doesn't begin with a msgpack value, as when it was captured partway through a record, skip ahead a byte at a time until one decodes"
+    )]
+    resync: bool,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 4096,
+        requires = "resync",
+        help = "With --resync, give up if no value begins within the first BYTES bytes"
+    )]
+    resync_limit: u64,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "Read only the first BYTES bytes of the input, converting the records wholly within them"
+    )]
+    input_limit: Option<u64>,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        help = "Skip the first BYTES bytes of the input, as reported by --resumable; offsets still count from the start"
+    )]
+    skip_bytes: u64,
+    #[clap(
+        long,
+        help = "On a record with invalid UTF-8, print \"resume-offset: N\" to stderr and exit with status 3, so that once fixed the input can be converted again with --skip-bytes N"
+    )]
+    resumable: bool,
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["reverse", "group_by", "count_by", "merge", "sample", "array"],
+        help = "Keep FILE up to date with the input offset just past the last record written, for --resume"
+    )]
+    checkpoint: Option<std::path::PathBuf>,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 1000,
+        requires = "checkpoint",
+        help = "Save the --checkpoint after every N records, as well as when conversion stops"
+    )]
+    checkpoint_interval: u64,
+    #[clap(
+        long,
+        requires = "checkpoint",
+        conflicts_with = "skip_bytes",
+        help = "Start from the offset saved in the --checkpoint file, if it exists"
+    )]
+    resume: bool,
+    #[clap(
+        long,
+        help = "Write each record which can't be converted as {\"__error__\":...,\"offset\":...} and carry on, exiting with an error at the end"
+    )]
+    errors_as_json: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["reverse", "group_by", "count_by", "merge", "sample", "route", "keys_only", "values_only"],
+        help = "Follow each record with one of the same shape giving the msgpack type of each value, such as \"str\" or \"bin\""
+    )]
+    types_sidecar: bool,
+    #[clap(
+        long,
+        help = "Write plain records straight from msgpack without building each JSON value first; the output is the same, and options which transform records fall back to the normal path"
+    )]
+    fast: bool,
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["group_by", "count_by", "merge", "sample"],
+        help = "Write only the value at PATH (like a.b[0]) of each record, or null where there is none"
+    )]
+    select: Option<Path>,
+    #[clap(
+        short = 'r',
+        long,
+        conflicts_with_all = ["array", "canonical"],
+        help = "Write string records as their bare text, without quotes, like jq -r"
+    )]
+    raw_output: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "What to do when the reader of the output goes away, as when piped into head"
+    )]
+    on_broken_pipe: OnBrokenPipe,
+    #[clap(
+        long,
+        value_name = "FILE",
+        value_parser = parse_schema,
+        conflicts_with = "reverse",
+        help = "Fail on the first record not matching the JSON Schema in FILE, giving its index; supports type, properties, required, items, enum, const, numeric and length bounds, the combinators and local $refs"
+    )]
+    assert_schema: Option<Schema>,
+    #[clap(
+        long = "continue",
+        requires = "assert_schema",
+        help = "With --assert-schema, write every record and list all those not matching at the end"
+    )]
+    schema_continue: bool,
+    #[clap(
+        long,
+        conflicts_with = "reverse",
+        help = "Warn on stderr of each msgpack value not in its smallest encoding, as canonical msgpack requires, giving its offset"
+    )]
+    check_canonical: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["reverse", "input_format"],
+        help = "Write a pretty-printed report on just the first record, giving its length in bytes, its msgpack type and its nesting depth as well as its value"
+    )]
+    inspect_first: bool,
+    #[clap(
+        long,
+        conflicts_with = "reverse",
+        help = "Fail on the first msgpack value not in its smallest encoding"
+    )]
+    strict_canonical: bool,
+    #[clap(
+        long,
+        alias = "map-value-type-check",
+        conflicts_with = "reverse",
+        help = "Warn on stderr of each array or object whose members aren't all of one JSON type, giving its path, or with --strict, fail on the first; binary values count as their own type"
+    )]
+    homogeneous_check: bool,
+}
+
+/// Literal input bytes given on the command line
+#[derive(Debug, Clone)]
+struct InlineInput(Vec<u8>);
+
+fn parse_hex(s: &str) -> Result<InlineInput, String> {
+    let digits = s
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| format!("invalid hex digit {:?}", c))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".to_string());
+    }
+    Ok(InlineInput(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    ))
+}
+
+fn parse_base64(s: &str) -> Result<InlineInput, String> {
+    base64::decode(s.trim())
+        .map(InlineInput)
+        .map_err(|e| e.to_string())
+}
+
+fn parse_frame_marker(s: &str) -> Result<u8, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "0xc1" | "c1" => Ok(0xc1),
+        _ => Err("only 0xc1, which never begins a msgpack value, can mark frames".to_string()),
+    }
+}
+
+fn parse_route_template(s: &str) -> Result<String, String> {
+    if s.contains("{value}") {
+        Ok(s.to_string())
+    } else {
+        Err("the template must contain {value}".to_string())
+    }
+}
+
+fn parse_schema(s: &str) -> Result<Schema, String> {
+    let text = std::fs::read_to_string(s).map_err(|e| e.to_string())?;
+    Schema::parse(&text)
+}
+
+fn parse_json(s: &str) -> Result<JsonValue, String> {
+    json::parse(s).map_err(|e| e.to_string())
+}
+
+/// Translate a JSON config file into the command-line arguments it stands
+/// for. `true` sets a flag, `false` and `null` leave it unset, arrays repeat
+/// an option, and objects are passed as JSON text.
+fn config_args(path: &std::path::Path) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+    let config = match json::parse(&text) {
+        Ok(JsonValue::Object(config)) => config,
+        Ok(_) => return Err(format!("config file {} is not an object", path.display())),
+        Err(e) => return Err(format!("invalid config file {}: {}", path.display(), e)),
+    };
+    let command = Args::command();
+    let mut args = Vec::new();
+    for (key, value) in config.iter() {
+        let name = key.replace('_', "-");
+        if name == "config" || !command.get_arguments().any(|a| a.get_long() == Some(&name)) {
+            eprintln!("mp2json: warning: ignoring unknown config key {:?}", key);
+            continue;
+        }
+        let values = match value {
+            JsonValue::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                JsonValue::Boolean(true) => args.push(format!("--{}", name).into()),
+                JsonValue::Boolean(false) | JsonValue::Null => {}
+                value => {
+                    let value = value.as_str().map_or_else(|| value.dump(), String::from);
+                    args.push(format!("--{}={}", name, value).into());
+                }
             }
         }
-        MpValue::F32(f) => f.into(),
-        MpValue::F64(f) => f.into(),
-        MpValue::String(s) => s
-            .into_str()
-            .map(|v| v.into())
-            .ok_or(Mp2JsonError::InvalidString)?,
-        MpValue::Binary(b) => {
-            let mut o = JsonObject::with_capacity(2);
-            o.insert("encoding", "base64".into());
-            o.insert("value", base64::encode(b).into());
-            JsonValue::Object(o)
+    }
+    Ok(args)
+}
+
+/// The options set on the command line `argv`, each with the arguments
+/// which set it, and the `--config` file given if any. The arguments are
+/// parsed without checking how the options relate to each other, since
+/// another layer of options may yet satisfy or override them.
+type Layer = (Vec<(clap::Id, Vec<OsString>)>, Option<std::path::PathBuf>);
+
+fn layer(command: &clap::Command, argv: Vec<OsString>) -> Result<Layer, clap::Error> {
+    // Anything but how the options relate is still an error in any layer
+    if let Err(e) = command.clone().try_get_matches_from(argv.clone()) {
+        if !matches!(
+            e.kind(),
+            ErrorKind::ArgumentConflict | ErrorKind::MissingRequiredArgument
+        ) {
+            return Err(e);
         }
-        MpValue::Array(v) => v
-            .into_iter()
-            .map(convert)
-            .collect::<Result<Vec<_>, _>>()?
-            .into(),
-        MpValue::Map(m) => m
-            .into_iter()
-            .map(|(k, v)| {
-                let (s, v) = if let rmpv::Value::String(s) = k {
-                    if let Some(s) = s.into_str() {
-                        (s, v)
-                    } else {
-                        return Err(Mp2JsonError::InvalidString);
+    }
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(argv)?;
+    let mut options = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let (Some(long), Some(ValueSource::CommandLine)) =
+            (arg.get_long(), matches.value_source(id))
+        else {
+            continue;
+        };
+        let mut args = Vec::new();
+        let occurrences = arg
+            .get_action()
+            .takes_values()
+            .then(|| matches.get_raw_occurrences(id))
+            .flatten();
+        match occurrences {
+            Some(occurrences) => {
+                for occurrence in occurrences {
+                    let mut values = occurrence.peekable();
+                    if values.peek().is_none() {
+                        args.push(format!("--{}", long).into());
                     }
-                } else {
-                    return Err(Mp2JsonError::MapKeyNotString);
-                };
-                let v = convert(v)?;
-                Ok((s, v))
-            })
-            .collect::<Result<JsonObject, _>>()?
-            .into(),
-        MpValue::Ext(type_code, bytes) => {
-            let mut o = JsonObject::with_capacity(3);
-            o.insert("type_code", type_code.into());
-            o.insert("encoding", "base64".into());
-            o.insert("value", base64::encode(bytes).into());
-            o.into()
+                    for value in values {
+                        let mut arg = OsString::from(format!("--{}=", long));
+                        arg.push(value);
+                        args.push(arg);
+                    }
+                }
+            }
+            None => args.push(format!("--{}", long).into()),
         }
-    };
-    Ok(jv)
+        options.push((arg.get_id().clone(), args));
+    }
+    let config = matches
+        .try_get_one::<std::path::PathBuf>("config")
+        .ok()
+        .flatten()
+        .cloned();
+    Ok((options, config))
 }
 
-fn read_and_convert_one<R: Read>(r: &mut R) -> Result<JsonValue, Mp2JsonError> {
-    let value = rmpv::decode::read_value(r)?;
-    convert(value)
+/// Whether either of two options declares that it conflicts with the other
+fn conflicting(command: &clap::Command, a: &clap::Id, b: &clap::Id) -> bool {
+    let find = |id: &clap::Id| command.get_arguments().find(|arg| arg.get_id() == id);
+    let (Some(a), Some(b)) = (find(a), find(b)) else {
+        return false;
+    };
+    command
+        .get_arg_conflicts_with(a)
+        .iter()
+        .any(|arg| arg.get_id() == b.get_id())
+        || command
+            .get_arg_conflicts_with(b)
+            .iter()
+            .any(|arg| arg.get_id() == a.get_id())
 }
 
-struct Converter {
-    buffered: bool,
-    pretty: bool,
+/// Parse the command line, merged over `MP2JSON_OPTS` and the contents of
+/// any `--config` file
+fn parse_args<I, T>(argv: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    parse_layered(argv, std::env::var_os("MP2JSON_OPTS"))
 }
 
-impl Converter {
-    fn run_inner<R: Read, W: Write>(self, mut input: R, mut output: W) -> Result<(), Mp2JsonError> {
-        loop {
-            match read_and_convert_one(&mut input) {
-                Ok(v) => {
-                    let write = if self.pretty {
-                        v.write_pretty(&mut output, 2)
-                    } else {
-                        v.write(&mut output)
-                    };
-                    match write.and_then(|_| output.write(&[0x0a])) {
-                        Ok(_) => {}
-                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
-                        Err(e) => return Err(Mp2JsonError::Output(e)),
-                    }
-                }
-                Err(Mp2JsonError::RmpDecode(rmpv::decode::Error::InvalidMarkerRead(e)))
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
-                {
-                    break
-                }
-                Err(e) => return Err(e),
-            }
+/// Parse the command line over the whitespace-separated options in `env`,
+/// over those in any `--config` file, over the defaults. An option set in
+/// a layer replaces it entirely in the layers below, as does any option it
+/// conflicts with; what's left is then checked as a whole.
+fn parse_layered<I, T>(argv: I, env: Option<OsString>) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+    let (program, cli) = argv.split_at(argv.len().min(1));
+    let command = Args::command();
+    let with_program = |args: Vec<OsString>| program.iter().cloned().chain(args).collect();
+    let (cli_options, cli_config) = layer(&command, argv.clone())?;
+    let env_args = env
+        .map(|env| {
+            env.to_string_lossy()
+                .split_whitespace()
+                .map(OsString::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    let (env_options, env_config) = layer(&command, with_program(env_args))?;
+    let config_options = match cli_config.or(env_config) {
+        Some(path) => {
+            let config = config_args(&path).map_err(|e| command.clone().error(ErrorKind::Io, e))?;
+            layer(&command, with_program(config))?.0
         }
-        Ok(())
+        None => Vec::new(),
+    };
+    let mut merged = program.to_vec();
+    let mut above: Vec<&clap::Id> = cli_options.iter().map(|(id, _)| id).collect();
+    for options in [&env_options, &config_options] {
+        let kept: Vec<_> = options
+            .iter()
+            .filter(|(id, _)| {
+                !above
+                    .iter()
+                    .any(|higher| *higher == id || conflicting(&command, higher, id))
+            })
+            .collect();
+        // Lower layers go first, so that the command line comes last
+        merged.splice(
+            program.len()..program.len(),
+            kept.iter().flat_map(|(_, args)| args.iter().cloned()),
+        );
+        above.extend(options.iter().map(|(id, _)| id));
     }
+    merged.extend(cli.iter().cloned());
+    Args::try_parse_from(merged)
+}
 
-    fn run<R: Read, W: Write>(self, input: R, output: W) -> Result<(), Mp2JsonError> {
-        if self.buffered {
-            let mut output = std::io::BufWriter::new(output);
-            self.run_inner(std::io::BufReader::new(input), &mut output)?;
-            output.flush().map_err(Mp2JsonError::Output)?;
-            Ok(())
+/// Take the input bytes given in place of `--input`, if any
+fn inline_input(args: &mut Args) -> Result<Option<Vec<u8>>, Mp2JsonError> {
+    #[cfg(feature = "clipboard")]
+    if args.clipboard {
+        return clipboard::read(&mut clipboard::SystemClipboard, args.clipboard_format)
+            .map(Some)
+            .map_err(|e| Mp2JsonError::InvalidInputDocument(format!("clipboard: {}", e)));
+    }
+    Ok(args.hex.take().or(args.base64.take()).map(|input| input.0))
+}
+
+fn run<W: Write>(mut args: Args, output: W) -> Result<(), Mp2JsonError> {
+    let inline = inline_input(&mut args)?;
+    let (files, sources) = match &args.input_dir {
+        Some(path) => {
+            let (files, sources) = dir::Files::open(path, args.glob.as_deref(), args.recursive)
+                .map_err(|e| {
+                    Mp2JsonError::InvalidInputDocument(format!("{}: {}", path.display(), e))
+                })?;
+            (Some(files), sources)
+        }
+        None => (None, Vec::new()),
+    };
+    #[cfg(unix)]
+    let fd_input = args
+        .fd
+        .map(fd::take)
+        .transpose()
+        .map_err(|e| Mp2JsonError::InvalidInputDocument(format!("--fd: {}", e)))?;
+    #[cfg(not(unix))]
+    let fd_input: Option<std::fs::File> = None;
+    if args.reverse {
+        let r = ReverseConverter {
+            buffered: !args.unbuffered,
+            canonical: args.reverse_canonical,
+            all_floats: args.reverse_all_floats,
+            strict: args.strict,
+            typed_nulls: args.typed_nulls,
+            on_broken_pipe: args.on_broken_pipe,
+        };
+        return match (inline, files, fd_input) {
+            (Some(bytes), _, _) => r.run(bytes.as_slice(), output),
+            (None, Some(files), _) => r.run(files, output),
+            (None, None, Some(file)) => r.run(file, output),
+            (None, None, None) => r.run(args.input, output),
+        };
+    }
+    let (mut skip_bytes, mut start_offset) = (args.skip_bytes, 0);
+    if let (true, Some(path)) = (args.resume, &args.checkpoint) {
+        let offset = match std::fs::read_to_string(path) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+                Mp2JsonError::InvalidInputDocument(format!("checkpoint {}: {}", path.display(), e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(Mp2JsonError::InvalidInputDocument(e.to_string())),
+        };
+        if inline.is_none() && files.is_none() && fd_input.is_none() && args.input.can_seek() {
+            args.input
+                .seek(SeekFrom::Start(offset))
+                .map_err(|e| Mp2JsonError::InvalidInputDocument(e.to_string()))?;
+            start_offset = offset;
         } else {
-            self.run_inner(input, output)
+            skip_bytes = offset;
         }
     }
+    let mut options = if args.strict {
+        ConvertOptions::strict()
+    } else {
+        ConvertOptions {
+            large_integers: if args.json_safe_numbers {
+                LargeIntegers::String
+            } else {
+                args.large_integers
+            },
+            non_finite: args.non_finite,
+            duplicate_keys: args.duplicate_keys,
+            ..Default::default()
+        }
+    };
+    options.int_as_timestamp = args.int_as_timestamp;
+    options.timestamp_unit = args.timestamp_unit;
+    options.max_depth = args.max_depth;
+    options.on_max_depth = args.on_max_depth;
+    options.max_string_length = args.max_string_length;
+    options.truncated_strings = args.truncated_strings;
+    options.truncate_keys = args.truncate_keys;
+    options.preserve_number_type = args.preserve_number_type;
+    options.binary_encoding = args.binary_encoding;
+    options.binary_threshold = args.binary_threshold;
+    options.decode_nested_msgpack = args.decode_nested_msgpack;
+    options.reject_binary = args.no_binary;
+    options.parse_json_strings = args.parse_json_strings;
+    options.typed_nulls = args.typed_nulls;
+    options.binary_keys = args.binary_keys;
+    options.key_case = args.key_case;
+    options.ordered_maps = args.ordered_maps;
+    options.ext_decoders = args.decode_ext;
+    if args.inspect_first {
+        let report = match (inline, files, fd_input) {
+            (Some(bytes), _, _) => mp2json::inspect_one(&mut bytes.as_slice(), &options),
+            (None, Some(mut files), _) => mp2json::inspect_one(&mut files, &options),
+            (None, None, Some(mut file)) => mp2json::inspect_one(&mut file, &options),
+            (None, None, None) => mp2json::inspect_one(&mut args.input, &options),
+        }?;
+        let mut output = output;
+        return report
+            .write_pretty(&mut output, 2)
+            .and_then(|_| output.write_all(b"\n"))
+            .map_err(Mp2JsonError::Output);
+    }
+    // Every report and warning goes to stderr a whole line at a time
+    let diagnostics = Diagnostics::new(std::io::stderr());
+    let c = Converter {
+        buffered: !args.unbuffered,
+        line_buffered: args.line_buffered,
+        pretty: args.pretty || args.collapse_below.is_some() || args.align,
+        pretty_width: args.pretty_width,
+        collapse_below: args.collapse_below,
+        align: match (args.align, args.align_numbers) {
+            (_, true) => Some(Align::Numbers),
+            (true, false) => Some(Align::Keys),
+            (false, false) => None,
+        },
+        float_notation: if args.deterministic_floats {
+            FloatNotation::Ecmascript
+        } else {
+            args.float_notation
+        },
+        sort_keys: args.sort_keys,
+        sort_by_value: args.sort_by_value,
+        canonical: args.canonical,
+        eof_marker: args.eof_marker,
+        annotate_types: args.annotate_types,
+        include_offsets: args.include_offsets,
+        sources: if args.include_filename {
+            sources
+        } else {
+            Vec::new()
+        },
+        projection: if args.keys_only {
+            Some(Projection::Keys)
+        } else if args.values_only {
+            Some(Projection::Values)
+        } else {
+            None
+        },
+        skip_non_objects: args.skip_non_objects,
+        allow_truncated: args.allow_truncated,
+        frame_marker: args.frame_marker,
+        resync: args.resync.then_some(args.resync_limit),
+        input_limit: args.input_limit,
+        skip_bytes,
+        start_offset,
+        checkpoint: args.checkpoint,
+        checkpoint_interval: args.checkpoint_interval,
+        resumable: args.resumable,
+        errors_as_json: args.errors_as_json,
+        types_sidecar: args.types_sidecar,
+        direct: args.fast,
+        array: args.array,
+        prefix: args.prefix,
+        suffix: args.suffix,
+        line_prefix: args.line_prefix,
+        line_numbers: args.line_numbers,
+        input_format: args.input_format,
+        output_encoding: args.output_encoding,
+        format: args.format,
+        strict_csv: args.strict_csv,
+        env_export: args.export,
+        env_flatten: args.flatten,
+        env_sanitize_keys: args.sanitize_keys,
+        max_output_bytes: args.max_output_bytes,
+        min_records: args.min_records,
+        max_records: args.max_records,
+        group_by: args.group_by,
+        merge: args.merge,
+        merge_arrays: args.merge_arrays,
+        sample: args.sample,
+        count_by: args.count_by,
+        seed: args.seed,
+        coerce: args.coerce,
+        unwrap_singletons: args.unwrap_singletons,
+        drop_keys: args.drop_keys,
+        drop_paths: args.drop_paths,
+        redact: args.redact,
+        redact_salt: args.redact_salt.unwrap_or_else(|| {
+            let mut rng = fastrand::Rng::new();
+            (0..16).map(|_| format!("{:02x}", rng.u8(..))).collect()
+        }),
+        schema: args.assert_schema,
+        schema_continue: args.schema_continue,
+        select: args.select,
+        raw_output: args.raw_output,
+        on_broken_pipe: args.on_broken_pipe,
+        check_canonical: args
+            .check_canonical
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        strict_canonical: args.strict_canonical,
+        check_homogeneous: (args.homogeneous_check && !args.strict)
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        strict_homogeneous: args.homogeneous_check && args.strict,
+        route: args.route.map(|path| Route {
+            path,
+            template: args.route_template,
+        }),
+        size_report: args
+            .size_report
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        summary: args
+            .summary
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        source_map: match &args.source_map {
+            Some(path) => Some(Box::new(std::io::BufWriter::new(
+                std::fs::File::create(path).map_err(Mp2JsonError::Output)?,
+            ))),
+            None => None,
+        },
+        hash_input: args
+            .hash_input
+            .is_some()
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        hash_output: args
+            .hash_output
+            .is_some()
+            .then(|| Box::new(diagnostics.clone()) as Box<dyn Write + Send>),
+        tee: match &args.tee {
+            Some(path) => Some(Box::new(std::io::BufWriter::new(
+                std::fs::File::create(path).map_err(Mp2JsonError::Output)?,
+            ))),
+            None => None,
+        },
+        interrupt: Some(&interrupt::INTERRUPTED),
+        jobs: args.jobs,
+        options,
+    };
+    match (inline, files, fd_input) {
+        (Some(bytes), _, _) => c.run(bytes.as_slice(), output),
+        (None, Some(files), _) => c.run(files, output),
+        (None, None, Some(file)) => c.run(file, output),
+        (None, None, None) => c.run(args.input, output),
+    }
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about)]
-struct Args {
-    #[clap(short = 'p', long)]
-    pretty: bool,
-    #[clap(short = 'U', long, help = "Flush input after each message")]
-    unbuffered: bool,
-    #[clap(
-        short,
-        long,
-        default_value = "-",
-        help = "Input path of file to convert from msgpack to JSON (or - for stdin)"
-    )]
-    input: clio::Input,
+/// The exit status after a record which can be fixed and skipped past
+const EXIT_RESUMABLE: i32 = 3;
+
+/// Convert the input file each time it changes, reporting errors from a
+/// run without giving up on the next
+fn run_watched(argv: Vec<OsString>, path: &std::path::Path) -> Result<(), Mp2JsonError> {
+    let stop = || interrupt::INTERRUPTED.load(std::sync::atomic::Ordering::SeqCst);
+    watch::watch(path, watch::POLL_INTERVAL, stop, || {
+        let args = parse_args(&argv).unwrap_or_else(|e| e.exit());
+        if let Err(e) = run(args, std::io::stdout().lock()) {
+            eprintln!("Error: {}", e);
+        }
+    })
+    .map_err(|e| Mp2JsonError::InvalidInputDocument(e.to_string()))
 }
 
 fn main() -> Result<(), Mp2JsonError> {
-    let args = Args::parse();
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let args = parse_args(&argv).unwrap_or_else(|e| e.exit());
 
-    let stdout = std::io::stdout();
-    let stdout_h = stdout.lock();
-    let c = Converter {
-        buffered: !args.unbuffered,
-        pretty: args.pretty,
+    interrupt::install();
+    if args.watch {
+        if args.input.is_std() {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--watch needs an input file, not standard input",
+                )
+                .exit();
+        }
+        let path = args.input.path().to_path_buf();
+        return run_watched(argv, &path);
+    }
+    #[cfg(unix)]
+    let result = match args.output_fd {
+        Some(fd) => fd::take(fd)
+            .map_err(Mp2JsonError::Output)
+            .and_then(|output| run(args, output)),
+        None => run(args, std::io::stdout().lock()),
     };
-    c.run(args.input, stdout_h)
+    #[cfg(not(unix))]
+    let result = run(args, std::io::stdout().lock());
+    match result {
+        Err(Mp2JsonError::Resumable {
+            resume_offset,
+            source,
+        }) => {
+            eprintln!("Error: {}", source);
+            eprintln!("resume-offset: {}", resume_offset);
+            std::process::exit(EXIT_RESUMABLE)
+        }
+        Err(e @ (Mp2JsonError::TooFewRecords { .. } | Mp2JsonError::TooManyRecords { .. })) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
+        }
+        result => result,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::io::Write;
 
-    use assert_matches::assert_matches;
-    use json::JsonValue;
+    use clap::Parser;
 
-    use super::{read_and_convert_one, Mp2JsonError};
+    use super::{parse_layered, run, Args};
+
+    fn parse_with_env(args: &[&str], env: Option<&str>) -> Result<Args, clap::Error> {
+        parse_layered(
+            std::iter::once("mp2json").chain(args.iter().copied()),
+            env.map(Into::into),
+        )
+    }
+
+    fn run_args(args: &[&str]) -> String {
+        let args = parse_with_env(args, None).unwrap();
+        let mut output = Vec::new();
+        run(args, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_inline_input() {
+        assert_eq!(run_args(&["--hex", "01"]), "1\n");
+        assert_eq!(
+            run_args(&["--hex", "81 a1 61 c3", "-p"]),
+            "{\n  \"a\": true\n}\n"
+        );
+        assert_eq!(run_args(&["--base64", "gaFhoWI="]), "{\"a\":\"b\"}\n");
+        assert!(Args::try_parse_from(["mp2json", "--hex", "0"]).is_err());
+        assert!(Args::try_parse_from(["mp2json", "--hex", "zz"]).is_err());
+        assert!(Args::try_parse_from(["mp2json", "--base64", "!"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"\x01\x81\xa1a\xc3").unwrap();
+        std::io::Seek::rewind(&mut file).unwrap();
+        let fd = file.into_raw_fd().to_string();
+        assert_eq!(run_args(&["--fd", &fd]), "1\n{\"a\":true}\n");
+        assert!(Args::try_parse_from(["mp2json", "--fd", "0"]).is_err());
+        assert!(Args::try_parse_from(["mp2json", "--fd", "3", "-i", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_input_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("b.msgpack"), b"\x02\x03").unwrap();
+        std::fs::write(dir.path().join("a.msgpack"), b"\x01").unwrap();
+        std::fs::write(dir.path().join("c.txt"), b"\x04").unwrap();
+        let path = dir.path().to_str().unwrap();
+        assert_eq!(run_args(&["--input-dir", path]), "1\n2\n3\n4\n");
+        assert_eq!(
+            run_args(&["--input-dir", path, "--glob", "*.msgpack"]),
+            "1\n2\n3\n"
+        );
+        assert_eq!(
+            run_args(&[
+                "--input-dir",
+                path,
+                "--glob",
+                "*.msgpack",
+                "--include-filename"
+            ]),
+            concat!(
+                r#"{"file":"a.msgpack","value":1}"#,
+                "\n",
+                r#"{"file":"b.msgpack","value":2}"#,
+                "\n",
+                r#"{"file":"b.msgpack","value":3}"#,
+                "\n"
+            )
+        );
+    }
 
     #[test]
-    fn test_smoke() {
+    fn test_raw_output() {
+        // {"a": {"b": "x\ty"}, "n": 1}
+        let input = "82 a1 61 81 a1 62 a3 78 09 79 a1 6e 01";
         assert_eq!(
-            read_and_convert_one(&mut Cursor::new(b"\x01")).unwrap(),
-            JsonValue::Number(1.into())
+            run_args(&["--hex", input, "--select", "a.b"]),
+            "\"x\\ty\"\n"
         );
         assert_eq!(
-            read_and_convert_one(&mut Cursor::new(b"\xc0")).unwrap(),
-            JsonValue::Null
+            run_args(&["--hex", input, "--select", "a.b", "-r"]),
+            "x\ty\n"
         );
+        // Other values are written as usual
+        assert_eq!(run_args(&["--hex", input, "--select", "n", "-r"]), "1\n");
         assert_eq!(
-            read_and_convert_one(&mut Cursor::new(b"\x81\xa3foo\xc4\x03bar"))
-                .unwrap()
-                .dump(),
-            r#"{"foo":{"encoding":"base64","value":"YmFy"}}"#.to_string(),
+            run_args(&["--hex", input, "--select", "a", "-r"]),
+            "{\"b\":\"x\\ty\"}\n"
         );
+        assert_eq!(run_args(&["--hex", input, "--select", "z", "-r"]), "null\n");
     }
 
     #[test]
-    fn test_non_stringy_map() {
-        assert_matches!(
-            read_and_convert_one(&mut Cursor::new(b"\x81\x01\x02")),
-            Err(Mp2JsonError::MapKeyNotString)
+    fn test_config_file() {
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        config
+            .write_all(
+                br#"{
+                    "pretty": true,
+                    "eof_marker": {"done": 1},
+                    "large-integers": "error",
+                    "unknown": 1
+                }"#,
+            )
+            .unwrap();
+        let path = config.path().to_str().unwrap();
+        assert_eq!(
+            run_args(&["--config", path, "--hex", "01"]),
+            "1\n{\n  \"done\": 1\n}\n"
         );
+        // 2^53 + 1, which only the command line allows
+        assert_eq!(
+            run_args(&[
+                "--config",
+                path,
+                "--hex",
+                "cf 00 20 00 00 00 00 00 01",
+                "--large-integers",
+                "number"
+            ]),
+            "9007199254740993\n{\n  \"done\": 1\n}\n"
+        );
+    }
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        config.write_all(contents.as_bytes()).unwrap();
+        config
     }
 
     #[test]
-    fn test_invalid_string() {
-        assert_matches!(
-            read_and_convert_one(&mut Cursor::new(b"\xa2\xc3(")),
-            Err(Mp2JsonError::InvalidString)
+    fn test_config_layers() {
+        // An option the command line requires may come from the file
+        let config = config_file(r#"{"merge": true}"#);
+        let path = config.path().to_str().unwrap();
+        let args = parse_with_env(&["--config", path, "--merge-arrays"], None).unwrap();
+        assert!(args.merge && args.merge_arrays);
+
+        // Repeated options on the command line replace those in the file
+        let config = config_file(r#"{"coerce": ["a=string", "b=number"]}"#);
+        let path = config.path().to_str().unwrap();
+        let args = parse_with_env(&["--config", path, "--coerce", "a=bool"], None).unwrap();
+        assert_eq!(args.coerce.len(), 1);
+        let args = parse_with_env(&["--config", path], None).unwrap();
+        assert_eq!(args.coerce.len(), 2);
+
+        // Options on the command line win over those they conflict with
+        let config = config_file(r#"{"json-safe-numbers": true}"#);
+        let path = config.path().to_str().unwrap();
+        let args = parse_with_env(&["--config", path, "--large-integers", "number"], None).unwrap();
+        assert!(!args.json_safe_numbers);
+        assert!(
+            parse_with_env(&["--json-safe-numbers", "--large-integers", "number"], None).is_err()
         );
+
+        // MP2JSON_OPTS sits between the file and the command line
+        let config = config_file(r#"{"pretty": true, "large-integers": "error"}"#);
+        let path = config.path().to_str().unwrap();
+        let env = format!("--config {} --json-safe-numbers", path);
+        let args = parse_with_env(&[], Some(&env)).unwrap();
+        assert!(args.pretty && args.json_safe_numbers);
+        let args = parse_with_env(&["--large-integers", "number"], Some(&env)).unwrap();
+        assert!(args.pretty && !args.json_safe_numbers);
+        assert!(parse_with_env(&[], Some("--no-such-option")).is_err());
     }
 }
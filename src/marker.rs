@@ -0,0 +1,81 @@
+//! Recovering the msgpack marker of each top-level value, which `rmpv`
+//! discards while decoding.
+
+use std::io::{self, Read};
+
+use rmp::Marker;
+
+/// A reader which remembers the first byte read through it since the last
+/// call to [`MarkerReader::take_marker`]; when reading one value at a time,
+/// that byte is the value's marker.
+pub struct MarkerReader<R> {
+    inner: R,
+    marker: Option<u8>,
+}
+
+impl<R> MarkerReader<R> {
+    pub fn new(inner: R) -> Self {
+        MarkerReader {
+            inner,
+            marker: None,
+        }
+    }
+
+    /// Return the first byte read since the previous call, if any
+    pub fn take_marker(&mut self) -> Option<u8> {
+        self.marker.take()
+    }
+}
+
+impl<R: Read> Read for MarkerReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 && self.marker.is_none() {
+            self.marker = Some(buf[0]);
+        }
+        Ok(n)
+    }
+}
+
+/// The name of the msgpack format family indicated by a marker byte
+pub fn type_name(marker: u8) -> &'static str {
+    match Marker::from_u8(marker) {
+        Marker::FixPos(_) => "positive_fixint",
+        Marker::FixNeg(_) => "negative_fixint",
+        Marker::Null => "nil",
+        Marker::True => "true",
+        Marker::False => "false",
+        Marker::U8 => "uint8",
+        Marker::U16 => "uint16",
+        Marker::U32 => "uint32",
+        Marker::U64 => "uint64",
+        Marker::I8 => "int8",
+        Marker::I16 => "int16",
+        Marker::I32 => "int32",
+        Marker::I64 => "int64",
+        Marker::F32 => "float32",
+        Marker::F64 => "float64",
+        Marker::FixStr(_) => "fixstr",
+        Marker::Str8 => "str8",
+        Marker::Str16 => "str16",
+        Marker::Str32 => "str32",
+        Marker::Bin8 => "bin8",
+        Marker::Bin16 => "bin16",
+        Marker::Bin32 => "bin32",
+        Marker::FixArray(_) => "fixarray",
+        Marker::Array16 => "array16",
+        Marker::Array32 => "array32",
+        Marker::FixMap(_) => "fixmap",
+        Marker::Map16 => "map16",
+        Marker::Map32 => "map32",
+        Marker::FixExt1 => "fixext1",
+        Marker::FixExt2 => "fixext2",
+        Marker::FixExt4 => "fixext4",
+        Marker::FixExt8 => "fixext8",
+        Marker::FixExt16 => "fixext16",
+        Marker::Ext8 => "ext8",
+        Marker::Ext16 => "ext16",
+        Marker::Ext32 => "ext32",
+        Marker::Reserved => "reserved",
+    }
+}
@@ -0,0 +1,265 @@
+//! Finding msgpack values which aren't in their smallest encoding, as
+//! canonical msgpack requires, such as 1 written as a uint64.
+
+use rmp::Marker;
+
+use crate::marker::type_name;
+
+/// A value which has a smaller encoding than the one it was written in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NonMinimal {
+    /// The offset of the value's marker within its record
+    pub(crate) offset: u64,
+    pub(crate) description: String,
+}
+
+/// The marker of the smallest encoding of an integer
+fn int_marker(i: i128) -> u8 {
+    match i {
+        0..=0x7f => 0x00,
+        -0x20..=-1 => 0xe0,
+        0x80..=0xff => 0xcc,
+        -0x80..=-0x21 => 0xd0,
+        0x100..=0xffff => 0xcd,
+        -0x8000..=-0x81 => 0xd1,
+        0x1_0000..=0xffff_ffff => 0xce,
+        -0x8000_0000..=-0x8001 => 0xd2,
+        0x1_0000_0000.. => 0xcf,
+        _ => 0xd3,
+    }
+}
+
+/// The number of bytes an integer takes with this marker
+fn int_len(marker: u8) -> usize {
+    match Marker::from_u8(marker) {
+        Marker::U8 | Marker::I8 => 2,
+        Marker::U16 | Marker::I16 => 3,
+        Marker::U32 | Marker::I32 => 5,
+        Marker::U64 | Marker::I64 => 9,
+        _ => 1,
+    }
+}
+
+/// The marker of the smallest header for a string, binary, array, map or
+/// ext of `len` items, where `family` is the marker of any one of them
+fn len_marker(family: Marker, len: usize) -> u8 {
+    let (fix, fix_max, eight, sixteen, thirty_two) = match family {
+        Marker::FixStr(_) | Marker::Str8 | Marker::Str16 | Marker::Str32 => {
+            (0xa0, 31, Some(0xd9), 0xda, 0xdb)
+        }
+        Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => (0xc4, 0xff, None, 0xc5, 0xc6),
+        Marker::FixArray(_) | Marker::Array16 | Marker::Array32 => (0x90, 15, None, 0xdc, 0xdd),
+        Marker::FixMap(_) | Marker::Map16 | Marker::Map32 => (0x80, 15, None, 0xde, 0xdf),
+        _ => {
+            return match len {
+                1 => 0xd4,
+                2 => 0xd5,
+                4 => 0xd6,
+                8 => 0xd7,
+                16 => 0xd8,
+                _ if len <= 0xff => 0xc7,
+                _ if len <= 0xffff => 0xc8,
+                _ => 0xc9,
+            }
+        }
+    };
+    match (len, eight) {
+        _ if len <= fix_max => fix,
+        (0..=0xff, Some(eight)) => eight,
+        (0..=0xffff, _) => sixteen,
+        _ => thirty_two,
+    }
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, b| n << 8 | u64::from(*b))
+}
+
+/// Every value in `record`, which must be the bytes of exactly one whole
+/// msgpack value, which has a smaller encoding than the one it has
+pub(crate) fn find(record: &[u8]) -> Vec<NonMinimal> {
+    let mut found = Vec::new();
+    let mut at = 0;
+    while at < record.len() {
+        let marker = record[at];
+        let mut report = |description: String| {
+            found.push(NonMinimal {
+                offset: at as u64,
+                description,
+            })
+        };
+        // Each arm moves past the marker and whatever data follows it, but
+        // not into the items of an array or map
+        let (header, data) = match Marker::from_u8(marker) {
+            Marker::U8 | Marker::U16 | Marker::U32 | Marker::U64 => {
+                let len = int_len(marker);
+                let i = read_uint(&record[at + 1..at + len]);
+                let smallest = int_marker(i.into());
+                if int_len(smallest) < len {
+                    report(format!(
+                        "{} for {} could be {}",
+                        type_name(marker),
+                        i,
+                        type_name(smallest)
+                    ));
+                }
+                (len, 0)
+            }
+            Marker::I8 | Marker::I16 | Marker::I32 | Marker::I64 => {
+                let len = int_len(marker);
+                let bits = 8 * (len - 1) as u32;
+                // Sign-extend from the width it was written in
+                let i = (read_uint(&record[at + 1..at + len]) << (64 - bits)) as i64 >> (64 - bits);
+                let smallest = int_marker(i.into());
+                if int_len(smallest) < len {
+                    report(format!(
+                        "{} for {} could be {}",
+                        type_name(marker),
+                        i,
+                        type_name(smallest)
+                    ));
+                }
+                (len, 0)
+            }
+            Marker::F64 => {
+                let f = f64::from_bits(read_uint(&record[at + 1..at + 9]));
+                if f64::from(f as f32) == f {
+                    report(format!("float64 for {} could be float32", f));
+                }
+                (9, 0)
+            }
+            Marker::F32 => (5, 0),
+            family @ (Marker::FixStr(_) | Marker::FixArray(_) | Marker::FixMap(_)) => {
+                let data = match family {
+                    Marker::FixStr(len) => len.into(),
+                    _ => 0,
+                };
+                (1, data)
+            }
+            family @ (Marker::Str8
+            | Marker::Str16
+            | Marker::Str32
+            | Marker::Bin8
+            | Marker::Bin16
+            | Marker::Bin32
+            | Marker::Array16
+            | Marker::Array32
+            | Marker::Map16
+            | Marker::Map32
+            | Marker::Ext8
+            | Marker::Ext16
+            | Marker::Ext32) => {
+                let width = match family {
+                    Marker::Str8 | Marker::Bin8 | Marker::Ext8 => 1,
+                    Marker::Str16
+                    | Marker::Bin16
+                    | Marker::Array16
+                    | Marker::Map16
+                    | Marker::Ext16 => 2,
+                    _ => 4,
+                };
+                let len = read_uint(&record[at + 1..at + 1 + width]) as usize;
+                let smallest = len_marker(family, len);
+                if smallest != marker {
+                    report(format!(
+                        "{} of length {} could be {}",
+                        type_name(marker),
+                        len,
+                        type_name(smallest)
+                    ));
+                }
+                match family {
+                    Marker::Array16 | Marker::Array32 | Marker::Map16 | Marker::Map32 => {
+                        (1 + width, 0)
+                    }
+                    // The type code, then the data
+                    Marker::Ext8 | Marker::Ext16 | Marker::Ext32 => (2 + width, len),
+                    _ => (1 + width, len),
+                }
+            }
+            Marker::FixExt1 => (2, 1),
+            Marker::FixExt2 => (2, 2),
+            Marker::FixExt4 => (2, 4),
+            Marker::FixExt8 => (2, 8),
+            Marker::FixExt16 => (2, 16),
+            Marker::FixPos(_)
+            | Marker::FixNeg(_)
+            | Marker::Null
+            | Marker::True
+            | Marker::False
+            | Marker::Reserved => (1, 0),
+        };
+        at += header + data;
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find;
+
+    fn descriptions(record: &[u8]) -> Vec<(u64, String)> {
+        find(record)
+            .into_iter()
+            .map(|n| (n.offset, n.description))
+            .collect()
+    }
+
+    #[test]
+    fn test_minimal() {
+        for record in [
+            &b"\x01"[..],
+            b"\xcc\x80",
+            b"\xcd\x01\x00",
+            b"\xe0",
+            b"\xd0\xdf",
+            b"\xd1\xff\x7f",
+            b"\xcf\xff\xff\xff\xff\xff\xff\xff\xff",
+            b"\xcb\x3f\xb9\x99\x99\x99\x99\x99\x9a",
+            b"\xca\x3f\xc0\x00\x00",
+            b"\x82\xa1a\x91\x01\xa1b\xd9\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            b"\xc4\x01\x00",
+            b"\xd6\x01abcd",
+            b"\xc7\x03\x01abc",
+        ] {
+            assert_eq!(descriptions(record), [], "{:x?}", record);
+        }
+    }
+
+    #[test]
+    fn test_non_minimal() {
+        assert_eq!(
+            descriptions(b"\xcf\x00\x00\x00\x00\x00\x00\x00\x01"),
+            [(0, "uint64 for 1 could be positive_fixint".to_string())]
+        );
+        assert_eq!(
+            descriptions(b"\xd1\xff\xff"),
+            [(0, "int16 for -1 could be negative_fixint".to_string())]
+        );
+        // Non-negative, so as small as a uint8
+        assert_eq!(
+            descriptions(b"\xd1\x00\xc8"),
+            [(0, "int16 for 200 could be uint8".to_string())]
+        );
+        assert_eq!(
+            descriptions(b"\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00"),
+            [(0, "float64 for 1.5 could be float32".to_string())]
+        );
+        // Nested, after a value which has data
+        assert_eq!(
+            descriptions(b"\x92\xa2ab\xdc\x00\x01\xd9\x01x"),
+            [
+                (4, "array16 of length 1 could be fixarray".to_string()),
+                (7, "str8 of length 1 could be fixstr".to_string())
+            ]
+        );
+        assert_eq!(
+            descriptions(b"\xc8\x00\x04\x01abcd"),
+            [(0, "ext16 of length 4 could be fixext4".to_string())]
+        );
+        assert_eq!(
+            descriptions(b"\xc5\x00\x01\x00"),
+            [(0, "bin16 of length 1 could be bin8".to_string())]
+        );
+    }
+}
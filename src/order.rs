@@ -0,0 +1,121 @@
+//! Ordering the entries of objects by their values.
+
+use std::cmp::Ordering;
+
+use json::object::Object as JsonObject;
+use json::JsonValue;
+
+use crate::SortOrder;
+
+/// Where each type of value sorts relative to the others
+fn type_rank(v: &JsonValue) -> u8 {
+    match v {
+        JsonValue::Null => 0,
+        JsonValue::Boolean(_) => 1,
+        JsonValue::Number(_) => 2,
+        JsonValue::Short(_) | JsonValue::String(_) => 3,
+        JsonValue::Array(_) => 4,
+        JsonValue::Object(_) => 5,
+    }
+}
+
+/// Compare two values: first by type, in the order null, boolean, number,
+/// string, array, object; then numbers numerically, strings by their UTF-8
+/// bytes, and arrays and objects member by member, objects by each key and
+/// then its value
+pub(crate) fn compare(a: &JsonValue, b: &JsonValue) -> Ordering {
+    match (a, b) {
+        (JsonValue::Boolean(a), JsonValue::Boolean(b)) => a.cmp(b),
+        (JsonValue::Number(a), JsonValue::Number(b)) => f64::from(*a).total_cmp(&f64::from(*b)),
+        (JsonValue::Array(a), JsonValue::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (JsonValue::Object(a), JsonValue::Object(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|((ak, av), (bk, bv))| ak.cmp(bk).then_with(|| compare(av, bv)))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => match (a.as_str(), b.as_str()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => type_rank(a).cmp(&type_rank(b)),
+        },
+    }
+}
+
+/// Recursively reorder the entries of every object in `v` by their values,
+/// breaking ties by key
+pub(crate) fn sort_by_value(v: &mut JsonValue, order: SortOrder) {
+    match v {
+        JsonValue::Array(a) => a.iter_mut().for_each(|v| sort_by_value(v, order)),
+        JsonValue::Object(o) => {
+            let mut entries: Vec<(String, JsonValue)> = o
+                .iter_mut()
+                .map(|(k, v)| {
+                    sort_by_value(v, order);
+                    (k.to_string(), v.take())
+                })
+                .collect();
+            entries.sort_by(|a, b| {
+                let by_value = compare(&a.1, &b.1);
+                match order {
+                    SortOrder::Asc => by_value,
+                    SortOrder::Desc => by_value.reverse(),
+                }
+                .then_with(|| a.0.cmp(&b.0))
+            });
+            let mut sorted = JsonObject::with_capacity(entries.len());
+            for (k, v) in entries {
+                sorted.insert(&k, v);
+            }
+            *o = sorted;
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::{compare, sort_by_value};
+    use crate::SortOrder;
+
+    fn keys(v: &json::JsonValue) -> Vec<&str> {
+        v.entries().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_sort_by_value() {
+        let mut v = json::object! { "a": 3, "b": 1, "c": 2 };
+        sort_by_value(&mut v, SortOrder::Asc);
+        assert_eq!(keys(&v), ["b", "c", "a"]);
+        sort_by_value(&mut v, SortOrder::Desc);
+        assert_eq!(keys(&v), ["a", "c", "b"]);
+        // Ties by key either way, and nested objects too
+        let mut v = json::object! {
+            "z": "x", "y": "x", "n": null, "o": { "q": 2, "p": 1 }, "t": true, "f": 1.5,
+        };
+        sort_by_value(&mut v, SortOrder::Desc);
+        assert_eq!(keys(&v), ["o", "y", "z", "f", "t", "n"]);
+        assert_eq!(keys(&v["o"]), ["q", "p"]);
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(compare(&(-2).into(), &1.5.into()), Ordering::Less);
+        assert_eq!(compare(&"b".into(), &"ab".into()), Ordering::Greater);
+        assert_eq!(compare(&false.into(), &0.into()), Ordering::Less);
+        assert_eq!(
+            compare(&json::array![1, 2], &json::array![1, 2, 0]),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&json::object! { "a": 2 }, &json::object! { "a": 1, "b": 0 }),
+            Ordering::Greater
+        );
+    }
+}
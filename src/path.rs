@@ -0,0 +1,138 @@
+//! Paths addressing values within a JSON document, like `a.b[0].c`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use json::JsonValue;
+
+use crate::is_binary_wrapper;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// A sequence of object keys and array indices, written as dot-separated
+/// keys with bracketed indices and an optional leading `$`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    /// Find the value at this path within `v`
+    pub fn lookup<'a>(&self, v: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.0.iter().try_fold(v, |v, segment| match (segment, v) {
+            (Segment::Key(k), JsonValue::Object(o)) => o.get(k),
+            (Segment::Index(i), JsonValue::Array(a)) => a.get(*i),
+            _ => None,
+        })
+    }
+
+    /// Find the value at this path within `v`, mutably
+    pub fn lookup_mut<'a>(&self, v: &'a mut JsonValue) -> Option<&'a mut JsonValue> {
+        lookup_mut(&self.0, v)
+    }
+
+    /// Remove the value at this path from its object or array within `v`,
+    /// returning it. The entries of the objects written for binary and ext
+    /// values are left alone.
+    pub fn remove(&self, v: &mut JsonValue) -> Option<JsonValue> {
+        let (last, parents) = self.0.split_last()?;
+        match (last, lookup_mut(parents, v)?) {
+            (Segment::Key(k), JsonValue::Object(o)) if !is_binary_wrapper(o) => o.remove(k),
+            (Segment::Index(i), JsonValue::Array(a)) if *i < a.len() => Some(a.remove(*i)),
+            _ => None,
+        }
+    }
+}
+
+fn lookup_mut<'a>(segments: &[Segment], v: &'a mut JsonValue) -> Option<&'a mut JsonValue> {
+    segments
+        .iter()
+        .try_fold(v, |v, segment| match (segment, v) {
+            (Segment::Key(k), JsonValue::Object(o)) => o.get_mut(k),
+            (Segment::Index(i), JsonValue::Array(a)) => a.get_mut(*i),
+            _ => None,
+        })
+}
+
+impl FromStr for Path {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('$').unwrap_or(s);
+        let s = s.strip_prefix('.').unwrap_or(s);
+        let mut segments = Vec::new();
+        if s.is_empty() {
+            return Ok(Path(segments));
+        }
+        for part in s.split('.') {
+            let (key, mut indices) = match part.find('[') {
+                Some(i) => part.split_at(i),
+                None => (part, ""),
+            };
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_string()));
+            } else if indices.is_empty() {
+                return Err(format!("empty path segment in {:?}", s));
+            }
+            while !indices.is_empty() {
+                let end = indices
+                    .find(']')
+                    .filter(|_| indices.starts_with('['))
+                    .ok_or_else(|| format!("malformed index in {:?}", part))?;
+                let index = indices[1..end]
+                    .parse()
+                    .map_err(|_| format!("invalid index {:?} in {:?}", &indices[1..end], part))?;
+                segments.push(Segment::Index(index));
+                indices = &indices[end + 1..];
+            }
+        }
+        Ok(Path(segments))
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("$")?;
+        for segment in &self.0 {
+            match segment {
+                Segment::Key(k) => write!(f, ".{}", k)?,
+                Segment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Path;
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let v = json::object! { "a": { "b": [1, { "c": "x" }] } };
+        let path: Path = "a.b[1].c".parse().unwrap();
+        assert_eq!(path.to_string(), "$.a.b[1].c");
+        assert_eq!(path.lookup(&v).unwrap(), "x");
+        assert_eq!("$.a.b[0]".parse::<Path>().unwrap().lookup(&v).unwrap(), 1);
+        assert!("a.z".parse::<Path>().unwrap().lookup(&v).is_none());
+        assert!("a..b".parse::<Path>().is_err());
+        assert!("a[x]".parse::<Path>().is_err());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut v = json::object! { "a": { "b": [1, { "c": "x" }], "d": true } };
+        let remove = |v: &mut json::JsonValue, path: &str| path.parse::<Path>().unwrap().remove(v);
+        assert_eq!(remove(&mut v, "a.b[1].c").unwrap(), "x");
+        assert_eq!(remove(&mut v, "a.b[0]").unwrap(), 1);
+        assert!(remove(&mut v, "a.b[5]").is_none());
+        assert!(remove(&mut v, "a.z").is_none());
+        assert!(remove(&mut v, "$").is_none());
+        assert_eq!(v, json::object! { "a": { "b": [{}], "d": true } });
+        let mut wrapped = json::object! { "bin": { "encoding": "base64", "value": "" } };
+        assert!(remove(&mut wrapped, "bin.value").is_none());
+        assert_eq!(remove(&mut wrapped, "bin").unwrap()["encoding"], "base64");
+    }
+}
@@ -0,0 +1,374 @@
+//! A pretty-printer which only breaks containers across lines when their
+//! compact form would not fit within a target column width, and which can
+//! summarise deeply nested containers rather than writing them out.
+
+use std::io::{self, Write};
+
+use json::codegen::Generator;
+use json::JsonValue;
+
+use crate::generator::{entries, format_float, OutputGenerator};
+use crate::{Align, FloatNotation};
+
+const INDENT: usize = 2;
+
+/// How every level of the value is written
+struct Style {
+    width: usize,
+    notation: FloatNotation,
+    sort_keys: bool,
+    /// Write non-empty containers this many levels deep as a summary like
+    /// `{… 5 keys}`
+    collapse_below: Option<usize>,
+    /// Pad the keys of objects broken across lines so that their values
+    /// line up
+    align: Option<Align>,
+}
+
+impl Style {
+    /// The summary to write in place of `v`, if it is collapsed
+    fn collapsed(&self, v: &JsonValue, level: usize) -> Option<String> {
+        if self.collapse_below.is_none_or(|depth| level < depth) {
+            return None;
+        }
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        match v {
+            JsonValue::Array(a) if !a.is_empty() => {
+                Some(format!("[\u{2026} {} item{}]", a.len(), plural(a.len())))
+            }
+            JsonValue::Object(o) if !o.is_empty() => {
+                Some(format!("{{\u{2026} {} key{}}}", o.len(), plural(o.len())))
+            }
+            _ => None,
+        }
+    }
+
+    /// How `v` is written if it is a number, for right-aligning it
+    fn number_text(&self, v: &JsonValue) -> Option<String> {
+        match v {
+            JsonValue::Number(n) if self.align == Some(Align::Numbers) => {
+                Some(format_float(n, self.notation).unwrap_or_else(|| v.dump()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Compute the length in characters of the compact serialization of `v`,
+/// giving up (and returning `None`) as soon as it exceeds `limit`.
+fn compact_len(v: &JsonValue, limit: usize, style: &Style, level: usize) -> Option<usize> {
+    let len = match v {
+        _ if style.collapse_below.is_some_and(|depth| level >= depth) => style
+            .collapsed(v, level)
+            .map_or_else(|| v.dump().chars().count(), |s| s.chars().count()),
+        JsonValue::Array(a) => {
+            let mut len = 2 + a.len().saturating_sub(1);
+            for item in a {
+                len += compact_len(item, limit.checked_sub(len)?, style, level + 1)?;
+            }
+            len
+        }
+        JsonValue::Object(o) => {
+            let mut len = 2 + o.len().saturating_sub(1);
+            for (k, item) in o.iter() {
+                len += JsonValue::from(k).dump().chars().count() + 1;
+                len += compact_len(item, limit.checked_sub(len)?, style, level + 1)?;
+            }
+            len
+        }
+        JsonValue::Number(n) => {
+            format_float(n, style.notation).map_or_else(|| v.dump().len(), |s| s.len())
+        }
+        other => other.dump().chars().count(),
+    };
+    if len > limit {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Write `v` compactly, collapsing containers as `style` says
+fn write_compact<W: Write>(
+    v: &JsonValue,
+    style: &Style,
+    level: usize,
+    output: &mut W,
+) -> io::Result<()> {
+    if let Some(summary) = style.collapsed(v, level) {
+        return output.write_all(summary.as_bytes());
+    }
+    match v {
+        JsonValue::Array(a) => {
+            output.write_all(b"[")?;
+            for (i, item) in a.iter().enumerate() {
+                if i > 0 {
+                    output.write_all(b",")?;
+                }
+                write_compact(item, style, level + 1, output)?;
+            }
+            output.write_all(b"]")
+        }
+        JsonValue::Object(o) => {
+            output.write_all(b"{")?;
+            for (i, (k, item)) in entries(o, style.sort_keys).into_iter().enumerate() {
+                if i > 0 {
+                    output.write_all(b",")?;
+                }
+                output.write_all(JsonValue::from(k).dump().as_bytes())?;
+                output.write_all(b":")?;
+                write_compact(item, style, level + 1, output)?;
+            }
+            output.write_all(b"}")
+        }
+        other => OutputGenerator::new(output, None, style.notation).write_json(other),
+    }
+}
+
+fn write_indent<W: Write>(output: &mut W, level: usize) -> io::Result<()> {
+    write!(output, "\n{:1$}", "", level * INDENT)
+}
+
+fn write_inner<W: Write>(
+    v: &JsonValue,
+    style: &Style,
+    level: usize,
+    column: usize,
+    trailer: usize,
+    output: &mut W,
+) -> io::Result<()> {
+    if let Some(summary) = style.collapsed(v, level) {
+        return output.write_all(summary.as_bytes());
+    }
+    let available = style.width.saturating_sub(column + trailer);
+    let fits = compact_len(v, available, style, level).is_some();
+    match v {
+        JsonValue::Array(a) if !fits && !a.is_empty() => {
+            output.write_all(b"[")?;
+            for (i, item) in a.iter().enumerate() {
+                write_indent(output, level + 1)?;
+                let trailer = usize::from(i + 1 < a.len());
+                write_inner(
+                    item,
+                    style,
+                    level + 1,
+                    (level + 1) * INDENT,
+                    trailer,
+                    output,
+                )?;
+                if trailer > 0 {
+                    output.write_all(b",")?;
+                }
+            }
+            write_indent(output, level)?;
+            output.write_all(b"]")
+        }
+        JsonValue::Object(o) if !fits && !o.is_empty() => {
+            let entries = entries(o, style.sort_keys);
+            let keys: Vec<String> = entries
+                .iter()
+                .map(|(k, _)| JsonValue::from(*k).dump())
+                .collect();
+            let key_width = match style.align {
+                Some(_) => keys.iter().map(|k| k.chars().count()).max().unwrap_or(0),
+                None => 0,
+            };
+            let numbers: Vec<Option<String>> = entries
+                .iter()
+                .map(|(_, item)| style.number_text(item))
+                .collect();
+            let number_width = numbers.iter().flatten().map(String::len).max().unwrap_or(0);
+            output.write_all(b"{")?;
+            for (i, ((_, item), key)) in entries.iter().zip(&keys).enumerate() {
+                write_indent(output, level + 1)?;
+                output.write_all(key.as_bytes())?;
+                output.write_all(b": ")?;
+                let padding = key_width.saturating_sub(key.chars().count())
+                    + numbers[i]
+                        .as_ref()
+                        .map_or(0, |number| number_width - number.len());
+                write!(output, "{:1$}", "", padding)?;
+                let column = (level + 1) * INDENT + key.chars().count() + 2 + padding;
+                let trailer = usize::from(i + 1 < o.len());
+                write_inner(item, style, level + 1, column, trailer, output)?;
+                if trailer > 0 {
+                    output.write_all(b",")?;
+                }
+            }
+            write_indent(output, level)?;
+            output.write_all(b"}")
+        }
+        _ if style.collapse_below.is_some() => write_compact(v, style, level, output),
+        other => OutputGenerator::new(output, None, style.notation)
+            .sorted(style.sort_keys)
+            .write_json(other),
+    }
+}
+
+/// Write `v`, inlining any subtree whose compact form fits within `width`
+/// columns and breaking the rest across lines. If `collapse_below` is given,
+/// containers nested that many levels deep are summarised rather than
+/// written, which leaves the output no longer JSON. With `align`, the values
+/// of objects broken across lines are lined up.
+pub fn write_width<W: Write>(
+    v: &JsonValue,
+    width: usize,
+    notation: FloatNotation,
+    sort_keys: bool,
+    collapse_below: Option<usize>,
+    align: Option<Align>,
+    output: &mut W,
+) -> io::Result<()> {
+    let style = Style {
+        width,
+        notation,
+        sort_keys,
+        collapse_below,
+        align,
+    };
+    write_inner(v, &style, 0, 0, 0, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_width;
+    use crate::{Align, FloatNotation};
+
+    fn render(v: &json::JsonValue, width: usize) -> String {
+        let mut out = Vec::new();
+        write_width(v, width, FloatNotation::Auto, false, None, None, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_short_stays_inline() {
+        let v = json::object! { "a": 1, "b": [1, 2] };
+        assert_eq!(render(&v, 40), r#"{"a":1,"b":[1,2]}"#);
+    }
+
+    #[test]
+    fn test_long_wraps() {
+        let v = json::object! {
+            "name": "a fairly long string value",
+            "tags": ["one", "two"],
+            "nested": { "x": 1, "y": [1, 2, 3, 4, 5, 6, 7, 8, 9, 10] }
+        };
+        assert_eq!(
+            render(&v, 32),
+            concat!(
+                "{\n",
+                "  \"name\": \"a fairly long string value\",\n",
+                "  \"tags\": [\"one\",\"two\"],\n",
+                "  \"nested\": {\n",
+                "    \"x\": 1,\n",
+                "    \"y\": [1,2,3,4,5,6,7,8,9,10]\n",
+                "  }\n",
+                "}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_width_counts_characters() {
+        // 13 characters, but 18 bytes
+        let v = json::object! { "a": "\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}" };
+        assert_eq!(render(&v, 13), "{\"a\":\"\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\"}");
+        // The array fits after its key only if that is counted in characters
+        let v = json::object! { "b": "xxxxxxxxxxxxxx", "\u{e9}\u{e9}\u{e9}": [1, 2, 3] };
+        assert_eq!(
+            render(&v, 16),
+            concat!(
+                "{\n",
+                "  \"b\": \"xxxxxxxxxxxxxx\",\n",
+                "  \"\u{e9}\u{e9}\u{e9}\": [1,2,3]\n",
+                "}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_collapse_below() {
+        let v = json::object! {
+            "id": 1,
+            "a": { "b": { "c": { "d": 1 } }, "list": [[1, 2], []] }
+        };
+        let render = |width| {
+            let mut out = Vec::new();
+            write_width(
+                &v,
+                width,
+                FloatNotation::Auto,
+                false,
+                Some(2),
+                None,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            render(0),
+            concat!(
+                "{\n",
+                "  \"id\": 1,\n",
+                "  \"a\": {\n",
+                "    \"b\": {\u{2026} 1 key},\n",
+                "    \"list\": [\u{2026} 2 items]\n",
+                "  }\n",
+                "}"
+            )
+        );
+        assert_eq!(
+            render(80),
+            "{\"id\":1,\"a\":{\"b\":{\u{2026} 1 key},\"list\":[\u{2026} 2 items]}}"
+        );
+    }
+
+    #[test]
+    fn test_align() {
+        let v =
+            json::object! { "id": 7, "name": "x", "count": 1234, "nested": { "a": 1, "bb": 2 } };
+        let render = |align| {
+            let mut out = Vec::new();
+            write_width(
+                &v,
+                0,
+                FloatNotation::Auto,
+                false,
+                None,
+                Some(align),
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            render(Align::Keys),
+            concat!(
+                "{\n",
+                "  \"id\":     7,\n",
+                "  \"name\":   \"x\",\n",
+                "  \"count\":  1234,\n",
+                "  \"nested\": {\n",
+                "    \"a\":  1,\n",
+                "    \"bb\": 2\n",
+                "  }\n",
+                "}"
+            )
+        );
+        assert_eq!(
+            render(Align::Numbers),
+            concat!(
+                "{\n",
+                "  \"id\":        7,\n",
+                "  \"name\":   \"x\",\n",
+                "  \"count\":  1234,\n",
+                "  \"nested\": {\n",
+                "    \"a\":  1,\n",
+                "    \"bb\": 2\n",
+                "  }\n",
+                "}"
+            )
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Hiding the values at particular paths while keeping their keys.
+
+use std::fmt;
+use std::str::FromStr;
+
+use json::JsonValue;
+
+use crate::path::Path;
+use crate::sha256::Sha256;
+
+/// What to replace a redacted value with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedactMode {
+    /// The string `"***"`
+    #[default]
+    Mask,
+    /// The SHA-256 of the salt followed by the value's compact JSON text, in
+    /// hex, so that equal values can still be matched up
+    Hash,
+    /// The number of characters in a string or members in an array or
+    /// object, or otherwise of the value's compact JSON text
+    Length,
+}
+
+impl fmt::Display for RedactMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RedactMode::Mask => "mask",
+            RedactMode::Hash => "hash",
+            RedactMode::Length => "length",
+        })
+    }
+}
+
+/// A rule redacting the value at a path, written as `path` or `path=mode`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    pub path: Path,
+    pub mode: RedactMode,
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, mode) = match s.rsplit_once('=') {
+            Some((path, "mask")) => (path, RedactMode::Mask),
+            Some((path, "hash")) => (path, RedactMode::Hash),
+            Some((path, "length")) => (path, RedactMode::Length),
+            Some((_, other)) => {
+                return Err(format!(
+                    "unknown mode {:?}; expected mask, hash, or length",
+                    other
+                ))
+            }
+            None => (s, RedactMode::default()),
+        };
+        Ok(Redaction {
+            path: path.parse()?,
+            mode,
+        })
+    }
+}
+
+fn redacted(v: &JsonValue, mode: RedactMode, salt: &str) -> JsonValue {
+    match mode {
+        RedactMode::Mask => "***".into(),
+        RedactMode::Hash => {
+            let mut hash = Sha256::default();
+            hash.update(salt.as_bytes());
+            hash.update(v.dump().as_bytes());
+            hash.finish_hex().into()
+        }
+        RedactMode::Length => match v {
+            JsonValue::Short(_) | JsonValue::String(_) => {
+                v.as_str().unwrap_or_default().chars().count().into()
+            }
+            JsonValue::Array(a) => a.len().into(),
+            JsonValue::Object(o) => o.len().into(),
+            _ => v.dump().len().into(),
+        },
+    }
+}
+
+impl Redaction {
+    /// Replace the value at this rule's path within `record`, if there is
+    /// one, salting any hash with `salt`
+    pub fn apply(&self, record: &mut JsonValue, salt: &str) {
+        if let Some(v) = self.path.lookup_mut(record) {
+            *v = redacted(v, self.mode, salt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RedactMode, Redaction};
+
+    fn redact(rule: &str, mut v: json::JsonValue) -> String {
+        rule.parse::<Redaction>().unwrap().apply(&mut v, "salt");
+        v.dump()
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("ssn", json::object! { "name": "x", "ssn": "123-45-6789" }),
+            r#"{"name":"x","ssn":"***"}"#
+        );
+        assert_eq!(
+            redact("a[0]=length", json::object! { "a": ["h\u{e9}llo"] }),
+            r#"{"a":[5]}"#
+        );
+        assert_eq!(
+            redact("a=length", json::object! { "a": { "b": 1, "c": 2 } }),
+            r#"{"a":2}"#
+        );
+        assert_eq!(
+            redact("n=length", json::object! { "n": 12.5 }),
+            r#"{"n":4}"#
+        );
+        // printf 'salt"x"' | sha256sum
+        assert_eq!(
+            redact("k=hash", json::object! { "k": "x" }),
+            format!(
+                r#"{{"k":"{}"}}"#,
+                "593952601c3c700c79e3469a26c4f8c67271bbb422fda9d6a1086da347529a3a"
+            )
+        );
+        assert_eq!(
+            redact("missing=mask", json::object! { "k": 1 }),
+            r#"{"k":1}"#
+        );
+        assert_eq!("a.b".parse::<Redaction>().unwrap().mode, RedactMode::Mask);
+        assert!("a=erase".parse::<Redaction>().is_err());
+    }
+}
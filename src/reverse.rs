@@ -0,0 +1,618 @@
+//! Conversion in the other direction, from a stream of JSON values back into
+//! concatenated msgpack.
+
+use std::io::{self, BufRead, Write};
+
+use json::JsonValue;
+use rmpv::Value as MpValue;
+
+use crate::adapters::RetryReader;
+use crate::{is_typed_null, Mp2JsonError, OnBrokenPipe};
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Splits a byte stream into its whitespace-separated or concatenated JSON
+/// values without parsing them, so that values may span any number of lines
+struct JsonSplitter<R> {
+    inner: R,
+    /// A byte which ended the previous value and begins the next one
+    pushback: Option<u8>,
+    started: bool,
+}
+
+impl<R: BufRead> JsonSplitter<R> {
+    fn new(inner: R) -> Self {
+        JsonSplitter {
+            inner,
+            pushback: None,
+            started: false,
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(b) = self.pushback.take() {
+            return Ok(Some(b));
+        }
+        let b = match self.inner.fill_buf()? {
+            [] => return Ok(None),
+            buf => buf[0],
+        };
+        self.inner.consume(1);
+        Ok(Some(b))
+    }
+
+    /// Read the text of the next value into `buf`, returning `false` at the
+    /// end of the input
+    fn next_value(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        // Windows tools like to begin UTF-8 files with a byte order mark,
+        // which is not JSON whitespace and only expected before the first value
+        if !self.started {
+            self.started = true;
+            if self.inner.fill_buf()?.starts_with(BOM) {
+                self.inner.consume(BOM.len());
+            }
+        }
+        buf.clear();
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        while let Some(b) = self.next_byte()? {
+            if in_string {
+                buf.push(b);
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_string = false;
+                    if depth == 0 {
+                        return Ok(true);
+                    }
+                }
+                continue;
+            }
+            let is_scalar = !buf.is_empty() && depth == 0;
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' if is_scalar => return Ok(true),
+                b' ' | b'\t' | b'\n' | b'\r' => {}
+                b'"' | b'{' | b'[' if is_scalar => {
+                    self.pushback = Some(b);
+                    return Ok(true);
+                }
+                b'"' => {
+                    in_string = true;
+                    buf.push(b);
+                }
+                b'{' | b'[' => {
+                    depth += 1;
+                    buf.push(b);
+                }
+                b'}' | b']' => {
+                    buf.push(b);
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        return Ok(true);
+                    }
+                }
+                _ => buf.push(b),
+            }
+        }
+        // A truncated value is left for the parser to reject
+        Ok(!buf.is_empty())
+    }
+}
+
+/// The parsed values of a JSON stream
+pub(crate) struct JsonValues<R> {
+    splitter: JsonSplitter<R>,
+    text: Vec<u8>,
+    index: usize,
+}
+
+impl<R: BufRead> JsonValues<R> {
+    pub(crate) fn new(input: R) -> Self {
+        JsonValues {
+            splitter: JsonSplitter::new(input),
+            text: Vec::new(),
+            index: 0,
+        }
+    }
+
+    /// The text of the value last parsed
+    fn text(&self) -> &str {
+        std::str::from_utf8(&self.text).unwrap_or_default()
+    }
+}
+
+impl<R: BufRead> Iterator for JsonValues<R> {
+    type Item = Result<JsonValue, Mp2JsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.splitter.next_value(&mut self.text) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(Mp2JsonError::InvalidInputDocument(e.to_string()))),
+        }
+        let index = self.index;
+        self.index += 1;
+        let element_error = |reason: String| Mp2JsonError::InvalidInputElement { index, reason };
+        Some(
+            std::str::from_utf8(&self.text)
+                .map_err(|e| element_error(e.to_string()))
+                .and_then(|text| json::parse(text).map_err(|e| element_error(e.to_string()))),
+        )
+    }
+}
+
+/// Whether the `json` crate dropped digits from `n` to fit its mantissa in
+/// a u64, counting them in the exponent instead
+fn truncated(n: json::number::Number) -> bool {
+    let (_, mantissa, exponent) = n.as_parts();
+    exponent > 0 && mantissa >= u64::MAX / 10
+}
+
+/// The float64 nearest to `n`, which the `json` crate's own conversion
+/// doesn't always find
+fn to_f64(n: json::number::Number) -> f64 {
+    let (positive, mantissa, exponent) = n.as_parts();
+    let sign = if positive { "" } else { "-" };
+    format!("{}{}e{}", sign, mantissa, exponent)
+        .parse()
+        .unwrap_or(f64::NAN)
+}
+
+/// Convert a JSON number written without a fractional part to the msgpack
+/// integer it equals, if there is one, and to a float otherwise, so that
+/// `1.0` stays a float. `rmpv` already writes integers in their smallest
+/// format; when `canonical`, floats are also written as float32 when that
+/// loses nothing.
+fn number_to_msgpack(n: json::number::Number, canonical: bool) -> MpValue {
+    let (positive, mantissa, exponent) = n.as_parts();
+    let integer = u32::try_from(exponent)
+        .ok()
+        .filter(|_| !truncated(n))
+        .and_then(|exponent| 10u64.checked_pow(exponent))
+        .and_then(|scale| mantissa.checked_mul(scale));
+    match integer {
+        Some(i) if positive || i == 0 => i.into(),
+        Some(i) if i <= 1 << 63 => (i as i64).wrapping_neg().into(),
+        _ => {
+            let f = to_f64(n);
+            if canonical && f64::from(f as f32) == f {
+                MpValue::F32(f as f32)
+            } else {
+                MpValue::F64(f)
+            }
+        }
+    }
+}
+
+/// The significant digits of the decimal number `text`, without leading or
+/// trailing zeros, with its sign and the power of ten they're scaled by; zero
+/// has no digits and is never negative
+fn decimal(text: &str) -> (bool, String, i64) {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (significand, exponent) = text.split_once(['e', 'E']).unwrap_or((text, "0"));
+    let exponent = exponent.strip_prefix('+').unwrap_or(exponent);
+    let mut exponent = exponent
+        .parse::<i64>()
+        .unwrap_or(if exponent.starts_with('-') {
+            i64::MIN / 2
+        } else {
+            i64::MAX / 2
+        });
+    let (whole, fraction) = significand.split_once('.').unwrap_or((significand, ""));
+    exponent = exponent.saturating_sub(fraction.len() as i64);
+    let digits = format!("{}{}", whole, fraction);
+    let digits = digits.trim_start_matches('0');
+    let significant = digits.trim_end_matches('0');
+    exponent = exponent.saturating_add((digits.len() - significant.len()) as i64);
+    if significant.is_empty() {
+        return (false, String::new(), 0);
+    }
+    (negative, significant.to_string(), exponent)
+}
+
+/// Why the number written as `token` can't be written as msgpack exactly,
+/// if it can't: it is an integer beyond 64 bits, or a float64 either can't
+/// hold it at all or holds a different number
+fn inexact(written: &MpValue, token: &str, all_floats: bool) -> Option<&'static str> {
+    let integer = !token.contains(['.', 'e', 'E']);
+    match written {
+        MpValue::Integer(i) => (decimal(&i.to_string()) != decimal(token))
+            .then_some("digits would be lost writing the number as an integer"),
+        MpValue::F32(_) | MpValue::F64(_) => {
+            let f = written.as_f64().unwrap_or(f64::NAN);
+            if integer && !all_floats {
+                Some("integer does not fit in 64 bits")
+            } else if !f.is_finite() {
+                Some("number is beyond the range of a float64")
+            } else if decimal(&format!("{:e}", f)) != decimal(token) {
+                Some("float64 would not hold the number exactly")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl ReverseConverter {
+    /// Check that each number in `text`, a JSON value, can be written as
+    /// msgpack exactly: that integers fit in 64 bits and that everything
+    /// else is a float64 which reads back as the digits written. Numbers
+    /// written with a fraction or exponent, like `1e20`, are taken to be
+    /// floats. The text is scanned, rather than the parsed value, since the
+    /// `json` crate drops digits which don't fit its mantissa.
+    fn check_exact(&self, text: &str) -> Result<(), Mp2JsonError> {
+        enum Frame {
+            Array(usize),
+            Object(String),
+        }
+        let path = |frames: &[Frame]| {
+            frames
+                .iter()
+                .fold(String::from("$"), |path, frame| match frame {
+                    Frame::Array(i) => format!("{}[{}]", path, i),
+                    Frame::Object(k) => format!("{}.{}", path, k),
+                })
+        };
+        let bytes = text.as_bytes();
+        let mut frames = Vec::new();
+        let mut key_next = false;
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    let start = i;
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                    if let (true, Some(Frame::Object(k))) = (key_next, frames.last_mut()) {
+                        *k = json::parse(&text[start..i.min(text.len())])
+                            .ok()
+                            .and_then(|k| k.as_str().map(str::to_string))
+                            .unwrap_or_default();
+                    }
+                    key_next = false;
+                    continue;
+                }
+                b'{' => {
+                    frames.push(Frame::Object(String::new()));
+                    key_next = true;
+                }
+                b'[' => frames.push(Frame::Array(0)),
+                b'}' | b']' => {
+                    frames.pop();
+                }
+                b':' => key_next = false,
+                b',' => match frames.last_mut() {
+                    Some(Frame::Array(n)) => *n += 1,
+                    Some(Frame::Object(_)) => key_next = true,
+                    None => {}
+                },
+                b'-' | b'0'..=b'9' => {
+                    let start = i;
+                    while i < bytes.len()
+                        && matches!(bytes[i], b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')
+                    {
+                        i += 1;
+                    }
+                    let token = &text[start..i];
+                    if let Ok(number @ JsonValue::Number(_)) = json::parse(token) {
+                        let written = self.to_msgpack(&number);
+                        if let Some(reason) = inexact(&written, token, self.all_floats) {
+                            return Err(Mp2JsonError::NotRoundTrippable {
+                                path: path(&frames),
+                                reason: reason.to_string(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Convert a parsed JSON value to the equivalent msgpack value. Objects
+    /// shaped like the wrappers written for binary and ext values are maps
+    /// like any other, since they can't be told apart from user data.
+    fn to_msgpack(&self, v: &JsonValue) -> MpValue {
+        match v {
+            JsonValue::Null => MpValue::Nil,
+            JsonValue::Object(o) if self.typed_nulls && is_typed_null(o) => MpValue::Nil,
+            JsonValue::Boolean(b) => (*b).into(),
+            JsonValue::Number(n) if self.all_floats => MpValue::F64(to_f64(*n)),
+            JsonValue::Number(n) => number_to_msgpack(*n, self.canonical),
+            JsonValue::Short(_) | JsonValue::String(_) => v.as_str().unwrap_or_default().into(),
+            JsonValue::Array(a) => MpValue::Array(a.iter().map(|v| self.to_msgpack(v)).collect()),
+            JsonValue::Object(o) => MpValue::Map(
+                o.iter()
+                    .map(|(k, v)| (MpValue::from(k), self.to_msgpack(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Converts a stream of JSON values, in any layout, into concatenated
+/// msgpack values
+#[derive(Debug, Default)]
+pub struct ReverseConverter {
+    /// Buffer writes; otherwise each value is written immediately
+    pub buffered: bool,
+    /// Write every value in its smallest encoding, narrowing floats which
+    /// are exactly representable as float32
+    pub canonical: bool,
+    /// Write every number as a float64, even those written as integers
+    pub all_floats: bool,
+    /// Fail on values which can't be written as msgpack exactly, such as
+    /// integers beyond 64 bits, rather than approximating them
+    pub strict: bool,
+    /// Write objects exactly `{"__nil__": true}`, as written for nil with
+    /// [`crate::ConvertOptions::typed_nulls`], as nil
+    pub typed_nulls: bool,
+    pub on_broken_pipe: OnBrokenPipe,
+}
+
+impl ReverseConverter {
+    fn run_inner<R: BufRead, W: Write>(
+        &self,
+        input: R,
+        output: &mut W,
+    ) -> Result<(), Mp2JsonError> {
+        let mut values = JsonValues::new(input);
+        while let Some(v) = values.next() {
+            let v = v?;
+            if self.strict {
+                self.check_exact(values.text())?;
+            }
+            let written =
+                rmpv::encode::write_value(output, &self.to_msgpack(&v)).map_err(io::Error::from);
+            if !self.on_broken_pipe.check(written)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert all of `input`, writing the results to `output`
+    pub fn run<R: io::Read, W: Write>(self, input: R, output: W) -> Result<(), Mp2JsonError> {
+        let input = io::BufReader::new(RetryReader::new(input));
+        if self.buffered {
+            let mut output = io::BufWriter::new(output);
+            self.run_inner(input, &mut output)?;
+            self.on_broken_pipe.check(output.flush()).map(|_| ())
+        } else {
+            let mut output = output;
+            self.run_inner(input, &mut output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::ReverseConverter;
+    use crate::Mp2JsonError;
+
+    fn reverse(input: &str) -> Vec<u8> {
+        let mut output = Vec::new();
+        ReverseConverter::default()
+            .run(input.as_bytes(), &mut output)
+            .unwrap();
+        output
+    }
+
+    fn reverse_canonical(input: &str) -> Vec<u8> {
+        let mut output = Vec::new();
+        ReverseConverter {
+            canonical: true,
+            ..Default::default()
+        }
+        .run(input.as_bytes(), &mut output)
+        .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_compact_and_pretty_match() {
+        let compact = "{\"a\":[1,-2,3.5],\"b\":\"x}\\\"\"}\n[]\n\"s\"\n";
+        let pretty = concat!(
+            "\u{feff}{\n",
+            "  \"a\": [\n",
+            "    1,\n",
+            "    -2,\n",
+            "    3.5\n",
+            "  ],\n",
+            "  \"b\": \"x}\\\"\"\n",
+            "}\n",
+            "[\n]  \"s\"  \n\n",
+        );
+        let expected =
+            b"\x82\xa1a\x93\x01\xfe\xcb\x40\x0c\x00\x00\x00\x00\x00\x00\xa1b\xa3x}\"\x90\xa1s";
+        assert_eq!(reverse(compact), expected);
+        assert_eq!(reverse(pretty), expected);
+    }
+
+    #[test]
+    fn test_leading_bom() {
+        assert_eq!(reverse("\u{feff}{\"a\":1}"), b"\x81\xa1a\x01");
+        // Only at the very start of the stream
+        let mut output = Vec::new();
+        assert!(ReverseConverter::default()
+            .run("{\"a\":1}\n\u{feff}{\"a\":2}".as_bytes(), &mut output)
+            .is_err());
+        assert_eq!(output, b"\x81\xa1a\x01");
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(
+            reverse("1 true\nnull\"a\"2[3]"),
+            b"\x01\xc3\xc0\xa1a\x02\x91\x03"
+        );
+        assert_eq!(reverse("  \n"), b"");
+    }
+
+    #[test]
+    fn test_minimal_integers_and_strings() {
+        for (input, expected) in [
+            ("127", &b"\x7f"[..]),
+            ("128", b"\xcc\x80"),
+            ("255", b"\xcc\xff"),
+            ("256", b"\xcd\x01\x00"),
+            ("65536", b"\xce\x00\x01\x00\x00"),
+            ("4294967296", b"\xcf\x00\x00\x00\x01\x00\x00\x00\x00"),
+            ("-32", b"\xe0"),
+            ("-33", b"\xd0\xdf"),
+            ("-129", b"\xd1\xff\x7f"),
+        ] {
+            assert_eq!(reverse(input), expected, "{}", input);
+            assert_eq!(reverse_canonical(input), expected, "{}", input);
+        }
+        let s31 = "a".repeat(31);
+        let s32 = "a".repeat(32);
+        assert_eq!(reverse(&format!("\"{}\"", s31))[0], 0xbf);
+        assert_eq!(reverse(&format!("\"{}\"", s32))[..2], [0xd9, 32]);
+    }
+
+    #[test]
+    fn test_canonical_floats() {
+        assert_eq!(reverse_canonical("1.5"), b"\xca\x3f\xc0\x00\x00");
+        assert_eq!(reverse("1.5"), b"\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00");
+        // Not exact in float32
+        assert_eq!(reverse_canonical("0.1"), reverse("0.1"));
+    }
+
+    #[test]
+    fn test_number_inference() {
+        assert_eq!(reverse("1"), b"\x01");
+        assert_eq!(reverse("1.5"), b"\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00");
+        // Integral, but written as a float
+        assert_eq!(reverse("1.0"), b"\xcb\x3f\xf0\x00\x00\x00\x00\x00\x00");
+        assert_eq!(reverse("-1"), b"\xff");
+        assert_eq!(reverse("1e2"), b"\x64");
+        // Beyond u64, including where the json crate has dropped digits
+        assert_eq!(reverse("1e20")[0], 0xcb);
+        assert_eq!(reverse("18446744073709551616")[0], 0xcb);
+        assert_eq!(reverse("18446744073709551615")[0], 0xcf);
+        let mut output = Vec::new();
+        ReverseConverter {
+            all_floats: true,
+            ..Default::default()
+        }
+        .run(&b"1 1.5 1.0"[..], &mut output)
+        .unwrap();
+        assert_eq!(
+            output,
+            [
+                &b"\xcb\x3f\xf0\x00\x00\x00\x00\x00\x00"[..],
+                b"\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00",
+                b"\xcb\x3f\xf0\x00\x00\x00\x00\x00\x00",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_wrapper_shaped_objects_stay_maps() {
+        let input = r#"{"encoding":"base64","value":"YmFy"}"#;
+        assert_eq!(
+            reverse(input),
+            b"\x82\xa8encoding\xa6base64\xa5value\xa4YmFy"
+        );
+    }
+
+    #[test]
+    fn test_typed_nulls() {
+        let input = r#"{"a":{"__nil__":true},"b":{"__nil__":false},"c":{"__nil__":true,"d":1}}"#;
+        let mut output = Vec::new();
+        ReverseConverter {
+            typed_nulls: true,
+            ..Default::default()
+        }
+        .run(input.as_bytes(), &mut output)
+        .unwrap();
+        assert_eq!(
+            output,
+            b"\x83\xa1a\xc0\xa1b\x81\xa7__nil__\xc2\xa1c\x82\xa7__nil__\xc3\xa1d\x01"
+        );
+        assert_eq!(reverse(r#"{"__nil__":true}"#), b"\x81\xa7__nil__\xc3");
+    }
+
+    #[test]
+    fn test_strict() {
+        let strict = |input: &str| {
+            let mut output = Vec::new();
+            ReverseConverter {
+                strict: true,
+                ..Default::default()
+            }
+            .run(input.as_bytes(), &mut output)
+            .map(|_| output)
+        };
+        assert_eq!(
+            strict("[18446744073709551615, -9223372036854775808, 1e20, 0.5]").unwrap(),
+            reverse("[18446744073709551615, -9223372036854775808, 1e20, 0.5]")
+        );
+        for (input, expected_path) in [
+            ("18446744073709551616", "$"),
+            (r#"{"a": [1, -9223372036854775809]}"#, "$.a[1]"),
+            ("[100000000000000000000]", "$[0]"),
+        ] {
+            assert_matches!(
+                strict(input),
+                Err(Mp2JsonError::NotRoundTrippable { path, reason })
+                    if path == expected_path && reason == "integer does not fit in 64 bits"
+            );
+        }
+        assert_eq!(
+            strict(r#"[0.1, -0.0, 1.0, 2.5e-3, 9007199254740992.0, {"a}": [0]}]"#).unwrap(),
+            reverse(r#"[0.1, -0.0, 1.0, 2.5e-3, 9007199254740992.0, {"a}": [0]}]"#)
+        );
+        for (input, expected_path, expected_reason) in [
+            ("1e400", "$", "number is beyond the range of a float64"),
+            (
+                r#"{"a\"": [1, 9007199254740993.0]}"#,
+                "$.a\"[1]",
+                "float64 would not hold the number exactly",
+            ),
+            (
+                "[123456789012345678901234567890.5]",
+                "$[0]",
+                "float64 would not hold the number exactly",
+            ),
+            ("1e-400", "$", "float64 would not hold the number exactly"),
+            (
+                "12345678901234567890.5",
+                "$",
+                "digits would be lost writing the number as an integer",
+            ),
+        ] {
+            assert_matches!(
+                strict(input),
+                Err(Mp2JsonError::NotRoundTrippable { path, reason })
+                    if path == expected_path && reason == expected_reason
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let mut output = Vec::new();
+        assert!(ReverseConverter::default()
+            .run(&b"1 {\"a\":"[..], &mut output)
+            .is_err());
+        assert_eq!(output, b"\x01");
+    }
+}
@@ -0,0 +1,87 @@
+//! Writing records to different files according to the value at a path.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use json::JsonValue;
+
+use crate::aggregate::key_for;
+use crate::path::Path;
+
+/// The name used in place of the value for records which lack one
+const UNROUTED: &str = "__unrouted__";
+
+/// Where to write each record: the file named by substituting the value at
+/// `path` for `{value}` in `template`
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub path: Path,
+    pub template: String,
+}
+
+/// Make a value safe to use as (part of) a file name
+fn sanitize(value: &str) -> String {
+    let name: String = value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => name.replace('.', "_") + "_",
+        _ => name,
+    }
+}
+
+/// The open files of a [`Route`]
+pub(crate) struct Router {
+    route: Route,
+    files: HashMap<String, BufWriter<File>>,
+}
+
+impl Router {
+    pub(crate) fn new(route: Route) -> Self {
+        Router {
+            route,
+            files: HashMap::new(),
+        }
+    }
+
+    /// The file which `record` should be written to, opening it if needed
+    pub(crate) fn output_for(&mut self, record: &JsonValue) -> io::Result<&mut BufWriter<File>> {
+        let value = self
+            .route
+            .path
+            .lookup(record)
+            .map(|v| sanitize(&key_for(v)))
+            .unwrap_or_else(|| UNROUTED.to_string());
+        if !self.files.contains_key(&value) {
+            let name = self.route.template.replace("{value}", &value);
+            self.files
+                .insert(value.clone(), BufWriter::new(File::create(name)?));
+        }
+        Ok(self.files.get_mut(&value).expect("file was just opened"))
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.files.values_mut().try_for_each(Write::flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("click"), "click");
+        assert_eq!(sanitize("../etc/passwd"), ".._etc_passwd");
+        assert_eq!(sanitize(".."), "___");
+        assert_eq!(sanitize(""), "_");
+    }
+}
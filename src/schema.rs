@@ -0,0 +1,406 @@
+//! Checking records against a JSON Schema.
+//!
+//! Only the commonly used validation keywords are supported; a schema using
+//! any other is rejected when loaded, rather than silently not enforced.
+//! Binary and ext values are checked in the form they are written, as
+//! objects like `{"encoding": "base64", "value": "..."}`.
+
+use std::fmt;
+
+use json::JsonValue;
+
+/// Keywords which only annotate a schema, and are accepted but ignored
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+/// Keywords whose values are maps of names to schemas
+const SCHEMA_MAPS: &[&str] = &["properties", "definitions", "$defs"];
+
+/// Keywords whose values are lists of schemas
+const SCHEMA_LISTS: &[&str] = &["allOf", "anyOf", "oneOf"];
+
+/// Keywords whose values are schemas
+const SCHEMAS: &[&str] = &["items", "not"];
+
+/// Keywords whose values are checked while validating
+const CONSTRAINTS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "required",
+    "additionalProperties",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "minLength",
+    "maxLength",
+    "minItems",
+    "maxItems",
+    "minProperties",
+    "maxProperties",
+    "$ref",
+];
+
+/// How many `$ref`s may be followed without moving into the value, so that
+/// a schema referring to itself can't loop forever
+const MAX_REFS: usize = 32;
+
+/// A JSON Schema to check records against
+#[derive(Debug, Clone)]
+pub struct Schema(JsonValue);
+
+/// A way in which a value doesn't match a [`Schema`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Where in the value the mismatch is, as a JSONPath
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}: {}", self.path, self.message)
+    }
+}
+
+fn check_supported(schema: &JsonValue, at: &str) -> Result<(), String> {
+    let object = match schema {
+        JsonValue::Boolean(_) => return Ok(()),
+        JsonValue::Object(object) => object,
+        _ => return Err(format!("{} is not a schema", at)),
+    };
+    for (key, value) in object.iter() {
+        let at = format!("{}/{}", at, key);
+        if SCHEMA_MAPS.contains(&key) {
+            for (name, schema) in value.entries() {
+                check_supported(schema, &format!("{}/{}", at, name))?;
+            }
+        } else if SCHEMA_LISTS.contains(&key) {
+            for (i, schema) in value.members().enumerate() {
+                check_supported(schema, &format!("{}/{}", at, i))?;
+            }
+        } else if SCHEMAS.contains(&key) || (key == "additionalProperties" && value.is_object()) {
+            check_supported(value, &at)?;
+        } else if key == "$ref" && !value.as_str().is_some_and(|r| r.starts_with('#')) {
+            return Err(format!("{} refers outside the schema", at));
+        } else if !ANNOTATIONS.contains(&key) && !CONSTRAINTS.contains(&key) {
+            return Err(format!("unsupported schema keyword {}", at));
+        }
+    }
+    Ok(())
+}
+
+fn type_matches(name: &str, v: &JsonValue) -> bool {
+    match name {
+        "null" => v.is_null(),
+        "boolean" => v.is_boolean(),
+        "string" => v.is_string(),
+        "number" => v.is_number(),
+        "integer" => v.as_f64().is_some_and(|f| f.fract() == 0.0),
+        "array" => v.is_array(),
+        "object" => v.is_object(),
+        _ => false,
+    }
+}
+
+fn describe(v: &JsonValue) -> String {
+    let text = v.dump();
+    if text.len() > 40 {
+        format!("{}...", &text[..text.floor_char_boundary(40)])
+    } else {
+        text
+    }
+}
+
+impl Schema {
+    /// Parse a schema, failing if it uses a keyword which isn't supported
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let schema = json::parse(text).map_err(|e| e.to_string())?;
+        check_supported(&schema, "#")?;
+        Ok(Schema(schema))
+    }
+
+    /// The part of the schema a `$ref` like `#/$defs/name` points to
+    fn resolve(&self, reference: &str) -> Option<&JsonValue> {
+        let pointer = reference.strip_prefix('#')?;
+        pointer
+            .split('/')
+            .skip(1)
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .try_fold(&self.0, |schema, token| match schema {
+                JsonValue::Object(o) => o.get(&token),
+                JsonValue::Array(a) => a.get(token.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    /// Check `v` against the schema, giving the first mismatch found
+    pub fn validate(&self, v: &JsonValue) -> Result<(), Violation> {
+        self.check(&self.0, v, "$", 0)
+    }
+
+    fn check(
+        &self,
+        schema: &JsonValue,
+        v: &JsonValue,
+        path: &str,
+        refs: usize,
+    ) -> Result<(), Violation> {
+        let fail = |message: String| {
+            Err(Violation {
+                path: path.to_string(),
+                message,
+            })
+        };
+        let object = match schema {
+            JsonValue::Boolean(true) => return Ok(()),
+            JsonValue::Boolean(false) => return fail("no value is allowed here".to_string()),
+            JsonValue::Object(object) => object,
+            _ => return Ok(()),
+        };
+        if let Some(reference) = object.get("$ref").and_then(JsonValue::as_str) {
+            if refs >= MAX_REFS {
+                return fail(format!("too many nested references at {}", reference));
+            }
+            match self.resolve(reference) {
+                Some(target) => self.check(target, v, path, refs + 1)?,
+                None => return fail(format!("unresolvable reference {}", reference)),
+            }
+        }
+        if let Some(types) = object.get("type") {
+            let names: Vec<&str> = match types {
+                JsonValue::Array(names) => names.iter().filter_map(JsonValue::as_str).collect(),
+                name => name.as_str().into_iter().collect(),
+            };
+            if !names.iter().any(|name| type_matches(name, v)) {
+                return fail(format!(
+                    "expected {}, found {}",
+                    names.join(" or "),
+                    describe(v)
+                ));
+            }
+        }
+        if let Some(allowed) = object.get("enum") {
+            if !allowed.members().any(|allowed| allowed == v) {
+                return fail(format!("{} is not one of {}", describe(v), allowed.dump()));
+            }
+        }
+        if let Some(expected) = object.get("const") {
+            if expected != v {
+                return fail(format!(
+                    "expected {}, found {}",
+                    expected.dump(),
+                    describe(v)
+                ));
+            }
+        }
+        if let Some(f) = v.as_f64() {
+            let bound = |key: &str| object.get(key).and_then(JsonValue::as_f64);
+            for (key, ok) in [
+                ("minimum", bound("minimum").is_none_or(|min| f >= min)),
+                ("maximum", bound("maximum").is_none_or(|max| f <= max)),
+                (
+                    "exclusiveMinimum",
+                    bound("exclusiveMinimum").is_none_or(|min| f > min),
+                ),
+                (
+                    "exclusiveMaximum",
+                    bound("exclusiveMaximum").is_none_or(|max| f < max),
+                ),
+            ] {
+                if !ok {
+                    return fail(format!(
+                        "{} is outside {} {}",
+                        describe(v),
+                        key,
+                        object[key]
+                    ));
+                }
+            }
+        }
+        let count = |key: &str| object.get(key).and_then(JsonValue::as_usize);
+        let check_len = |len: usize, min: &str, max: &str, what: &str| {
+            if count(min).is_some_and(|min| len < min) || count(max).is_some_and(|max| len > max) {
+                return fail(format!("{} {} is outside {} and {}", what, len, min, max));
+            }
+            Ok(())
+        };
+        if let Some(s) = v.as_str() {
+            check_len(s.chars().count(), "minLength", "maxLength", "length")?;
+        }
+        if let JsonValue::Array(items) = v {
+            check_len(items.len(), "minItems", "maxItems", "item count")?;
+            if let Some(item_schema) = object.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    self.check(item_schema, item, &format!("{}[{}]", path, i), 0)?;
+                }
+            }
+        }
+        if let JsonValue::Object(fields) = v {
+            check_len(
+                fields.len(),
+                "minProperties",
+                "maxProperties",
+                "property count",
+            )?;
+            for name in object
+                .get("required")
+                .into_iter()
+                .flat_map(JsonValue::members)
+            {
+                if let Some(name) = name.as_str().filter(|name| fields.get(name).is_none()) {
+                    return fail(format!("missing required property {:?}", name));
+                }
+            }
+            let properties = object.get("properties");
+            for (key, field) in fields.iter() {
+                let field_path = format!("{}.{}", path, key);
+                match properties
+                    .and_then(|properties| properties.entries().find(|(k, _)| *k == key))
+                {
+                    Some((_, field_schema)) => self.check(field_schema, field, &field_path, 0)?,
+                    None => {
+                        if let Some(additional) = object.get("additionalProperties") {
+                            if additional == &JsonValue::Boolean(false) {
+                                return fail(format!("unexpected property {:?}", key));
+                            }
+                            self.check(additional, field, &field_path, 0)?;
+                        }
+                    }
+                }
+            }
+        }
+        for schema in object.get("allOf").into_iter().flat_map(JsonValue::members) {
+            self.check(schema, v, path, refs)?;
+        }
+        if let Some(any_of) = object.get("anyOf") {
+            if !any_of
+                .members()
+                .any(|schema| self.check(schema, v, path, refs).is_ok())
+            {
+                return fail("matches none of anyOf".to_string());
+            }
+        }
+        if let Some(one_of) = object.get("oneOf") {
+            let matching = one_of
+                .members()
+                .filter(|schema| self.check(schema, v, path, refs).is_ok())
+                .count();
+            if matching != 1 {
+                return fail(format!(
+                    "matches {} of oneOf rather than exactly one",
+                    matching
+                ));
+            }
+        }
+        if let Some(not) = object.get("not") {
+            if self.check(not, v, path, refs).is_ok() {
+                return fail("matches the schema under not".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schema;
+
+    fn violation(schema: &str, value: &str) -> Option<String> {
+        Schema::parse(schema)
+            .unwrap()
+            .validate(&json::parse(value).unwrap())
+            .err()
+            .map(|v| v.to_string())
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = r##"{
+            "type": "object",
+            "required": ["id"],
+            "properties": {
+                "id": {"type": "integer", "minimum": 1},
+                "tags": {"type": "array", "items": {"type": "string", "maxLength": 3}},
+                "data": {"$ref": "#/$defs/binary"}
+            },
+            "additionalProperties": false,
+            "$defs": {
+                "binary": {
+                    "type": "object",
+                    "properties": {"encoding": {"enum": ["base64", "hex"]}, "value": {"type": "string"}}
+                }
+            }
+        }"##;
+        assert_eq!(violation(schema, r#"{"id": 1, "tags": ["a"]}"#), None);
+        assert_eq!(
+            violation(
+                schema,
+                r#"{"id": 1, "data": {"encoding": "base64", "value": "AA=="}}"#
+            ),
+            None
+        );
+        assert_eq!(
+            violation(schema, r#"{"tags": []}"#).unwrap(),
+            r#"at $: missing required property "id""#
+        );
+        assert_eq!(
+            violation(schema, r#"{"id": 1.5}"#).unwrap(),
+            "at $.id: expected integer, found 1.5"
+        );
+        assert_eq!(
+            violation(schema, r#"{"id": 0}"#).unwrap(),
+            "at $.id: 0 is outside minimum 1"
+        );
+        assert_eq!(
+            violation(schema, r#"{"id": 1, "tags": ["a", "long"]}"#).unwrap(),
+            "at $.tags[1]: length 4 is outside minLength and maxLength"
+        );
+        assert_eq!(
+            violation(schema, r#"{"id": 1, "x": null}"#).unwrap(),
+            r#"at $: unexpected property "x""#
+        );
+        assert_eq!(
+            violation(schema, r#"{"id": 1, "data": {"encoding": "raw"}}"#).unwrap(),
+            r#"at $.data.encoding: "raw" is not one of ["base64","hex"]"#
+        );
+    }
+
+    #[test]
+    fn test_combinators() {
+        let schema = r#"{"oneOf": [{"type": "integer"}, {"type": "number", "maximum": 2}]}"#;
+        assert_eq!(violation(schema, "3"), None);
+        assert_eq!(violation(schema, "1.5"), None);
+        assert_eq!(
+            violation(schema, "1").unwrap(),
+            "at $: matches 2 of oneOf rather than exactly one"
+        );
+        assert_eq!(violation(r#"{"not": {"type": "null"}}"#, "1"), None);
+        assert!(violation(r#"{"anyOf": [false, {"const": 1}]}"#, "2").is_some());
+        // A schema referring to itself without moving into the value
+        assert!(violation(r##"{"$ref": "#"}"##, "1")
+            .unwrap()
+            .contains("too many nested references"));
+    }
+
+    #[test]
+    fn test_unsupported_keywords() {
+        assert_eq!(
+            Schema::parse(r#"{"properties": {"a": {"pattern": "^x"}}}"#).unwrap_err(),
+            "unsupported schema keyword #/properties/a/pattern"
+        );
+        assert!(Schema::parse(r#"{"$ref": "other.json"}"#).is_err());
+        assert!(Schema::parse(r#"{"title": "ok", "description": "ok"}"#).is_ok());
+    }
+}
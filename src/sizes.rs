@@ -0,0 +1,124 @@
+//! A report of where the bytes of the output go, keyed by JSONPath.
+
+use std::collections::HashMap;
+
+use json::object::Object as JsonObject;
+use json::JsonValue;
+
+#[derive(Default)]
+struct Stats {
+    count: u64,
+    bytes: u64,
+    /// Total elements of the arrays and maps seen at this path
+    length: Option<u64>,
+}
+
+/// Totals of the compact JSON size of every value in the records seen, with
+/// array elements merged under `[*]` so that the report stays small
+#[derive(Default)]
+pub(crate) struct SizeReport {
+    paths: Vec<(String, Stats)>,
+    index: HashMap<String, usize>,
+}
+
+/// Append `key` to a path, bracketing and quoting it unless it is a plain
+/// identifier
+fn push_key(path: &mut String, key: &str) {
+    let plain = !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        path.push('.');
+        path.push_str(key);
+    } else {
+        path.push('[');
+        path.push_str(&JsonValue::from(key).dump());
+        path.push(']');
+    }
+}
+
+impl SizeReport {
+    pub(crate) fn add(&mut self, v: &JsonValue) {
+        let mut path = String::from("$");
+        self.visit(v, &mut path);
+    }
+
+    /// Record `v` and everything within it, returning its compact size
+    fn visit(&mut self, v: &JsonValue, path: &mut String) -> u64 {
+        let i = match self.index.get(path.as_str()) {
+            Some(&i) => i,
+            None => {
+                self.index.insert(path.clone(), self.paths.len());
+                self.paths.push((path.clone(), Stats::default()));
+                self.paths.len() - 1
+            }
+        };
+        let prefix = path.len();
+        let (bytes, length) = match v {
+            JsonValue::Array(a) => {
+                path.push_str("[*]");
+                let mut bytes = 2 + a.len().saturating_sub(1) as u64;
+                for item in a {
+                    bytes += self.visit(item, path);
+                }
+                path.truncate(prefix);
+                (bytes, Some(a.len() as u64))
+            }
+            JsonValue::Object(o) => {
+                let mut bytes = 2 + o.len().saturating_sub(1) as u64;
+                for (k, item) in o.iter() {
+                    push_key(path, k);
+                    bytes += JsonValue::from(k).dump().len() as u64 + 1;
+                    bytes += self.visit(item, path);
+                    path.truncate(prefix);
+                }
+                (bytes, Some(o.len() as u64))
+            }
+            other => (other.dump().len() as u64, None),
+        };
+        let stats = &mut self.paths[i].1;
+        stats.count += 1;
+        stats.bytes += bytes;
+        if let Some(length) = length {
+            *stats.length.get_or_insert(0) += length;
+        }
+        bytes
+    }
+
+    /// An object mapping each path to the number of values seen there, their
+    /// total size in bytes, and for containers their total number of elements
+    pub(crate) fn finish(self) -> JsonValue {
+        let mut report = JsonObject::with_capacity(self.paths.len());
+        for (path, stats) in self.paths {
+            let mut o = JsonObject::with_capacity(3);
+            o.insert("count", stats.count.into());
+            o.insert("bytes", stats.bytes.into());
+            if let Some(length) = stats.length {
+                o.insert("length", length.into());
+            }
+            report.insert(&path, o.into());
+        }
+        report.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SizeReport;
+
+    #[test]
+    fn test_size_report() {
+        let mut report = SizeReport::default();
+        report.add(&json::object! { "a": [1, 22], "b c": null });
+        report.add(&json::object! { "a": [333] });
+        assert_eq!(
+            report.finish().dump(),
+            concat!(
+                r#"{"$":{"count":2,"bytes":34,"length":3},"#,
+                r#""$.a":{"count":2,"bytes":11,"length":3},"#,
+                r#""$.a[*]":{"count":3,"bytes":6},"#,
+                r#""$[\"b c\"]":{"count":1,"bytes":4}}"#
+            )
+        );
+    }
+}
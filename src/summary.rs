@@ -0,0 +1,73 @@
+//! A one-line account of a finished conversion, for stderr.
+
+use std::fmt;
+use std::time::Duration;
+
+/// The totals reported once the input is finished
+pub(crate) struct Summary {
+    pub(crate) converted: u64,
+    /// Records not written, because they couldn't be converted or weren't
+    /// objects to project
+    pub(crate) skipped: u64,
+    pub(crate) bytes_in: u64,
+    pub(crate) elapsed: Duration,
+}
+
+/// Write a byte count in the largest binary unit it reaches
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "converted {} record{}, {} skipped, {} in, {:.2}s",
+            self.converted,
+            if self.converted == 1 { "" } else { "s" },
+            self.skipped,
+            format_bytes(self.bytes_in),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_bytes, Summary};
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(5_872_026), "5.6 MiB");
+        assert_eq!(format_bytes(3 << 40), "3.0 TiB");
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary {
+            converted: 1234,
+            skipped: 2,
+            bytes_in: 5_872_026,
+            elapsed: Duration::from_millis(1500),
+        };
+        assert_eq!(
+            summary.to_string(),
+            "converted 1234 records, 2 skipped, 5.6 MiB in, 1.50s"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Rendering of integer timestamps as RFC3339 strings.
+
+use clap::ValueEnum;
+
+/// The unit of an integer timestamp, counted from the Unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TimestampUnit {
+    /// Seconds
+    #[default]
+    S,
+    /// Milliseconds
+    Ms,
+    /// Microseconds
+    Us,
+    /// Nanoseconds
+    Ns,
+}
+
+impl TimestampUnit {
+    fn per_second(self) -> i128 {
+        match self {
+            TimestampUnit::S => 1,
+            TimestampUnit::Ms => 1_000,
+            TimestampUnit::Us => 1_000_000,
+            TimestampUnit::Ns => 1_000_000_000,
+        }
+    }
+
+    fn fraction_digits(self) -> usize {
+        match self {
+            TimestampUnit::S => 0,
+            TimestampUnit::Ms => 3,
+            TimestampUnit::Us => 6,
+            TimestampUnit::Ns => 9,
+        }
+    }
+}
+
+/// Convert a count of days since 1970-01-01 into a (year, month, day) in the
+/// proleptic Gregorian calendar
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i128) -> (i128, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i128::from(month <= 2);
+    (year, month, day)
+}
+
+/// Format `value`, counted in `unit`s since the Unix epoch, as an RFC3339
+/// timestamp in UTC, if it falls in the years 0000 to 9999 which RFC3339 can
+/// write
+pub fn format_rfc3339(value: i128, unit: TimestampUnit) -> Option<String> {
+    let per_second = unit.per_second();
+    let seconds = value.div_euclid(per_second);
+    let fraction = value.rem_euclid(per_second);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    if !(0..=9999).contains(&year) {
+        return None;
+    }
+    let time = seconds.rem_euclid(86_400);
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    );
+    if unit != TimestampUnit::S {
+        s.push_str(&format!(".{:01$}", fraction, unit.fraction_digits()));
+    }
+    s.push('Z');
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_rfc3339, TimestampUnit};
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(
+            format_rfc3339(0, TimestampUnit::S).unwrap(),
+            "1970-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            format_rfc3339(951_782_400, TimestampUnit::S).unwrap(),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            format_rfc3339(-1_500, TimestampUnit::Ms).unwrap(),
+            "1969-12-31T23:59:58.500Z"
+        );
+    }
+
+    #[test]
+    fn test_format_rfc3339_range() {
+        // 0000-01-01T00:00:00Z and 9999-12-31T23:59:59Z
+        assert_eq!(
+            format_rfc3339(-62_167_219_200, TimestampUnit::S).unwrap(),
+            "0000-01-01T00:00:00Z"
+        );
+        assert_eq!(format_rfc3339(-62_167_219_201, TimestampUnit::S), None);
+        assert_eq!(
+            format_rfc3339(253_402_300_799_999, TimestampUnit::Ms).unwrap(),
+            "9999-12-31T23:59:59.999Z"
+        );
+        assert_eq!(format_rfc3339(253_402_300_800, TimestampUnit::S), None);
+        assert_eq!(format_rfc3339(i128::from(i64::MAX), TimestampUnit::S), None);
+    }
+}
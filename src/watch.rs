@@ -0,0 +1,90 @@
+//! Re-running the conversion whenever the input file changes, by polling its
+//! modification time and size.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often to check the input for changes
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What identifies one version of a file
+fn stamp(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// Call `run` once, and again each time the file at `path` changes, until
+/// `stop` returns `true`. A change is only acted on once the file has stayed
+/// the same for a whole `interval`, so a burst of writes causes one run.
+pub fn watch<F, S>(path: &Path, interval: Duration, stop: S, mut run: F) -> io::Result<()>
+where
+    F: FnMut(),
+    S: Fn() -> bool,
+{
+    let mut last = stamp(path)?;
+    run();
+    while !stop() {
+        thread::sleep(interval);
+        let current = match stamp(path) {
+            Ok(current) => current,
+            // Editors often replace a file by renaming over it, leaving
+            // nothing at the path for a moment
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if current == last {
+            continue;
+        }
+        let mut settled = current;
+        loop {
+            thread::sleep(interval);
+            match stamp(path) {
+                Ok(next) if next == settled => break,
+                Ok(next) => settled = next,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        last = settled;
+        if !stop() {
+            run();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::time::Duration;
+
+    use super::watch;
+
+    #[test]
+    fn test_watch() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let runs = Cell::new(0);
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            for _ in 0..3 {
+                file.write_all(b"\x01").unwrap();
+                file.flush().unwrap();
+            }
+            file
+        });
+        watch(
+            &path,
+            Duration::from_millis(20),
+            || runs.get() >= 2,
+            || runs.set(runs.get() + 1),
+        )
+        .unwrap();
+        assert_eq!(runs.get(), 2);
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Checks that `--fast` streams the base64 of a large binary value to the
+//! output rather than building it in memory first.
+//!
+//! This is its own test binary since it replaces the global allocator,
+//! which would otherwise watch every other test too.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Write;
+
+use mp2json::Converter;
+use rmpv::Value;
+
+thread_local! {
+    static LARGEST_ALLOCATION: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Passes allocations on to the system allocator, noting the largest made
+/// by each thread
+struct Watching;
+
+unsafe impl GlobalAlloc for Watching {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LARGEST_ALLOCATION.with(|largest| largest.set(largest.get().max(layout.size())));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LARGEST_ALLOCATION.with(|largest| largest.set(largest.get().max(new_size)));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Watching = Watching;
+
+/// An output noting the largest write, and the largest allocation made
+/// between any two writes once the first has begun the record
+#[derive(Default)]
+struct Watched {
+    written: usize,
+    largest_write: usize,
+    largest_between_writes: usize,
+}
+
+impl Write for Watched {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let largest = LARGEST_ALLOCATION.with(|largest| largest.replace(0));
+        if self.written > 0 {
+            self.largest_between_writes = self.largest_between_writes.max(largest);
+        }
+        self.written += buf.len();
+        self.largest_write = self.largest_write.max(buf.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_large_binary_is_streamed() {
+    let mut input = Vec::new();
+    rmpv::encode::write_value(&mut input, &Value::Binary(vec![0xab; 8 << 20])).unwrap();
+    let mut output = Watched::default();
+    Converter {
+        direct: true,
+        ..Default::default()
+    }
+    .run(input.as_slice(), &mut output)
+    .unwrap();
+    let wrapper = r#"{"encoding":"base64","value":""}"#;
+    assert_eq!(output.written, wrapper.len() + (8 << 20) / 3 * 4 + 4 + 1);
+    // Rather than the 11 MiB of base64 text at once; reading and decoding
+    // the value itself still takes its own size
+    assert!(output.largest_write < 64 * 1024);
+    assert!(output.largest_between_writes < 64 * 1024);
+}